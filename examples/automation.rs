@@ -29,14 +29,14 @@ fn main() {
         balance: Balance::Both,
         amplitude: 1.0,
     };
-    let bass_track_midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let bass_track_midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: TRACK_NO_BASS,
         },
-        channels: HashMap::from([(CHANNEL_NO, ChildConfig(Box::new(bass_track_instrument)))]),
-    };
+        HashMap::from([(CHANNEL_NO, ChildConfig(Box::new(bass_track_instrument)))]),
+    );
     let bass_track_midi_node = bass_track_midi.to_node(&mut asset_loader).unwrap();
     let lead_track_instrument = SquareWave {
         node_id: Some(NODE_ID_LEAD),
@@ -44,14 +44,14 @@ fn main() {
         amplitude: 0.25,
         duty_cycle: 0.0625,
     };
-    let lead_track_midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let lead_track_midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: TRACK_NO_LEAD,
         },
-        channels: HashMap::from([(CHANNEL_NO, ChildConfig(Box::new(lead_track_instrument)))]),
-    };
+        HashMap::from([(CHANNEL_NO, ChildConfig(Box::new(lead_track_instrument)))]),
+    );
     let lead_track_midi_node = lead_track_midi.to_node(&mut asset_loader).unwrap();
     let combiner_node = CombinerNode::new(None, vec![bass_track_midi_node, lead_track_midi_node]);
     let _mixer = BaseMixer::builder_with_default_registry()
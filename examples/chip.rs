@@ -35,6 +35,7 @@ fn main() {
                 })),
             ],
         })),
+        pressure: vec![],
     };
     let triangle_instrument = Font {
         node_id: None,
@@ -84,18 +85,18 @@ fn main() {
         }]),
     };
     let mut asset_loader = FileAssetLoader::default();
-    let midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (TRIANGLE_CHANNEL, ChildConfig(Box::new(triangle_instrument))),
             (SQUARE_CHANNEL, ChildConfig(Box::new(square_instrument))),
             (NOISE_CHANNEL, ChildConfig(Box::new(noise_instrument))),
         ]),
-    };
+    );
     let _mixer = BaseMixer::builder_with_default_registry()
         .unwrap()
         .set_initial_program_from_config(1, ChildConfig(Box::new(midi)), &mut asset_loader)
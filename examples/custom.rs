@@ -20,13 +20,13 @@ const JSON_SUBTREE_FILE: &'static str = "resources/custom-example-subtree.json";
 
 fn main() {
     let subtree_config = Subtree::as_path(JSON_SUBTREE_FILE);
-    let config = ChildConfig(Box::new(Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let config = ChildConfig(Box::new(Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (CHANNEL_0, ChildConfig(Box::new(subtree_config))),
             (
                 CHANNEL_1,
@@ -36,7 +36,7 @@ fn main() {
                 })),
             ),
         ]),
-    }));
+    )));
     let mut asset_loader = FileAssetLoader::default();
     let _mixer = BaseMixer::builder_with_custom_registry(|registry| {
         registry.register_node_type::<SineWave>("SineWave");
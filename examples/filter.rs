@@ -38,6 +38,7 @@ fn main() {
                 })),
             ],
         })),
+        pressure: vec![],
     };
     let triangle_font = Font {
         node_id: None,
@@ -59,6 +60,7 @@ fn main() {
                 amplitude: 0.125,
                 duty_cycle: 0.5,
             })),
+            pressure: vec![],
         })),
     };
     let noise_font = Font {
@@ -76,18 +78,18 @@ fn main() {
         }]),
     };
     let mut asset_loader = FileAssetLoader::default();
-    let midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (TRIANGLE_CHANNEL, ChildConfig(Box::new(triangle_font))),
             (SQUARE_CHANNEL, ChildConfig(Box::new(square_node))),
             (NOISE_CHANNEL, ChildConfig(Box::new(noise_font))),
         ]),
-    };
+    );
     let mixer = BaseMixer::builder_with_default_registry()
         .unwrap()
         .set_initial_program_from_config(1, ChildConfig(Box::new(midi)), &mut asset_loader)
@@ -0,0 +1,32 @@
+extern crate midi_graph;
+
+use midi_graph::{
+    Balance, BaseMixer, effect::AdsrEnvelopeNode, generator::TriangleWaveNode,
+    group::PolyphonyNode, midi::MidiInput,
+};
+
+const POLYPHONY_NODE_ID: u64 = 100;
+
+/// Plays raw MIDI bytes read from standard input on MIDI channel 1, such as:
+/// `cat /dev/snd/midiC1D0 | cargo run --example live_input`
+fn main() {
+    let inner = AdsrEnvelopeNode::from_parameters(
+        None,
+        0.01,
+        0.2,
+        0.8,
+        0.3,
+        Box::new(TriangleWaveNode::new(None, Balance::Both, 0.5)),
+    );
+    let polyphony = PolyphonyNode::new(Some(POLYPHONY_NODE_ID), 8, Box::new(inner)).unwrap();
+    let mixer = BaseMixer::builder_with_default_registry()
+        .unwrap()
+        .set_initial_program(1, Box::new(polyphony))
+        .start(Some(1))
+        .unwrap();
+    let mut input =
+        MidiInput::new(mixer.get_event_sender()).add_channel_target(0, POLYPHONY_NODE_ID);
+    if let Err(error) = input.read_from(std::io::stdin().lock()) {
+        println!("Input error: {:?}", error);
+    }
+}
@@ -19,13 +19,13 @@ const MIDI_NODE_ID: u64 = 100;
 const FADER_NODE_ID: u64 = 101;
 
 fn main() {
-    let config = ChildConfig(Box::new(Midi {
-        node_id: Some(MIDI_NODE_ID),
-        source: MidiDataSource::FilePath {
+    let config = ChildConfig(Box::new(Midi::new(
+        Some(MIDI_NODE_ID),
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (
                 NOISE_CHANNEL,
                 ChildConfig(Box::new(Font {
@@ -63,7 +63,7 @@ fn main() {
                 })),
             ),
         ]),
-    }));
+    )));
     let mut asset_loader = FileAssetLoader::default();
     let mixer = BaseMixer::builder_with_default_registry()
         .unwrap()
@@ -87,7 +87,7 @@ fn main() {
     send_after(
         &mut sender,
         EventTarget::SpecificNode(MIDI_NODE_ID),
-        Event::CueData(CueData::SeekWhenIdeal(1)),
+        Event::CueData(CueData::SeekWhenIdeal(1.into())),
         absolute_frame,
         12.5,
     );
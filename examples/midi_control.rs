@@ -47,14 +47,14 @@ fn main() {
     }
 
     let mut asset_loader = FileAssetLoader::default();
-    let program_0 = Midi {
-        node_id: Some(MIDI_NODE_ID),
-        source: MidiDataSource::FilePath {
+    let program_0 = Midi::new(
+        Some(MIDI_NODE_ID),
+        MidiDataSource::FilePath {
             path: MIDI_0_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([(0, ChildConfig(wavetable()))]),
-    }
+        HashMap::from([(0, ChildConfig(wavetable()))]),
+    )
     .to_node(&mut asset_loader)
     .unwrap();
 
@@ -65,31 +65,31 @@ fn main() {
     }
 
     let mut asset_loader = FileAssetLoader::default();
-    let program_0 = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let program_0 = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_0_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (0, ChildConfig(triangle_font())),
             (1, ChildConfig(square_font())),
             (2, ChildConfig(noise_font())),
         ]),
-    }
+    )
     .to_node(&mut asset_loader)
     .unwrap();
-    let program_1 = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let program_1 = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_1_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (0, ChildConfig(noise_font())),
             (1, ChildConfig(square_font())),
         ]),
-    }
+    )
     .to_node(&mut asset_loader)
     .unwrap();
 
@@ -48,18 +48,18 @@ fn main() {
             upper: 127,
         }]),
     };
-    let midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (SOUNDFONT_0_CHANNEL, ChildConfig(Box::new(font_0))),
             (SOUNDFONT_1_CHANNEL, ChildConfig(Box::new(font_1))),
             (NOISE_CHANNEL, ChildConfig(Box::new(noise_font))),
         ]),
-    };
+    );
 
     let _mixer = BaseMixer::builder_with_default_registry()
         .unwrap()
@@ -65,32 +65,32 @@ fn main() {
     }
 
     let mut asset_loader = FileAssetLoader::default();
-    let program_0 = Midi {
-        node_id: Some(MIDI_NODE_ID),
-        source: MidiDataSource::FilePath {
+    let program_0 = Midi::new(
+        Some(MIDI_NODE_ID),
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (0, ChildConfig(triangle_font())),
             (1, ChildConfig(square_font())),
             (2, ChildConfig(noise_font())),
         ]),
-    }
+    )
     .to_node(&mut asset_loader)
     .unwrap();
-    let program_1 = Midi {
-        node_id: Some(MIDI_NODE_ID),
-        source: MidiDataSource::FilePath {
+    let program_1 = Midi::new(
+        Some(MIDI_NODE_ID),
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([
+        HashMap::from([
             (0, ChildConfig(square_font())),
             (1, ChildConfig(triangle_font())),
             (2, ChildConfig(noise_font())),
         ]),
-    }
+    )
     .to_node(&mut asset_loader)
     .unwrap();
 
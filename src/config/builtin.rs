@@ -2,8 +2,8 @@ use crate::{
     config::NodeRegistry,
     effect::{AdsrEnvelope, Fader, Filter, Lfo, Transition},
     generator::{LfsrNoise, Null, OneShot, SampleLoop, SawtoothWave, SquareWave, TriangleWave},
-    group::{Combiner, Font, Mixer, Polyphony, Subtree},
-    midi::Midi,
+    group::{AdaptiveMusic, Arrangements, Combiner, Font, Mixer, Polyphony, Subtree},
+    midi::{GeneralMidi, Midi},
 };
 
 pub(crate) fn register_builtin_types(registry: &mut NodeRegistry) {
@@ -21,8 +21,11 @@ pub(crate) fn register_builtin_types(registry: &mut NodeRegistry) {
     registry.register_node_type::<TriangleWave>("TriangleWave");
     registry.register_node_type::<Font>("Font");
     registry.register_node_type::<Mixer>("Mixer");
+    registry.register_node_type::<AdaptiveMusic>("AdaptiveMusic");
+    registry.register_node_type::<Arrangements>("Arrangements");
     registry.register_node_type::<Combiner>("Combiner");
     registry.register_node_type::<Polyphony>("Polyphony");
     registry.register_node_type::<Midi>("Midi");
+    registry.register_node_type::<GeneralMidi>("GeneralMidi");
     registry.register_node_type::<Subtree>("Subtree");
 }
@@ -1,4 +1,4 @@
-use crate::Balance;
+use crate::{Balance, midi::ControllerBinding};
 
 pub const fn none_id() -> Option<u64> {
     None
@@ -47,3 +47,27 @@ pub const fn mixer_balance() -> f32 {
 pub const fn max_voices() -> usize {
     4
 }
+
+pub const fn pitch_bend_range() -> f32 {
+    2.0
+}
+
+pub const fn controller_min() -> f32 {
+    0.0
+}
+
+pub const fn controller_max() -> f32 {
+    1.0
+}
+
+pub const fn ticks_per_beat() -> u16 {
+    480
+}
+
+pub const fn beats_per_minute() -> f64 {
+    120.0
+}
+
+pub fn controller_bindings() -> Vec<ControllerBinding> {
+    ControllerBinding::general_midi()
+}
@@ -1,4 +1,10 @@
-use crate::{consts, effect::ModulationProperty, midi::CueData};
+use crate::{
+    consts,
+    effect::ModulationProperty,
+    midi::{
+        CueData, Groove, MidiText, MidiTextKind, SeekPosition, StingerAlignment, VelocityCurve,
+    },
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -79,11 +85,69 @@ impl EventTiming {
 pub enum Event {
     StateSnapshot(Value),
     CueData(CueData),
+    /// Text from a MIDI file, such as a lyric, reached during playback
+    Text {
+        kind: MidiTextKind,
+        text: String,
+    },
+    /// Subscribe to the text events a MIDI node reaches during playback
+    SubscribeText(crossbeam_channel::Sender<MidiText>),
+    CueVariable {
+        name: String,
+        value: f32,
+    },
     LoopCue {
         is_ideal_point: bool,
         seek_anchor: Option<u32>,
     },
     MidiPlayback(MidiPlaybackState),
+    /// Play a named stinger of a MIDI node over its music, from the given
+    /// point in the music
+    Stinger {
+        name: String,
+        alignment: StingerAlignment,
+    },
+    /// Move an adaptive music node to the named state, by its transition rules
+    MusicState(String),
+    /// Crossfade an arrangements node to the named arrangement, at the same
+    /// song position
+    Arrangement {
+        name: String,
+        seconds: f32,
+    },
+    /// Set a MIDI node's groove. Its events are rebuilt on receipt, which
+    /// allocates, so send it ahead of the music it affects.
+    Groove(Groove),
+    Transpose {
+        channel: usize,
+        semitones: i8,
+    },
+    VelocityCurve {
+        channel: usize,
+        curve: VelocityCurve,
+    },
+    /// Play a different note in place of one, or stop doing so when None
+    NoteRemap {
+        channel: usize,
+        from: u8,
+        to: Option<u8>,
+    },
+    ChannelVolume {
+        channel: usize,
+        volume: f32,
+        seconds: f32,
+    },
+    ChannelMute {
+        channel: usize,
+        muted: bool,
+        seconds: f32,
+    },
+    ChannelSolo {
+        channel: usize,
+        soloed: bool,
+        seconds: f32,
+    },
+    Seek(SeekPosition),
     NoteOn {
         note: u8,
         vel: f32,
@@ -97,7 +161,15 @@ pub enum Event {
     SourceBalance(Balance),
     Volume(f32),
     PitchMultiplier(f32),
+    PitchBend(f32),
+    PitchBendRange(f32),
     TimeDilation(f32),
+    /// Set a MIDI node's tempo in beats per minute, ramping to it over the
+    /// given time, in place of the tempo map's own tempo
+    Tempo {
+        bpm: f32,
+        seconds: f32,
+    },
     FilterFrequencyShift(f32),
     Fade {
         from: f32,
@@ -123,6 +195,21 @@ pub enum Event {
         cutoff_frequency: f32,
     },
     EndModulation,
+    LfoDepth(f32),
+    Controller {
+        controller: u8,
+        value: u8,
+    },
+    Parameter {
+        name: String,
+        value: f32,
+    },
+    ProgramChange(u8),
+    KeyPressure {
+        note: u8,
+        pressure: f32,
+    },
+    ChannelPressure(f32),
     Wavetable(Vec<f32>),
     Unknown,
 }
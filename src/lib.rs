@@ -33,6 +33,10 @@
 //!   time from a MIDI file source or a custom list
 //! - [MessageSender] for an asynchronous channel sender to queue events
 //!   any time
+//! - [midi::MidiInput] for a live MIDI byte stream, sending events
+//!   through a [MessageSender] as they arrive
+//! - [midi::MidiRecorderNode] for capturing the note and cue events going
+//!   into a node, to be saved as a MIDI file
 //!
 //! ## File Formats
 //!
@@ -42,6 +46,7 @@
 //! - MIDI files - `.mid` or `.smf`
 //! - WAV audio files - `.wav`
 //! - SoundFont 2 files - `.sf2`
+//! - ProTracker modules - `.mod`
 //! - RON files - `.ron` (for loading graph configuration)
 
 // Test suite for the Web and headless browsers.
@@ -114,6 +119,10 @@ pub mod generator {
 /// Nodes that wrap and orchestrate child nodes
 pub mod group {
     pub use crate::node::group::{
+        adaptive::{
+            AdaptiveMusic, AdaptiveMusicNode, MusicState, MusicTransition, TransitionTiming,
+        },
+        arrangements::{Arrangements, ArrangementsNode},
         combiner::{Combiner, CombinerNode},
         font::{Font, FontNode, FontNodeBuilder, FontSource, RangeSource},
         mixer::{Mixer, MixerNode},
@@ -125,7 +134,28 @@ pub mod group {
 /// Special node that plays through a pre-defined, timed event sequence
 pub mod midi {
     pub use crate::node::midi::{
-        Midi, MidiDataSource, MidiNode, MidiNodeBuilder, cue::CueData, event::MidiEvent,
+        Midi, MidiDataSource, MidiNode, MidiNodeBuilder,
+        channel_mix::{ChannelFade, ChannelMix, ChannelMixSettings},
+        controller::{
+            ControllerBinding, ControllerCurve, ControllerMapper, ControllerSource,
+            ControllerTarget,
+        },
+        cue::{Anchor, CueCondition, CueData},
+        event::MidiEvent,
+        general_midi::{GeneralMidi, GeneralMidiChannelNode},
+        groove::{Groove, SwingGrid},
+        live::MidiInput,
+        mml::parse_mml,
+        prepared::PreparedMidiFile,
+        program::ProgramBank,
+        recorder::{MidiRecorderNode, MidiRecording, RecordedEvent},
+        routing::{ChannelNumbering, ChannelRouting, ChannelSelector},
+        sequence::{SequenceEvent, SequenceEventData, SequenceTime, midi_events_from_sequence},
+        stinger::{Stinger, StingerAlignment, StingerConfig},
+        tempo::{SeekPosition, TempoMap},
+        text::{MidiText, MidiTextKind},
+        tracker::TrackerModule,
+        transform::{NoteTransform, VelocityCurve},
     };
 }
 
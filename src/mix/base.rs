@@ -43,7 +43,7 @@ impl AudioClock {
     }
 }
 
-enum AudioCommand {
+pub(crate) enum AudioCommand {
     GraphMessage(Message),
     SwapConsumer {
         consumer: GraphNode,
@@ -74,6 +74,17 @@ impl MessageSender {
         }
     }
 
+    /// Sender whose commands are read from the returned receiver rather than
+    /// by an audio stream, with the clock stopped at frame 0
+    #[cfg(test)]
+    pub(crate) fn detached() -> (Self, Receiver<AudioCommand>) {
+        let (command_sender, command_receiver) = unbounded();
+        (
+            Self::new(command_sender, Arc::new(AudioClock::new())),
+            command_receiver,
+        )
+    }
+
     pub fn current_rendering_absolute_frame(&self) -> u64 {
         self.clock
             .current_rendering_absolute_frame
@@ -97,15 +108,18 @@ enum ConsumerCell {
     Placeholder,
 }
 
+/// Message waiting for its frame. Messages for the same frame are handled in
+/// the order they were sent, as tracked by the sequence number.
 struct ScheduledMessageEvent {
     target: EventTarget,
     data: Event,
     absolute_frame: u64,
+    sequence: u64,
 }
 
 impl PartialEq for ScheduledMessageEvent {
     fn eq(&self, other: &Self) -> bool {
-        self.absolute_frame == other.absolute_frame
+        self.absolute_frame == other.absolute_frame && self.sequence == other.sequence
     }
 }
 
@@ -113,15 +127,49 @@ impl Eq for ScheduledMessageEvent {}
 
 impl PartialOrd for ScheduledMessageEvent {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.absolute_frame
-            .partial_cmp(&other.absolute_frame)
-            .map(|ord| ord.reverse())
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for ScheduledMessageEvent {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.absolute_frame.cmp(&other.absolute_frame).reverse()
+        (self.absolute_frame, self.sequence)
+            .cmp(&(other.absolute_frame, other.sequence))
+            .reverse()
+    }
+}
+
+/// Messages waiting for their frames, taken earliest first
+#[derive(Default)]
+pub(crate) struct ScheduledMessages {
+    pending: BinaryHeap<ScheduledMessageEvent>,
+    next_sequence: u64,
+}
+
+impl ScheduledMessages {
+    pub(crate) fn push(&mut self, target: EventTarget, data: Event, absolute_frame: u64) {
+        self.pending.push(ScheduledMessageEvent {
+            target,
+            data,
+            absolute_frame,
+            sequence: self.next_sequence,
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Frame of the earliest waiting message
+    pub(crate) fn next_frame(&self) -> Option<u64> {
+        self.pending.peek().map(|message| message.absolute_frame)
+    }
+
+    /// Take the earliest waiting message, if due by the frame
+    pub(crate) fn pop_due(&mut self, frame: u64) -> Option<(EventTarget, Event)> {
+        self.pending
+            .peek()
+            .is_some_and(|message| message.absolute_frame <= frame)
+            .then(|| self.pending.pop())
+            .flatten()
+            .map(|message| (message.target, message.data))
     }
 }
 
@@ -382,7 +430,7 @@ impl BaseMixer {
             sample_rate: cpal::SampleRate(consts::PLAYBACK_SAMPLE_RATE as u32),
         };
 
-        let mut pending_messages: BinaryHeap<ScheduledMessageEvent> = BinaryHeap::new();
+        let mut pending_messages = ScheduledMessages::default();
 
         clock.reset();
         let stream = device.build_output_stream(
@@ -401,11 +449,7 @@ impl BaseMixer {
                                 consumer.on_event(&message);
                             }
                             EventTiming::AtAbsoluteFrame(absolute_frame) => {
-                                pending_messages.push(ScheduledMessageEvent {
-                                    target: message.target,
-                                    data: message.data,
-                                    absolute_frame: absolute_frame,
-                                });
+                                pending_messages.push(message.target, message.data, absolute_frame);
                             }
                         },
                         AudioCommand::SwapConsumer {
@@ -425,15 +469,15 @@ impl BaseMixer {
                 }
 
                 let mut cursor_offset_frame: usize = 0;
-                while let Some(next_message) = pending_messages.peek() {
-                    let message_frame = next_message.absolute_frame.max(buffer_start_frame);
+                while let Some(next_frame) = pending_messages.next_frame() {
+                    let message_frame = next_frame.max(buffer_start_frame);
                     if message_frame >= buffer_end_frame {
                         break;
                     }
-                    if next_message.absolute_frame < buffer_start_frame {
+                    if next_frame < buffer_start_frame {
                         println!(
                             "WARNING: Message processed late ({} < {})",
-                            next_message.absolute_frame, buffer_start_frame
+                            next_frame, buffer_start_frame
                         );
                     }
                     let buffer_offset_frame = (message_frame - buffer_start_frame) as usize;
@@ -442,14 +486,10 @@ impl BaseMixer {
                     consumer.fill_buffer(&mut data[samples_start..samples_end]);
                     cursor_offset_frame = buffer_offset_frame;
 
-                    while pending_messages
-                        .peek()
-                        .is_some_and(|message| message.absolute_frame <= message_frame)
-                    {
-                        let message = pending_messages.pop().unwrap();
+                    while let Some((target, data)) = pending_messages.pop_due(message_frame) {
                         consumer.on_event(&Message {
-                            target: message.target,
-                            data: message.data,
+                            target,
+                            data,
                             timing: EventTiming::AtAbsoluteFrame(
                                 buffer_start_frame + cursor_offset_frame as u64,
                             ),
@@ -43,6 +43,7 @@ pub struct LfoNode {
     cycle_steps: usize,
     low: f32,
     high: f32,
+    depth: f32,
 }
 
 impl LfoNode {
@@ -57,25 +58,22 @@ impl LfoNode {
             cycle_steps: 0,
             low: 0.0,
             high: 1.0,
+            depth: 1.0,
         })
     }
 
     fn send_step_event(&mut self) {
+        let Some(property) = self.property else {
+            return;
+        };
         let period_value = self.current_step as f32 / self.cycle_steps as f32;
-        let value = self.low
+        let centre = (self.low + self.high) * 0.5;
+        let value = centre
             + (self.high - self.low)
-                * ((period_value * 2.0 * std::f32::consts::PI).cos() * 0.5 + 0.5);
-        let event = match self.property {
-            Some(ModulationProperty::Volume) => Event::Volume(value),
-            Some(ModulationProperty::Pan) => Event::SourceBalance(Balance::Pan(value)),
-            Some(ModulationProperty::PitchMultiplier) => Event::PitchMultiplier(value),
-            Some(ModulationProperty::MixBalance) => Event::MixerBalance(value),
-            Some(ModulationProperty::TimeDilation) => Event::TimeDilation(value),
-            Some(ModulationProperty::FilterFrequencyShift) => Event::FilterFrequencyShift(value),
-            None => {
-                return;
-            }
-        };
+                * self.depth
+                * (period_value * 2.0 * std::f32::consts::PI).cos()
+                * 0.5;
+        let event = property.event_for_value(value);
         self.consumer.on_event(&Message::broadcast(event));
     }
 
@@ -85,8 +83,11 @@ impl LfoNode {
             Some(ModulationProperty::Pan) => Event::SourceBalance(Balance::Both),
             Some(ModulationProperty::PitchMultiplier) => Event::PitchMultiplier(1.0),
             Some(ModulationProperty::MixBalance) => Event::MixerBalance(0.5),
-            Some(ModulationProperty::TimeDilation) => Event::TimeDilation(1.0),
+            Some(ModulationProperty::TimeDilation) | Some(ModulationProperty::Tempo) => {
+                Event::TimeDilation(1.0)
+            }
             Some(ModulationProperty::FilterFrequencyShift) => Event::FilterFrequencyShift(0.0),
+            Some(ModulationProperty::LfoDepth) => Event::LfoDepth(1.0),
             None => {
                 return;
             }
@@ -116,6 +117,7 @@ impl Node for LfoNode {
             cycle_steps: self.cycle_steps,
             low: 0.0,
             high: 1.0,
+            depth: self.depth,
         };
         Ok(Box::new(lfo))
     }
@@ -157,6 +159,9 @@ impl Node for LfoNode {
                 self.send_off_event();
                 self.property = None;
             }
+            Event::LfoDepth(depth) => {
+                self.depth = depth;
+            }
             _ => {}
         }
 
@@ -4,12 +4,37 @@ pub mod filter;
 pub mod lfo;
 pub mod transition;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+use crate::{Balance, Event};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub enum ModulationProperty {
     Volume,
     Pan,
     PitchMultiplier,
     MixBalance,
     TimeDilation,
+    /// MIDI playback tempo in beats per minute
+    Tempo,
     FilterFrequencyShift,
+    LfoDepth,
+}
+
+impl ModulationProperty {
+    /// The event that sets this property to the given value
+    pub fn event_for_value(&self, value: f32) -> Event {
+        match self {
+            ModulationProperty::Volume => Event::Volume(value),
+            ModulationProperty::Pan => Event::SourceBalance(Balance::Pan(value)),
+            ModulationProperty::PitchMultiplier => Event::PitchMultiplier(value),
+            ModulationProperty::MixBalance => Event::MixerBalance(value),
+            ModulationProperty::TimeDilation => Event::TimeDilation(value),
+            ModulationProperty::Tempo => Event::Tempo {
+                bpm: value,
+                seconds: 0.0,
+            },
+            ModulationProperty::FilterFrequencyShift => Event::FilterFrequencyShift(value),
+            ModulationProperty::LfoDepth => Event::LfoDepth(value),
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use crate::{
-    AssetLoader, Balance, Error, Event, GraphNode, Message, Node,
+    AssetLoader, Error, Event, GraphNode, Message, Node,
     abstraction::{ChildConfig, NodeConfig, defaults},
     consts,
     effect::ModulationProperty,
@@ -61,19 +61,12 @@ impl TransitionNode {
     }
 
     fn send_event(&mut self) {
+        let Some(property) = self.property else {
+            return;
+        };
         let period_value = self.current_step as f32 / self.total_steps as f32;
         let value = self.from + (self.to - self.from) * period_value;
-        let event = match self.property {
-            Some(ModulationProperty::Volume) => Event::Volume(value),
-            Some(ModulationProperty::Pan) => Event::SourceBalance(Balance::Pan(value)),
-            Some(ModulationProperty::PitchMultiplier) => Event::PitchMultiplier(value),
-            Some(ModulationProperty::MixBalance) => Event::MixerBalance(value),
-            Some(ModulationProperty::TimeDilation) => Event::TimeDilation(value),
-            Some(ModulationProperty::FilterFrequencyShift) => Event::FilterFrequencyShift(value),
-            None => {
-                return;
-            }
-        };
+        let event = property.event_for_value(value);
         self.consumer.on_event(&Message::broadcast(event));
     }
 }
@@ -1,8 +1,8 @@
-
 pub mod noise;
 pub mod null;
 pub mod one_shot;
 pub mod sawtooth;
 pub mod square;
 pub mod triangle;
+pub(crate) mod util;
 pub mod wav;
@@ -1,3 +1,4 @@
+use super::util::PitchBend;
 use crate::{
     AssetLoader, Balance, Error, Event, EventTarget, GraphNode, Message, Node,
     abstraction::{ChildConfig, NodeConfig, defaults},
@@ -69,6 +70,7 @@ pub struct LfsrNoiseNode {
     peak_amplitude: f32,
     note_velocity: f32,
     modulated_volume: f32,
+    pitch_bend: PitchBend,
 }
 
 impl LfsrNoiseNode {
@@ -104,6 +106,7 @@ impl LfsrNoiseNode {
             peak_amplitude: amplitude,
             note_velocity: 1.0,
             modulated_volume: 1.0,
+            pitch_bend: PitchBend::default(),
         }
     }
 
@@ -172,6 +175,12 @@ impl Node for LfsrNoiseNode {
             Event::PitchMultiplier(multiplier) => {
                 self.current_frequency = multiplier * util::frequency_of(self.current_note);
             }
+            Event::PitchBend(position) => {
+                self.pitch_bend.set_position(position);
+            }
+            Event::PitchBendRange(semitones) => {
+                self.pitch_bend.set_range(semitones);
+            }
             Event::SourceBalance(balance) => {
                 self.balance = balance;
             }
@@ -187,10 +196,13 @@ impl Node for LfsrNoiseNode {
 
     fn fill_buffer(&mut self, buffer: &mut [f32]) {
         if !self.is_on {
+            self.pitch_bend
+                .advance(buffer.len() / consts::CHANNEL_COUNT);
             return;
         }
         let size = buffer.len();
-        let pitch_cycle_samples = consts::PLAYBACK_SAMPLE_RATE as f32 / self.current_frequency;
+        let mut pitch_cycle_samples = consts::PLAYBACK_SAMPLE_RATE as f32
+            / (self.current_frequency * self.pitch_bend.multiplier());
         let mut stretched_progress =
             self.cycle_progress_samples * pitch_cycle_samples / self.cycle_samples_a440;
 
@@ -209,6 +221,12 @@ impl Node for LfsrNoiseNode {
             Balance::Pan(pan) => (1.0 - pan, pan),
         };
         for i in (0..size).step_by(consts::CHANNEL_COUNT) {
+            if self.pitch_bend.is_gliding() {
+                let bent_period_samples = consts::PLAYBACK_SAMPLE_RATE as f32
+                    / (self.current_frequency * self.pitch_bend.advance(1));
+                stretched_progress *= bent_period_samples / pitch_cycle_samples;
+                pitch_cycle_samples = bent_period_samples;
+            }
             stretched_progress += 1.0;
             if stretched_progress >= pitch_cycle_samples {
                 stretched_progress -= pitch_cycle_samples;
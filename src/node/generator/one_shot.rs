@@ -31,8 +31,11 @@ pub struct OneShot {
     pub path: String,
 }
 
-impl NodeConfig for OneShot {
-    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
+impl OneShot {
+    pub fn to_one_shot_node(
+        &self,
+        asset_loader: &mut dyn AssetLoader,
+    ) -> Result<OneShotNode, Error> {
         let (metadata, sample_buffer) = match asset_loader.load_asset_data(&self.path)? {
             AssetLoadPayload::RawAssetData(raw_data) => {
                 let cursor = Cursor::new(raw_data);
@@ -54,8 +57,13 @@ impl NodeConfig for OneShot {
                 (metadata, sample_buffer)
             }
         };
-        let source =
-            OneShotNode::new_from_data(self.node_id, self.balance, metadata, sample_buffer)?;
+        OneShotNode::new_from_data(self.node_id, self.balance, metadata, sample_buffer)
+    }
+}
+
+impl NodeConfig for OneShot {
+    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
+        let source = self.to_one_shot_node(asset_loader)?;
         let source: GraphNode = Box::new(source);
         Ok(source)
     }
@@ -113,6 +121,11 @@ impl OneShotNode {
         }
     }
 
+    /// Whether the sample has played to its end, or was stopped
+    pub fn has_finished(&self) -> bool {
+        self.data_position >= self.sample_buffer.len()
+    }
+
     fn validate_spec(spec: &WavSpec) -> Result<(), Error> {
         if spec.channels == 0 || spec.channels > 2 {
             return Err(Error::User(format!(
@@ -0,0 +1,70 @@
+use crate::{abstraction::defaults, consts};
+
+const BEND_GLIDE_SECONDS: f32 = 0.005;
+const BEND_SETTLE_THRESHOLD: f32 = 0.00001;
+
+/// Pitch bend state of a single voice. New bend positions are approached over
+/// a few milliseconds, since bend data arrives in coarse steps that would
+/// otherwise be heard as zipper noise.
+#[derive(Clone)]
+pub struct PitchBend {
+    range_semitones: f32,
+    position: f32,
+    current_multiplier: f32,
+    target_multiplier: f32,
+    retain_per_frame: f32,
+}
+
+impl Default for PitchBend {
+    fn default() -> Self {
+        Self {
+            range_semitones: defaults::pitch_bend_range(),
+            position: 0.0,
+            current_multiplier: 1.0,
+            target_multiplier: 1.0,
+            retain_per_frame: (-1.0 / (BEND_GLIDE_SECONDS * consts::PLAYBACK_SAMPLE_RATE as f32))
+                .exp(),
+        }
+    }
+}
+
+impl PitchBend {
+    /// Set the bend position, from -1.0 (full bend down) to 1.0 (full bend up)
+    pub fn set_position(&mut self, position: f32) {
+        self.position = position.clamp(-1.0, 1.0);
+        self.target_multiplier = 2.0f32.powf(self.position * self.range_semitones / 12.0);
+    }
+
+    pub fn set_range(&mut self, range_semitones: f32) {
+        self.range_semitones = range_semitones;
+        self.set_position(self.position);
+    }
+
+    #[inline]
+    pub fn is_gliding(&self) -> bool {
+        self.current_multiplier != self.target_multiplier
+    }
+
+    #[inline]
+    pub fn multiplier(&self) -> f32 {
+        self.current_multiplier
+    }
+
+    /// Move the glide forward by some number of frames, returning the new multiplier
+    #[inline]
+    pub fn advance(&mut self, frames: usize) -> f32 {
+        if !self.is_gliding() {
+            return self.current_multiplier;
+        }
+        let retained = match frames {
+            1 => self.retain_per_frame,
+            _ => self.retain_per_frame.powi(frames as i32),
+        };
+        let difference = (self.current_multiplier - self.target_multiplier) * retained;
+        self.current_multiplier = match difference.abs() < BEND_SETTLE_THRESHOLD {
+            true => self.target_multiplier,
+            false => self.target_multiplier + difference,
+        };
+        self.current_multiplier
+    }
+}
@@ -1,3 +1,4 @@
+use super::util::PitchBend;
 use crate::{
     AssetLoadPayload, AssetLoader, Balance, Error, Event, GraphNode, LoopRange, Message, Node,
     SampleBuffer,
@@ -9,6 +10,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{io::Cursor, sync::Arc};
 
+const BEND_GLIDE_SECTION_FRAMES: usize = 32;
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct SampleLoopSourceMetadata {
     sample_rate: u32,
@@ -117,6 +120,7 @@ pub struct SampleLoopNode {
     data_position: usize,
     current_note: u8,
     pitch_multiplier: f32,
+    pitch_bend: PitchBend,
     volume: f32,
     sample_buffer: SampleBuffer,
     buffer_start_index: usize,
@@ -205,6 +209,7 @@ impl SampleLoopNode {
             data_position: sample_buffer.len(),
             current_note: 0,
             pitch_multiplier: 1.0,
+            pitch_bend: PitchBend::default(),
             volume: 1.0,
             sample_buffer,
             buffer_start_index,
@@ -288,6 +293,61 @@ impl SampleLoopNode {
         let dst_data_points_advanced = dst_index;
         (src_data_points_advanced, dst_data_points_advanced)
     }
+
+    fn fill_at_current_pitch(&mut self, buffer: &mut [f32]) {
+        if buffer.is_empty() {
+            return;
+        }
+
+        if self.is_on && self.data_position >= self.loop_end_buffer_index {
+            self.data_position -= self.loop_end_buffer_index - self.loop_start_buffer_index;
+        }
+
+        // Scaling
+        let relative_pitch = self.pitch_multiplier as f64
+            * self.pitch_bend.multiplier() as f64
+            * util::relative_pitch_ratio_of(self.current_note, self.source_note) as f64;
+        let source_frames_per_output_frame = relative_pitch * self.playback_scale;
+
+        #[cfg(debug_assertions)]
+        assert_eq!(buffer.len() % consts::CHANNEL_COUNT, 0);
+
+        let mut remaining_buffer = &mut buffer[0..];
+        while !remaining_buffer.is_empty() {
+            let sample_end_index = self.buffer_start_index + self.buffer_length_samples;
+            if self.data_position >= sample_end_index {
+                self.is_on = false;
+                return;
+            }
+
+            let source_end_point = match self.is_on {
+                true => sample_end_index.min(self.loop_end_buffer_index),
+                false => sample_end_index,
+            };
+
+            let (src_data_points_advanced, dst_data_points_advanced) = self.stretch_buffer(
+                &self.sample_buffer[self.data_position..source_end_point],
+                self.source_channel_count,
+                remaining_buffer,
+                source_frames_per_output_frame,
+            );
+
+            self.data_position += src_data_points_advanced;
+
+            if self.data_position != source_end_point {
+                break;
+            }
+            if self.is_on && source_end_point == self.loop_end_buffer_index {
+                self.data_position = self.loop_start_buffer_index;
+                let remaining_dst_data_points = remaining_buffer.len() - dst_data_points_advanced;
+                let dst_buffer_index = buffer.len() - remaining_dst_data_points;
+                remaining_buffer = &mut buffer[dst_buffer_index..];
+            } else {
+                self.is_on = false;
+                return;
+            }
+        }
+    }
 }
 
 impl Node for SampleLoopNode {
@@ -304,8 +364,9 @@ impl Node for SampleLoopNode {
         let loop_range = match self.loop_end_buffer_index == usize::MAX {
             true => None,
             false => Some(LoopRange::new_frame_range(
-                self.loop_start_buffer_index / self.source_channel_count,
-                self.loop_end_buffer_index / self.source_channel_count,
+                (self.loop_start_buffer_index - self.buffer_start_index)
+                    / self.source_channel_count,
+                (self.loop_end_buffer_index - self.buffer_start_index) / self.source_channel_count,
             )),
         };
         let source = Self::new(
@@ -341,6 +402,12 @@ impl Node for SampleLoopNode {
             Event::PitchMultiplier(multiplier) => {
                 self.pitch_multiplier = *multiplier;
             }
+            Event::PitchBend(position) => {
+                self.pitch_bend.set_position(*position);
+            }
+            Event::PitchBendRange(semitones) => {
+                self.pitch_bend.set_range(*semitones);
+            }
             Event::SourceBalance(balance) => {
                 self.balance = *balance;
             }
@@ -370,56 +437,14 @@ impl Node for SampleLoopNode {
     fn propagate(&mut self, _event: &Message) {}
 
     fn fill_buffer(&mut self, buffer: &mut [f32]) {
-        if buffer.is_empty() {
+        if !self.pitch_bend.is_gliding() {
+            self.fill_at_current_pitch(buffer);
             return;
         }
-
-        if self.is_on && self.data_position >= self.loop_end_buffer_index {
-            self.data_position -= self.loop_end_buffer_index - self.loop_start_buffer_index;
-        }
-
-        // Scaling
-        let relative_pitch = self.pitch_multiplier as f64
-            * util::relative_pitch_ratio_of(self.current_note, self.source_note) as f64;
-        let source_frames_per_output_frame = relative_pitch * self.playback_scale;
-
-        #[cfg(debug_assertions)]
-        assert_eq!(buffer.len() % consts::CHANNEL_COUNT, 0);
-
-        let mut remaining_buffer = &mut buffer[0..];
-        while !remaining_buffer.is_empty() {
-            let sample_end_index = self.buffer_start_index + self.buffer_length_samples;
-            if self.data_position >= sample_end_index {
-                self.is_on = false;
-                return;
-            }
-
-            let source_end_point = match self.is_on {
-                true => sample_end_index.min(self.loop_end_buffer_index),
-                false => sample_end_index,
-            };
-
-            let (src_data_points_advanced, dst_data_points_advanced) = self.stretch_buffer(
-                &self.sample_buffer[self.data_position..source_end_point],
-                self.source_channel_count,
-                remaining_buffer,
-                source_frames_per_output_frame,
-            );
-
-            self.data_position += src_data_points_advanced;
-
-            if self.data_position != source_end_point {
-                break;
-            }
-            if self.is_on && source_end_point == self.loop_end_buffer_index {
-                self.data_position = self.loop_start_buffer_index;
-                let remaining_dst_data_points = remaining_buffer.len() - dst_data_points_advanced;
-                let dst_buffer_index = buffer.len() - remaining_dst_data_points;
-                remaining_buffer = &mut buffer[dst_buffer_index..];
-            } else {
-                self.is_on = false;
-                return;
-            }
+        for section in buffer.chunks_mut(BEND_GLIDE_SECTION_FRAMES * consts::CHANNEL_COUNT) {
+            self.pitch_bend
+                .advance(section.len() / consts::CHANNEL_COUNT);
+            self.fill_at_current_pitch(section);
         }
     }
 
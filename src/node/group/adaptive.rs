@@ -0,0 +1,421 @@
+use super::util::GainRamp;
+use crate::{
+    AssetLoader, Error, Event, GraphNode, Message, Node,
+    abstraction::{ChildConfig, NodeConfig, defaults},
+    consts,
+    midi::{Anchor, CueData, Midi, MidiNode},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Music for a state: a segment, played from its start or from a cue anchor
+/// within it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MusicState {
+    pub segment: String,
+    #[serde(default)]
+    pub anchor: Option<Anchor>,
+    /// Anchor marking the end of the state's music within the segment, such
+    /// as one just before the cue that loops it, for `EndOfSegment`
+    /// transitions out of the state to wait for
+    #[serde(default)]
+    pub end_anchor: Option<Anchor>,
+}
+
+/// Point in the playing segment at which a transition happens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum TransitionTiming {
+    #[default]
+    Immediate,
+    NextBeat,
+    NextBar,
+    /// The next good point to seek from, written `?` in cue labels
+    NextIdealPoint,
+    /// The state's end anchor, or the last event of the segment
+    EndOfSegment,
+}
+
+/// Rule for moving between states. The first rule in a list that matches
+/// both states is used.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MusicTransition {
+    /// State being left, or any state if None
+    #[serde(default)]
+    pub from: Option<String>,
+    /// State being entered, or any state if None
+    #[serde(default)]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub timing: TransitionTiming,
+    /// Segment played once between the two states, which the new state's
+    /// music follows on from without a fade
+    #[serde(default)]
+    pub via: Option<String>,
+    /// Seconds for the music being left to fade out
+    #[serde(default)]
+    pub fade_out: f32,
+    /// Seconds for the music entered to fade in
+    #[serde(default)]
+    pub fade_in: f32,
+}
+
+impl MusicTransition {
+    fn matches(&self, from: &str, to: &str) -> bool {
+        self.from.as_deref().is_none_or(|state| state == from)
+            && self.to.as_deref().is_none_or(|state| state == to)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AdaptiveMusic {
+    #[serde(default = "defaults::none_id")]
+    pub node_id: Option<u64>,
+    /// Music sequences, by name
+    pub segments: HashMap<String, Midi>,
+    pub states: HashMap<String, MusicState>,
+    #[serde(default)]
+    pub initial_state: Option<String>,
+    #[serde(default)]
+    pub transitions: Vec<MusicTransition>,
+}
+
+impl NodeConfig for AdaptiveMusic {
+    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
+        let mut segments: HashMap<String, MidiNode> = HashMap::new();
+        for (name, config) in self.segments.iter() {
+            segments.insert(name.clone(), config.to_midi_node(asset_loader)?);
+        }
+        let mut node = AdaptiveMusicNode::new(
+            self.node_id,
+            segments,
+            self.states.clone(),
+            self.transitions.clone(),
+        )?;
+        if let Some(state) = &self.initial_state {
+            node.set_state(state);
+        }
+        Ok(Box::new(node))
+    }
+
+    fn clone_child_configs(&self) -> Option<Vec<ChildConfig>> {
+        Some(
+            self.segments
+                .values()
+                .map(|config| ChildConfig(Box::new(config.clone())))
+                .collect(),
+        )
+    }
+
+    fn asset_source(&self) -> Option<&str> {
+        None
+    }
+
+    fn duplicate(&self) -> Box<dyn NodeConfig + Send + Sync + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+struct Segment {
+    node: MidiNode,
+    gain: GainRamp,
+    is_audible: bool,
+}
+
+/// Transition waiting for its point in the lead segment
+struct PendingTransition {
+    to_state: String,
+    transition: MusicTransition,
+}
+
+/// Plays one of a set of named music states, moving between them at musical
+/// points in the playing segment as set by transition rules
+pub struct AdaptiveMusicNode {
+    node_id: u64,
+    segments: HashMap<String, Segment>,
+    states: HashMap<String, MusicState>,
+    transitions: Vec<MusicTransition>,
+    current_state: Option<String>,
+    /// Segment that pending transitions are timed against
+    lead_segment: Option<String>,
+    pending: Option<PendingTransition>,
+    intermediate_buffer: Vec<f32>,
+}
+
+impl AdaptiveMusicNode {
+    pub fn new(
+        node_id: Option<u64>,
+        segments: HashMap<String, MidiNode>,
+        states: HashMap<String, MusicState>,
+        transitions: Vec<MusicTransition>,
+    ) -> Result<Self, Error> {
+        let segment_names = states
+            .values()
+            .map(|state| &state.segment)
+            .chain(transitions.iter().filter_map(|rule| rule.via.as_ref()));
+        for name in segment_names {
+            if !segments.contains_key(name) {
+                return Err(Error::User(format!(
+                    "ERROR: AdaptiveMusic: No segment \"{}\"",
+                    name
+                )));
+            }
+        }
+        Ok(Self {
+            node_id: node_id.unwrap_or_else(<Self as Node>::new_node_id),
+            segments: segments
+                .into_iter()
+                .map(|(name, node)| {
+                    let segment = Segment {
+                        node,
+                        gain: GainRamp::default(),
+                        is_audible: false,
+                    };
+                    (name, segment)
+                })
+                .collect(),
+            states,
+            transitions,
+            current_state: None,
+            lead_segment: None,
+            pending: None,
+            intermediate_buffer: vec![0.0; consts::BUFFER_SIZE * consts::CHANNEL_COUNT],
+        })
+    }
+
+    /// The state most recently entered
+    pub fn current_state(&self) -> Option<&str> {
+        self.current_state.as_deref()
+    }
+
+    /// Move to a state, by the first transition rule matching the current
+    /// state and this one. With nothing playing, the state starts straight
+    /// away. Setting a state while a transition waits replaces its target.
+    pub fn set_state(&mut self, name: &str) {
+        if !self.states.contains_key(name) {
+            println!("WARNING: AdaptiveMusic: No state \"{}\"", name);
+            return;
+        }
+        let Some(current_state) = self.current_state.clone() else {
+            self.enter_state(name, &MusicTransition::default());
+            return;
+        };
+        if current_state == name && self.lead_is_current_state() {
+            self.pending = None;
+            return;
+        }
+        let transition = self
+            .transitions
+            .iter()
+            .find(|rule| rule.matches(&current_state, name))
+            .cloned()
+            .unwrap_or_default();
+        self.pending = Some(PendingTransition {
+            to_state: name.to_owned(),
+            transition,
+        });
+    }
+
+    fn lead_is_current_state(&self) -> bool {
+        let state_segment = self
+            .current_state
+            .as_ref()
+            .and_then(|state| self.states.get(state))
+            .map(|state| &state.segment);
+        state_segment.is_some() && state_segment == self.lead_segment.as_ref()
+    }
+
+    /// End anchor of the current state, while its segment is leading
+    fn current_end_anchor(&self) -> Option<&Anchor> {
+        if !self.lead_is_current_state() {
+            return None;
+        }
+        self.current_state
+            .as_ref()
+            .and_then(|state| self.states.get(state))
+            .and_then(|state| state.end_anchor.as_ref())
+    }
+
+    /// Frames until the pending transition is due, if it has a lead segment
+    /// to be timed against
+    fn frames_until_transition(&self, pending: &PendingTransition) -> usize {
+        let Some(lead) = self
+            .lead_segment
+            .as_ref()
+            .and_then(|name| self.segments.get(name))
+        else {
+            return 0;
+        };
+        let node = &lead.node;
+        if node.has_finished() {
+            return 0;
+        }
+        let current_tick = node.playback_tick();
+        let target_tick = match pending.transition.timing {
+            TransitionTiming::Immediate => return 0,
+            TransitionTiming::NextBeat => node.tempo_map().next_beat_tick(current_tick),
+            TransitionTiming::NextBar => node.tempo_map().next_bar_tick(current_tick),
+            TransitionTiming::NextIdealPoint => node.next_event_tick(|data| {
+                matches!(data, Event::CueData(CueData::GoodPointToSeekFrom))
+            }),
+            TransitionTiming::EndOfSegment => match self.current_end_anchor() {
+                Some(anchor) => node.next_event_tick(
+                    |data| matches!(data, Event::CueData(CueData::TargetMarker(a)) if a == anchor),
+                ),
+                None => Some(node.end_tick()),
+            },
+        };
+        match target_tick {
+            Some(target_tick) => node.samples_until_tick(target_tick),
+            None => {
+                println!(
+                    "WARNING: AdaptiveMusic: No {:?} point ahead; moving to state \"{}\" now",
+                    pending.transition.timing, pending.to_state
+                );
+                0
+            }
+        }
+    }
+
+    /// Carry out the pending transition, either into its transition segment
+    /// or into the new state
+    fn run_transition(&mut self, pending: PendingTransition) {
+        let transition = pending.transition;
+        let Some(via) = transition.via.clone() else {
+            self.enter_state(&pending.to_state, &transition);
+            return;
+        };
+        self.fade_out_lead(&via, transition.fade_out);
+        self.start_segment(&via, None, transition.fade_in);
+        self.lead_segment = Some(via);
+        self.pending = Some(PendingTransition {
+            to_state: pending.to_state,
+            transition: MusicTransition {
+                timing: TransitionTiming::EndOfSegment,
+                ..MusicTransition::default()
+            },
+        });
+    }
+
+    fn enter_state(&mut self, name: &str, transition: &MusicTransition) {
+        let Some(state) = self.states.get(name).cloned() else {
+            return;
+        };
+        self.fade_out_lead(&state.segment, transition.fade_out);
+        self.start_segment(&state.segment, state.anchor.as_ref(), transition.fade_in);
+        self.lead_segment = Some(state.segment);
+        self.current_state = Some(name.to_owned());
+    }
+
+    fn fade_out_lead(&mut self, next_segment: &str, seconds: f32) {
+        let lead = self
+            .lead_segment
+            .as_ref()
+            .filter(|lead| lead.as_str() != next_segment)
+            .and_then(|lead| self.segments.get_mut(lead));
+        if let Some(lead) = lead {
+            lead.gain.fade_to(0.0, seconds);
+        }
+    }
+
+    /// Play a segment from its start or an anchor. A segment already leading
+    /// seeks without a fade.
+    fn start_segment(&mut self, name: &str, anchor: Option<&Anchor>, fade_in: f32) {
+        let is_lead = self.lead_segment.as_deref() == Some(name);
+        let Some(segment) = self.segments.get_mut(name) else {
+            return;
+        };
+        segment.node.restart();
+        if let Some(anchor) = anchor {
+            segment.node.seek_to_anchor(anchor);
+        }
+        if !is_lead {
+            segment.gain.current = 0.0;
+            segment.gain.fade_to(1.0, fade_in);
+        }
+        segment.is_audible = true;
+    }
+
+    fn fill_segments(&mut self, buffer: &mut [f32]) {
+        if self.intermediate_buffer.len() < buffer.len() {
+            self.intermediate_buffer.resize(buffer.len(), 0.0);
+        }
+        let intermediate_buffer = &mut self.intermediate_buffer[0..buffer.len()];
+        for segment in self.segments.values_mut() {
+            if !segment.is_audible {
+                continue;
+            }
+            intermediate_buffer.fill(0.0);
+            segment.node.fill_buffer(intermediate_buffer);
+            segment.gain.mix_into(intermediate_buffer, buffer);
+            if segment.gain.is_silent() {
+                segment.is_audible = false;
+            }
+        }
+    }
+}
+
+impl Node for AdaptiveMusicNode {
+    fn get_node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: u64) {
+        self.node_id = node_id;
+    }
+
+    fn duplicate(&self) -> Result<GraphNode, Error> {
+        Err(Error::User(
+            "AdaptiveMusicNode cannot be duplicated".to_owned(),
+        ))
+    }
+
+    fn try_consume_event(&mut self, event: &Message) -> bool {
+        match &event.data {
+            Event::MusicState(name) => {
+                self.set_state(name);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn propagate(&mut self, event: &Message) {
+        for segment in self.segments.values_mut() {
+            segment.node.on_event(event);
+        }
+    }
+
+    fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        let mut remaining_buffer = buffer;
+        while let Some(pending) = self.pending.take() {
+            let frames = remaining_buffer.len() / consts::CHANNEL_COUNT;
+            let frames_until = self.frames_until_transition(&pending);
+            if frames_until >= frames {
+                self.pending = Some(pending);
+                break;
+            }
+            let (before, after) =
+                remaining_buffer.split_at_mut(frames_until * consts::CHANNEL_COUNT);
+            self.fill_segments(before);
+            self.run_transition(pending);
+            remaining_buffer = after;
+        }
+        self.fill_segments(remaining_buffer);
+    }
+
+    fn replace_children(&mut self, children: &[GraphNode]) -> Result<(), Error> {
+        match children.is_empty() {
+            true => Ok(()),
+            false => Err(Error::User(
+                "AdaptiveMusicNode does not support replacing its children".to_owned(),
+            )),
+        }
+    }
+
+    fn get_state_snapshot(&self, for_node_id: u64) -> Option<Result<Value, Error>> {
+        self.segments
+            .values()
+            .find_map(|segment| segment.node.get_state_snapshot(for_node_id))
+    }
+}
@@ -0,0 +1,196 @@
+use super::util::GainRamp;
+use crate::{
+    AssetLoader, Error, Event, GraphNode, Message, Node,
+    abstraction::{ChildConfig, NodeConfig, defaults},
+    consts,
+    midi::{Midi, MidiNode},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Clone)]
+pub struct Arrangements {
+    #[serde(default = "defaults::none_id")]
+    pub node_id: Option<u64>,
+    /// Arrangements of one song on the same timeline, by name
+    pub arrangements: HashMap<String, Midi>,
+    pub selected: String,
+}
+
+impl NodeConfig for Arrangements {
+    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
+        let mut arrangements: HashMap<String, MidiNode> = HashMap::new();
+        for (name, config) in self.arrangements.iter() {
+            arrangements.insert(name.clone(), config.to_midi_node(asset_loader)?);
+        }
+        Ok(Box::new(ArrangementsNode::new(
+            self.node_id,
+            arrangements,
+            &self.selected,
+        )?))
+    }
+
+    fn clone_child_configs(&self) -> Option<Vec<ChildConfig>> {
+        Some(
+            self.arrangements
+                .values()
+                .map(|config| ChildConfig(Box::new(config.clone())))
+                .collect(),
+        )
+    }
+
+    fn asset_source(&self) -> Option<&str> {
+        None
+    }
+
+    fn duplicate(&self) -> Box<dyn NodeConfig + Send + Sync + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+struct Arrangement {
+    node: MidiNode,
+    gain: GainRamp,
+    is_audible: bool,
+}
+
+/// Holds arrangements of one song in lockstep, so that switching crossfades
+/// to another arrangement at the same musical position. Only the selected
+/// arrangement is heard; the rest are brought to its position, tempo and
+/// channel mix as they are switched to, rather than being played silently. An arrangement switched
+/// back to while still fading out is already in step, so its fade is turned
+/// around without seeking it.
+pub struct ArrangementsNode {
+    node_id: u64,
+    arrangements: HashMap<String, Arrangement>,
+    selected: String,
+    intermediate_buffer: Vec<f32>,
+}
+
+impl ArrangementsNode {
+    pub fn new(
+        node_id: Option<u64>,
+        arrangements: HashMap<String, MidiNode>,
+        selected: &str,
+    ) -> Result<Self, Error> {
+        if !arrangements.contains_key(selected) {
+            return Err(Error::User(format!(
+                "ERROR: Arrangements: No arrangement \"{}\" to select",
+                selected
+            )));
+        }
+        let arrangements = arrangements
+            .into_iter()
+            .map(|(name, node)| {
+                let arrangement = Arrangement {
+                    node,
+                    gain: GainRamp::default(),
+                    is_audible: name == selected,
+                };
+                (name, arrangement)
+            })
+            .collect::<HashMap<String, Arrangement>>();
+        let mut node = Self {
+            node_id: node_id.unwrap_or_else(<Self as Node>::new_node_id),
+            arrangements,
+            selected: selected.to_owned(),
+            intermediate_buffer: vec![0.0; consts::BUFFER_SIZE * consts::CHANNEL_COUNT],
+        };
+        if let Some(arrangement) = node.arrangements.get_mut(selected) {
+            arrangement.gain.fade_to(1.0, 0.0);
+        }
+        Ok(node)
+    }
+
+    pub fn selected(&self) -> &str {
+        &self.selected
+    }
+
+    /// Crossfade over the given number of seconds to another arrangement,
+    /// which continues from the position the selected one has reached
+    pub fn select(&mut self, name: &str, seconds: f32) {
+        if name == self.selected {
+            return;
+        }
+        let [Some(current), Some(next)] = self
+            .arrangements
+            .get_disjoint_mut([self.selected.as_str(), name])
+        else {
+            println!("WARNING: Arrangements: No arrangement \"{}\"", name);
+            return;
+        };
+        if !next.is_audible {
+            next.node.align_to(&current.node);
+        }
+        current.gain.fade_to(0.0, seconds);
+        next.gain.fade_to(1.0, seconds);
+        next.is_audible = true;
+        self.selected = name.to_owned();
+    }
+}
+
+impl Node for ArrangementsNode {
+    fn get_node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: u64) {
+        self.node_id = node_id;
+    }
+
+    fn duplicate(&self) -> Result<GraphNode, Error> {
+        Err(Error::User(
+            "ArrangementsNode cannot be duplicated".to_owned(),
+        ))
+    }
+
+    fn try_consume_event(&mut self, event: &Message) -> bool {
+        match &event.data {
+            Event::Arrangement { name, seconds } => {
+                self.select(name, *seconds);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn propagate(&mut self, event: &Message) {
+        for arrangement in self.arrangements.values_mut() {
+            arrangement.node.on_event(event);
+        }
+    }
+
+    fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        if self.intermediate_buffer.len() < buffer.len() {
+            self.intermediate_buffer.resize(buffer.len(), 0.0);
+        }
+        let intermediate_buffer = &mut self.intermediate_buffer[0..buffer.len()];
+        for arrangement in self.arrangements.values_mut() {
+            if !arrangement.is_audible {
+                continue;
+            }
+            intermediate_buffer.fill(0.0);
+            arrangement.node.fill_buffer(intermediate_buffer);
+            arrangement.gain.mix_into(intermediate_buffer, buffer);
+            if arrangement.gain.is_silent() {
+                arrangement.is_audible = false;
+            }
+        }
+    }
+
+    fn replace_children(&mut self, children: &[GraphNode]) -> Result<(), Error> {
+        match children.is_empty() {
+            true => Ok(()),
+            false => Err(Error::User(
+                "ArrangementsNode does not support replacing its children".to_owned(),
+            )),
+        }
+    }
+
+    fn get_state_snapshot(&self, for_node_id: u64) -> Option<Result<Value, Error>> {
+        self.arrangements
+            .values()
+            .find_map(|arrangement| arrangement.node.get_state_snapshot(for_node_id))
+    }
+}
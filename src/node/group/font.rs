@@ -35,10 +35,32 @@ pub enum FontSource {
 #[derive(Deserialize, Serialize, Clone)]
 pub struct FontFileMetadata {
     pub instruments: Vec<InstrumentMetadata>,
+    #[serde(default)]
+    pub presets: Vec<PresetMetadata>,
 }
 
 impl FontFileMetadata {
     fn from_spec(sf2: &SoundFont2) -> Result<Self, Error> {
+        let mut presets: Vec<PresetMetadata> = vec![];
+        for preset in sf2.presets.iter() {
+            let zones = preset
+                .zones
+                .iter()
+                .filter_map(|zone| {
+                    let instrument_index = *zone.instrument()? as usize;
+                    Some(PresetZoneMetadata {
+                        note_range: font_util::note_range_for_zone(zone),
+                        velocity_range: font_util::velocity_range_for_zone(zone),
+                        instrument_index,
+                    })
+                })
+                .collect();
+            presets.push(PresetMetadata {
+                bank: preset.header.bank,
+                program: preset.header.preset,
+                zones,
+            });
+        }
         let mut instruments: Vec<InstrumentMetadata> = vec![];
         for instrument in sf2.instruments.iter() {
             let mut ranges: Vec<InstrumentRangeMetadata> = vec![];
@@ -54,8 +76,10 @@ impl FontFileMetadata {
                     );
                     continue;
                 };
-                let channel_count = match sample_header.sample_type {
-                    SampleLink::MonoSample => 1,
+                let (channel_count, balance) = match sample_header.sample_type {
+                    SampleLink::MonoSample => (1, Balance::Both),
+                    SampleLink::LeftSample => (1, Balance::Left),
+                    SampleLink::RightSample => (1, Balance::Right),
                     _ => {
                         return Err(Error::User(format!(
                             "Unsupported sample type for SF2 files: {:?}",
@@ -66,16 +90,18 @@ impl FontFileMetadata {
 
                 let data_offset = sample_header.start as usize;
                 let sample_count = sample_header.end as usize - data_offset;
-                let note_range = font_util::note_range_for_zone(zone)?;
+                let note_range = font_util::note_range_for_zone(zone);
                 let loop_range = LoopRange::new_frame_range(
                     (sample_header.loop_start as usize - data_offset) / channel_count,
                     (sample_header.loop_end as usize - data_offset) / channel_count,
                 );
                 ranges.push(InstrumentRangeMetadata {
                     note_range,
+                    velocity_range: font_util::velocity_range_for_zone(zone),
                     channel_count,
                     sample_rate: sample_header.sample_rate,
                     base_note: sample_header.origpitch,
+                    balance,
                     loop_range,
                     buffer_index: data_offset,
                     buffer_length: sample_count,
@@ -83,10 +109,81 @@ impl FontFileMetadata {
             }
             instruments.push(InstrumentMetadata { ranges });
         }
-        Ok(Self { instruments })
+        Ok(Self {
+            instruments,
+            presets,
+        })
+    }
+
+    /// Preset for a bank and program number, as selected by MIDI bank select
+    /// and program change messages
+    pub fn find_preset(&self, bank: u16, program: u16) -> Option<&PresetMetadata> {
+        self.presets
+            .iter()
+            .find(|preset| preset.bank == bank && preset.program == program)
+    }
+}
+
+/// Load a SoundFont file's metadata and sample data, storing them with the
+/// asset loader as prepared data on first load
+pub(crate) fn load_sf2_file(
+    asset_loader: &mut dyn AssetLoader,
+    path: &str,
+) -> Result<(FontFileMetadata, SampleBuffer), Error> {
+    match asset_loader.load_asset_data(path)? {
+        AssetLoadPayload::RawAssetData(raw_data) => {
+            let mut cursor = Cursor::new(raw_data.as_slice());
+            let sf2 = SoundFont2::load(&mut cursor)?;
+            font_util::validate_sf2_file(&sf2)?;
+
+            if DebugLogging::get_log_on_init() {
+                font_util::log_opened_sf2(&sf2);
+            }
+
+            let sample_chunk_metadata = &sf2.sample_data.smpl.ok_or_else(|| {
+                Error::User("There was no sample header in the SF2 file".to_owned())
+            })?;
+
+            let data_point_size = std::mem::size_of::<i16>();
+            cursor.seek(SeekFrom::Start(sample_chunk_metadata.offset as u64))?;
+            let mut sample_data = vec![0i16; sample_chunk_metadata.len as usize / data_point_size];
+            cursor.read_i16_into::<LittleEndian>(&mut sample_data)?;
+
+            let float_buffer = sample_data
+                .into_iter()
+                .map(|s| s as f32 / 32768.0)
+                .collect();
+            let sample_buffer: SampleBuffer = Arc::new(float_buffer);
+
+            let metadata = FontFileMetadata::from_spec(&sf2)?;
+            let raw_metadata = Arc::new(serde_json::to_vec(&metadata)?);
+            asset_loader.store_prepared_data(path, raw_metadata.clone(), sample_buffer.clone());
+            Ok((metadata, sample_buffer))
+        }
+        AssetLoadPayload::PreparedData((raw_metadata, sample_buffer)) => {
+            let metadata: FontFileMetadata = serde_json::from_slice(&raw_metadata)?;
+            Ok((metadata, sample_buffer))
+        }
     }
 }
 
+/// Preset of a SoundFont, made of instruments over key ranges
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PresetMetadata {
+    pub bank: u16,
+    pub program: u16,
+    pub zones: Vec<PresetZoneMetadata>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PresetZoneMetadata {
+    pub note_range: NoteRange,
+    /// MIDI velocities (0 to 127) that the zone plays for
+    #[serde(default = "NoteRange::new_full_range")]
+    pub velocity_range: NoteRange,
+    pub instrument_index: usize,
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct InstrumentMetadata {
     pub ranges: Vec<InstrumentRangeMetadata>,
@@ -95,9 +192,15 @@ pub struct InstrumentMetadata {
 #[derive(Deserialize, Serialize, Clone)]
 pub struct InstrumentRangeMetadata {
     pub note_range: NoteRange,
+    /// MIDI velocities (0 to 127) that the range plays for
+    #[serde(default = "NoteRange::new_full_range")]
+    pub velocity_range: NoteRange,
     pub channel_count: usize,
     pub sample_rate: u32,
     pub base_note: u8,
+    /// Side that the sample plays on, for the halves of stereo samples
+    #[serde(default = "defaults::source_balance")]
+    pub balance: Balance,
     pub loop_range: LoopRange,
     pub buffer_index: usize,
     pub buffer_length: usize,
@@ -140,46 +243,7 @@ impl NodeConfig for Font {
                 instrument_index,
                 polyphony_voices,
             } => {
-                let (metadata, sample_buffer) = match asset_loader.load_asset_data(path)? {
-                    AssetLoadPayload::RawAssetData(raw_data) => {
-                        let mut cursor = Cursor::new(raw_data.as_slice());
-                        let sf2 = SoundFont2::load(&mut cursor)?;
-                        font_util::validate_sf2_file(&sf2)?;
-
-                        if DebugLogging::get_log_on_init() {
-                            font_util::log_opened_sf2(&sf2);
-                        }
-
-                        let sample_chunk_metadata = &sf2.sample_data.smpl.ok_or_else(|| {
-                            Error::User("There was no sample header in the SF2 file".to_owned())
-                        })?;
-
-                        let data_point_size = std::mem::size_of::<i16>();
-                        cursor.seek(SeekFrom::Start(sample_chunk_metadata.offset as u64))?;
-                        let mut sample_data =
-                            vec![0i16; sample_chunk_metadata.len as usize / data_point_size];
-                        cursor.read_i16_into::<LittleEndian>(&mut sample_data)?;
-
-                        let float_buffer = sample_data
-                            .into_iter()
-                            .map(|s| s as f32 / 32768.0)
-                            .collect();
-                        let sample_buffer: SampleBuffer = Arc::new(float_buffer);
-
-                        let metadata = FontFileMetadata::from_spec(&sf2)?;
-                        let raw_metadata = Arc::new(serde_json::to_vec(&metadata)?);
-                        asset_loader.store_prepared_data(
-                            path,
-                            raw_metadata.clone(),
-                            sample_buffer.clone(),
-                        );
-                        (metadata, sample_buffer)
-                    }
-                    AssetLoadPayload::PreparedData((raw_metadata, sample_buffer)) => {
-                        let metadata: FontFileMetadata = serde_json::from_slice(&raw_metadata)?;
-                        (metadata, sample_buffer)
-                    }
-                };
+                let (metadata, sample_buffer) = load_sf2_file(asset_loader, path)?;
 
                 let Some(instrument) = metadata.instruments.get(*instrument_index) else {
                     return Err(Error::User(format!(
@@ -197,7 +261,7 @@ impl NodeConfig for Font {
                         range.channel_count,
                         range.base_note,
                         Some(range.loop_range.clone()),
-                        Balance::Both,
+                        range.balance,
                         sample_buffer.clone(),
                         range.buffer_index as usize,
                         range.buffer_length as usize,
@@ -250,7 +314,7 @@ impl NodeConfig for Font {
 
 pub struct FontNodeBuilder {
     node_id: Option<u64>,
-    ranges: Vec<(NoteRange, GraphNode)>,
+    ranges: Vec<(NoteRange, NoteRange, GraphNode)>,
 }
 
 impl Default for FontNodeBuilder {
@@ -267,8 +331,19 @@ impl FontNodeBuilder {
         }
     }
 
-    pub fn add_range(mut self, range: NoteRange, consumer: GraphNode) -> Result<Self, Error> {
-        self.ranges.push((range, consumer));
+    pub fn add_range(self, range: NoteRange, consumer: GraphNode) -> Result<Self, Error> {
+        self.add_velocity_layer(range, NoteRange::new_full_range(), consumer)
+    }
+
+    /// Add a source for the notes of a range that are played at MIDI
+    /// velocities (0 to 127) within another range
+    pub fn add_velocity_layer(
+        mut self,
+        range: NoteRange,
+        velocity_range: NoteRange,
+        consumer: GraphNode,
+    ) -> Result<Self, Error> {
+        self.ranges.push((range, velocity_range, consumer));
         Ok(self)
     }
 
@@ -277,13 +352,16 @@ impl FontNodeBuilder {
     }
 }
 
+/// Sources over ranges of notes, each playing the notes of its range that
+/// are started at velocities within its velocity range. Note-offs go to every
+/// source whose range has the note, since only the one playing it responds.
 pub struct FontNode {
     node_id: u64,
-    ranges: Vec<(NoteRange, GraphNode)>,
+    ranges: Vec<(NoteRange, NoteRange, GraphNode)>,
 }
 
 impl FontNode {
-    fn new(node_id: Option<u64>, ranges: Vec<(NoteRange, GraphNode)>) -> Self {
+    fn new(node_id: Option<u64>, ranges: Vec<(NoteRange, NoteRange, GraphNode)>) -> Self {
         Self {
             node_id: node_id.unwrap_or_else(<Self as Node>::new_node_id),
             ranges,
@@ -305,21 +383,30 @@ impl Node for FontNode {
     }
 
     fn try_consume_event(&mut self, event: &Message) -> bool {
-        let note = match event.data {
-            Event::NoteOn { note, .. } => Some(note),
-            Event::NoteOff { note, .. } => Some(note),
-            _ => None,
+        let (note, velocity) = match event.data {
+            Event::NoteOn { note, vel } if vel > 0.0 => (
+                Some(note),
+                Some((vel.clamp(0.0, 1.0) * 127.0).round() as u8),
+            ),
+            Event::NoteOn { note, .. } => (Some(note), None),
+            Event::NoteOff { note, .. } => (Some(note), None),
+            _ => (None, None),
         };
         if note.is_some() {
             let note = note.unwrap();
-            for (range, consumer) in self.ranges.iter_mut() {
+            for (range, velocity_range, consumer) in self.ranges.iter_mut() {
                 if !range.contains(note) {
                     continue;
                 }
+                if let Some(velocity) = velocity
+                    && !velocity_range.contains(velocity)
+                {
+                    continue;
+                }
                 consumer.on_event(event);
             }
         } else {
-            for (_, consumer) in self.ranges.iter_mut() {
+            for (_, _, consumer) in self.ranges.iter_mut() {
                 consumer.on_event(event);
             }
         }
@@ -327,13 +414,13 @@ impl Node for FontNode {
     }
 
     fn propagate(&mut self, event: &Message) {
-        for (_, consumer) in self.ranges.iter_mut() {
+        for (_, _, consumer) in self.ranges.iter_mut() {
             consumer.on_event(event);
         }
     }
 
     fn fill_buffer(&mut self, buffer: &mut [f32]) {
-        for (_, consumer) in self.ranges.iter_mut() {
+        for (_, _, consumer) in self.ranges.iter_mut() {
             consumer.fill_buffer(buffer);
         }
     }
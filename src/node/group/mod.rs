@@ -1,3 +1,5 @@
+pub mod adaptive;
+pub mod arrangements;
 pub mod combiner;
 pub mod font;
 pub mod mixer;
@@ -1,6 +1,8 @@
 use crate::{
     AssetLoader, Error, Event, GraphNode, Message, Node,
     abstraction::{ChildConfig, NodeConfig, defaults},
+    midi::{ControllerBinding, ControllerMapper, ControllerSource},
+    node::midi::controller::{CC_RESET_ALL_CONTROLLERS, CC_SOSTENUTO, CC_SUSTAIN},
 };
 use serde::Deserialize;
 use serde_json::Value;
@@ -12,6 +14,10 @@ pub struct Polyphony {
     #[serde(default = "defaults::max_voices")]
     pub max_voices: usize,
     pub source: ChildConfig,
+    /// Bindings for key pressure (polyphonic aftertouch), applied to each
+    /// voice separately
+    #[serde(default)]
+    pub pressure: Vec<ControllerBinding>,
 }
 
 impl Polyphony {
@@ -20,6 +26,7 @@ impl Polyphony {
             node_id: defaults::none_id(),
             max_voices: defaults::max_voices(),
             source: inner,
+            pressure: vec![],
         }))
     }
 }
@@ -27,11 +34,9 @@ impl Polyphony {
 impl NodeConfig for Polyphony {
     fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
         let child = self.source.0.to_node(asset_loader)?;
-        Ok(Box::new(PolyphonyNode::new(
-            self.node_id,
-            self.max_voices,
-            child,
-        )?))
+        let polyphony = PolyphonyNode::new(self.node_id, self.max_voices, child)?
+            .with_pressure_bindings(self.pressure.clone())?;
+        Ok(Box::new(polyphony))
     }
 
     fn clone_child_configs(&self) -> Option<Vec<ChildConfig>> {
@@ -50,12 +55,48 @@ impl NodeConfig for Polyphony {
 struct Voice {
     pub current_note: Option<u8>,
     pub source: GraphNode,
+    /// Velocity of a note-off received while a pedal holds the note
+    pub pending_off_vel: Option<f32>,
+    pub held_by_sostenuto: bool,
+    /// Count of notes started on the node when this voice's note started
+    pub started_at: u64,
+    /// Volume set by key pressure, applied on top of the channel volume
+    pub pressure_gain: f32,
+}
+
+impl Voice {
+    fn new(source: GraphNode) -> Self {
+        Self {
+            current_note: None,
+            source,
+            pending_off_vel: None,
+            held_by_sostenuto: false,
+            started_at: 0,
+            pressure_gain: 1.0,
+        }
+    }
+
+    fn release(&mut self, vel: f32) {
+        if let Some(note) = self.current_note {
+            let event = Message::broadcast(Event::NoteOff { note, vel });
+            self.source.on_event(&event);
+        }
+        self.current_note = None;
+        self.pending_off_vel = None;
+        self.held_by_sostenuto = false;
+    }
 }
 
 pub struct PolyphonyNode {
     node_id: u64,
     voices: Vec<Voice>,
-    next_on_index: usize,
+    notes_started: u64,
+    sustain_down: bool,
+    sostenuto_down: bool,
+    pressure_mapper: ControllerMapper,
+    /// Latest volume sent to the node, which each voice's pressure gain
+    /// multiplies
+    channel_volume: f32,
 }
 
 impl PolyphonyNode {
@@ -71,23 +112,129 @@ impl PolyphonyNode {
             )));
         }
         let mut voices = (0..(max_voices - 1))
-            .map(|_| {
-                consumer.duplicate().map(|source| Voice {
-                    current_note: None,
-                    source,
-                })
-            })
+            .map(|_| consumer.duplicate().map(Voice::new))
             .collect::<Result<Vec<Voice>, Error>>()?;
-        voices.push(Voice {
-            current_note: None,
-            source: consumer,
-        });
+        voices.push(Voice::new(consumer));
         Ok(Self {
             node_id: node_id.unwrap_or_else(<Self as Node>::new_node_id),
             voices,
-            next_on_index: 0,
+            notes_started: 0,
+            sustain_down: false,
+            sostenuto_down: false,
+            pressure_mapper: ControllerMapper::new_with_channel_count(vec![], max_voices)?,
+            channel_volume: 1.0,
         })
     }
+
+    /// Map key pressure to events for each voice. Channel pressure applies to
+    /// every voice alike, so is bound by the MIDI node's controller bindings.
+    pub fn with_pressure_bindings(
+        mut self,
+        bindings: Vec<ControllerBinding>,
+    ) -> Result<Self, Error> {
+        if let Some(binding) = bindings
+            .iter()
+            .find(|binding| binding.source != ControllerSource::KeyPressure)
+        {
+            return Err(Error::User(format!(
+                "ERROR: Polyphony: Pressure bindings must be for KeyPressure, not {:?}",
+                binding.source
+            )));
+        }
+        self.pressure_mapper =
+            ControllerMapper::new_with_channel_count(bindings, self.voices.len())?;
+        Ok(self)
+    }
+
+    fn apply_key_pressure(&mut self, index: usize, pressure: f32) {
+        let voice = &mut self.voices[index];
+        let channel_volume = self.channel_volume;
+        self.pressure_mapper.map_source(
+            index,
+            ControllerSource::KeyPressure,
+            pressure,
+            |message| match message.data {
+                Event::Volume(gain) => {
+                    voice.pressure_gain = gain;
+                    voice.source.on_event(&Message {
+                        data: Event::Volume(channel_volume * gain),
+                        ..message.clone()
+                    });
+                }
+                _ => voice.source.on_event(message),
+            },
+        );
+    }
+
+    fn set_channel_volume(&mut self, volume: f32) {
+        self.channel_volume = volume;
+        for voice in self.voices.iter_mut() {
+            let message = Message::broadcast(Event::Volume(volume * voice.pressure_gain));
+            voice.source.on_event(&message);
+        }
+    }
+
+    fn set_sustain(&mut self, down: bool) {
+        self.sustain_down = down;
+        if !down {
+            self.release_unheld_voices();
+        }
+    }
+
+    /// Sostenuto holds only the notes whose keys are down as it is pressed
+    fn set_sostenuto(&mut self, down: bool) {
+        if down && !self.sostenuto_down {
+            for voice in self.voices.iter_mut() {
+                voice.held_by_sostenuto =
+                    voice.current_note.is_some() && voice.pending_off_vel.is_none();
+            }
+        }
+        if !down {
+            for voice in self.voices.iter_mut() {
+                voice.held_by_sostenuto = false;
+            }
+        }
+        self.sostenuto_down = down;
+        if !down {
+            self.release_unheld_voices();
+        }
+    }
+
+    /// Voice for a new note: a free one, or else the voice whose key was
+    /// released longest ago, which only a pedal is still holding
+    fn voice_for_new_note(&mut self) -> Option<usize> {
+        if let Some(index) = self
+            .voices
+            .iter()
+            .position(|voice| voice.current_note.is_none())
+        {
+            return Some(index);
+        }
+        let index = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(_, voice)| voice.pending_off_vel.is_some())
+            .min_by_key(|(_, voice)| voice.started_at)
+            .map(|(index, _)| index)?;
+        self.voices[index].release(0.0);
+        Some(index)
+    }
+
+    /// Send the note-offs that were deferred while a pedal was down
+    fn release_unheld_voices(&mut self) {
+        if self.sustain_down {
+            return;
+        }
+        for voice in self.voices.iter_mut() {
+            if voice.held_by_sostenuto {
+                continue;
+            }
+            if let Some(vel) = voice.pending_off_vel {
+                voice.release(vel);
+            }
+        }
+    }
 }
 
 impl Node for PolyphonyNode {
@@ -103,17 +250,19 @@ impl Node for PolyphonyNode {
         let voices = self
             .voices
             .iter()
-            .map(|voice| {
-                voice.source.duplicate().map(|source| Voice {
-                    current_note: None,
-                    source,
-                })
-            })
+            .map(|voice| voice.source.duplicate().map(Voice::new))
             .collect::<Result<Vec<Voice>, Error>>()?;
         let polyphony = Self {
             node_id: self.node_id,
             voices,
-            next_on_index: 0,
+            notes_started: 0,
+            sustain_down: false,
+            sostenuto_down: false,
+            pressure_mapper: ControllerMapper::new_with_channel_count(
+                self.pressure_mapper.bindings().to_vec(),
+                self.voices.len(),
+            )?,
+            channel_volume: 1.0,
         };
         Ok(Box::new(polyphony))
     }
@@ -121,29 +270,37 @@ impl Node for PolyphonyNode {
     fn try_consume_event(&mut self, event: &Message) -> bool {
         match event.data {
             Event::NoteOn { note, .. } => {
-                if let Some(index) = self
-                    .voices
-                    .iter()
-                    .position(|voice| voice.current_note.is_none())
-                {
+                // A note struck again while held by a pedal restarts in a new voice
+                if let Some(voice) = self.voices.iter_mut().find(|voice| {
+                    voice.current_note == Some(note) && voice.pending_off_vel.is_some()
+                }) {
+                    voice.release(0.0);
+                }
+                if let Some(index) = self.voice_for_new_note() {
                     let broadcast_event = Message::broadcast(event.data.clone());
-                    self.voices[index].current_note = Some(note);
-                    self.voices[index].source.on_event(&broadcast_event);
+                    let voice = &mut self.voices[index];
+                    voice.current_note = Some(note);
+                    voice.started_at = self.notes_started;
+                    self.notes_started += 1;
+                    self.pressure_mapper.reset_channel(index);
+                    if voice.pressure_gain != 1.0 {
+                        voice.pressure_gain = 1.0;
+                        let volume = Event::Volume(self.channel_volume);
+                        voice.source.on_event(&Message::broadcast(volume));
+                    }
+                    voice.source.on_event(&broadcast_event);
                 }
                 true
             }
-            Event::NoteOff { note, .. } => {
-                if let Some(index) = self
-                    .voices
-                    .iter()
-                    .position(|voice| match voice.current_note {
-                        Some(current_note) => current_note == note,
-                        None => false,
-                    })
-                {
-                    let broadcast_event = Message::broadcast(event.data.clone());
-                    self.voices[index].source.on_event(&broadcast_event);
-                    self.voices[index].current_note = None;
+            Event::NoteOff { note, vel } => {
+                if let Some(index) = self.voices.iter().position(|voice| {
+                    voice.current_note == Some(note) && voice.pending_off_vel.is_none()
+                }) {
+                    let voice = &mut self.voices[index];
+                    match self.sustain_down || voice.held_by_sostenuto {
+                        true => voice.pending_off_vel = Some(vel),
+                        false => voice.release(vel),
+                    }
                 }
                 true
             }
@@ -153,10 +310,41 @@ impl Node for PolyphonyNode {
                     if voice.current_note.is_some() {
                         voice.source.on_event(&broadcast_event);
                         voice.current_note = None;
+                        voice.pending_off_vel = None;
+                        voice.held_by_sostenuto = false;
                     }
                 }
                 true
             }
+            Event::KeyPressure { note, pressure } => {
+                if let Some(index) = self
+                    .voices
+                    .iter()
+                    .position(|voice| voice.current_note == Some(note))
+                {
+                    let broadcast_event = Message::broadcast(event.data.clone());
+                    self.voices[index].source.on_event(&broadcast_event);
+                    self.apply_key_pressure(index, pressure);
+                }
+                true
+            }
+            Event::Volume(volume) => {
+                self.set_channel_volume(volume);
+                true
+            }
+            // Pedal changes are tracked here but still passed on to the voices
+            Event::Controller { controller, value } => {
+                match controller {
+                    CC_SUSTAIN => self.set_sustain(value >= 64),
+                    CC_SOSTENUTO => self.set_sostenuto(value >= 64),
+                    CC_RESET_ALL_CONTROLLERS => {
+                        self.set_sostenuto(false);
+                        self.set_sustain(false);
+                    }
+                    _ => {}
+                }
+                false
+            }
             _ => false,
         }
     }
@@ -181,14 +369,8 @@ impl Node for PolyphonyNode {
         }
 
         self.voices = (0..(self.voices.len()))
-            .map(|_| {
-                children[0].duplicate().map(|source| Voice {
-                    current_note: None,
-                    source,
-                })
-            })
+            .map(|_| children[0].duplicate().map(Voice::new))
             .collect::<Result<Vec<Voice>, Error>>()?;
-        self.next_on_index = 0;
         Ok(())
     }
 
@@ -1,4 +1,4 @@
-use crate::{Error, NoteRange};
+use crate::{Error, NoteRange, consts};
 use soundfont::{
     SfEnum, SoundFont2, Zone,
     data::{GeneratorAmount, GeneratorType},
@@ -12,9 +12,6 @@ pub fn validate_sf2_file(sf2: &SoundFont2) -> Result<(), Error> {
         )));
     }
 
-    if !sf2.presets.is_empty() {
-        println!("WARNING: SF2: File has presets; these will be ignored");
-    }
     if sf2.instruments.is_empty() {
         return Err(Error::User("The SF2 file has no instruments".to_owned()));
     }
@@ -30,15 +27,63 @@ pub fn log_opened_sf2(sf2: &SoundFont2) {
     );
 }
 
-pub fn note_range_for_zone(zone: &Zone) -> Result<NoteRange, Error> {
+/// Key range of a zone, which covers every note if the zone doesn't set one
+pub fn note_range_for_zone(zone: &Zone) -> NoteRange {
+    range_for_zone(zone, GeneratorType::KeyRange)
+}
+
+/// Velocity range of a zone, which covers every velocity if the zone doesn't
+/// set one
+pub fn velocity_range_for_zone(zone: &Zone) -> NoteRange {
+    range_for_zone(zone, GeneratorType::VelRange)
+}
+
+fn range_for_zone(zone: &Zone, range_type: GeneratorType) -> NoteRange {
     for generator in zone.gen_list.iter() {
-        if let SfEnum::Value(GeneratorType::KeyRange) = generator.ty {
+        if generator.ty == SfEnum::Value(range_type) {
             if let GeneratorAmount::Range(range) = generator.amount {
-                return Ok(NoteRange::new_inclusive_range(range.low, range.high));
+                return NoteRange::new_inclusive_range(range.low, range.high);
+            }
+        }
+    }
+    NoteRange::new_full_range()
+}
+
+/// Gain of a child node, ramping linearly towards its target
+#[derive(Default)]
+pub struct GainRamp {
+    pub current: f32,
+    target: f32,
+    step_per_frame: f32,
+}
+
+impl GainRamp {
+    pub fn fade_to(&mut self, target: f32, seconds: f32) {
+        self.target = target;
+        let frames = seconds.max(0.0) * consts::PLAYBACK_SAMPLE_RATE as f32;
+        match frames < 1.0 {
+            true => self.current = target,
+            false => self.step_per_frame = (target - self.current).abs() / frames,
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.current == 0.0 && self.target == 0.0
+    }
+
+    pub fn mix_into(&mut self, source: &[f32], output: &mut [f32]) {
+        for (source, output) in source
+            .chunks(consts::CHANNEL_COUNT)
+            .zip(output.chunks_mut(consts::CHANNEL_COUNT))
+        {
+            if self.current < self.target {
+                self.current = (self.current + self.step_per_frame).min(self.target);
+            } else if self.current > self.target {
+                self.current = (self.current - self.step_per_frame).max(self.target);
+            }
+            for (source, output) in source.iter().zip(output.iter_mut()) {
+                *output += source * self.current;
             }
         }
     }
-    Err(Error::User(
-        "No key range found in an instrument zone in the SF2 file".to_owned(),
-    ))
 }
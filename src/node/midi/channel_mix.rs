@@ -0,0 +1,258 @@
+use crate::{consts, node::midi::routing::FALLBACK_DESTINATION};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, hash_map::Entry};
+
+/// Volume, mute and solo settings of one MIDI channel, as saved in a MIDI
+/// node's state snapshot
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelMixSettings {
+    pub channel: usize,
+    pub volume: f32,
+    pub muted: bool,
+    pub soloed: bool,
+    /// Fade still in progress towards these settings, if any
+    #[serde(default)]
+    pub fade: Option<ChannelFade>,
+}
+
+/// Gain a channel has reached while fading, and the frames left to go
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelFade {
+    pub gain: f32,
+    pub frames_remaining: usize,
+}
+
+/// Gain applied to one MIDI channel's output, which fades towards its target
+/// over a set time
+#[derive(Clone)]
+struct ChannelGain {
+    volume: f32,
+    muted: bool,
+    soloed: bool,
+    current: f32,
+    target: f32,
+    step_per_frame: f32,
+    frames_remaining: usize,
+}
+
+impl Default for ChannelGain {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+            soloed: false,
+            current: 1.0,
+            target: 1.0,
+            step_per_frame: 0.0,
+            frames_remaining: 0,
+        }
+    }
+}
+
+impl ChannelGain {
+    fn fade_to(&mut self, target: f32, seconds: f32) {
+        if target == self.target {
+            return;
+        }
+        let frames = (seconds.max(0.0) * consts::PLAYBACK_SAMPLE_RATE as f32) as usize;
+        self.fade_over_frames(target, frames);
+    }
+
+    fn fade_over_frames(&mut self, target: f32, frames: usize) {
+        self.target = target;
+        if frames == 0 {
+            self.current = target;
+            self.frames_remaining = 0;
+            return;
+        }
+        self.step_per_frame = (target - self.current) / frames as f32;
+        self.frames_remaining = frames;
+    }
+
+    fn has_default_settings(&self) -> bool {
+        self.volume == 1.0 && !self.muted && !self.soloed && self.frames_remaining == 0
+    }
+
+    fn is_unity(&self) -> bool {
+        self.frames_remaining == 0 && self.current == 1.0
+    }
+
+    fn is_silent(&self) -> bool {
+        self.frames_remaining == 0 && self.current == 0.0
+    }
+
+    fn mix_into(&mut self, source: &[f32], buffer: &mut [f32]) {
+        for (source_frame, frame) in source
+            .chunks_exact(consts::CHANNEL_COUNT)
+            .zip(buffer.chunks_exact_mut(consts::CHANNEL_COUNT))
+        {
+            if self.frames_remaining > 0 {
+                self.frames_remaining -= 1;
+                self.current = match self.frames_remaining {
+                    0 => self.target,
+                    _ => self.current + self.step_per_frame,
+                };
+            }
+            for (sample, data) in source_frame.iter().zip(frame.iter_mut()) {
+                *data += sample * self.current;
+            }
+        }
+    }
+}
+
+/// Per-channel volume, mute and solo for a MIDI node, used for bringing
+/// layers of an arrangement in and out
+pub struct ChannelMix {
+    gains: HashMap<usize, ChannelGain>,
+    intermediate_buffer: Vec<f32>,
+}
+
+impl Default for ChannelMix {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelMix {
+    /// Starts with gains for every MIDI channel and the fallback destination,
+    /// so rendering them never allocates
+    pub fn new() -> Self {
+        let mut mix = Self {
+            gains: HashMap::new(),
+            intermediate_buffer: vec![0.0; consts::BUFFER_SIZE * consts::CHANNEL_COUNT],
+        };
+        mix.prepare_channels(0..=FALLBACK_DESTINATION);
+        mix
+    }
+
+    /// Add gains for channels or destinations that don't have one yet. Call
+    /// this off the audio thread, before they are rendered.
+    pub fn prepare_channels(&mut self, channels: impl Iterator<Item = usize>) {
+        let mut added = false;
+        for channel in channels {
+            if let Entry::Vacant(entry) = self.gains.entry(channel) {
+                entry.insert(ChannelGain::default());
+                added = true;
+            }
+        }
+        if added {
+            self.update_targets(0.0);
+        }
+    }
+
+    pub fn set_volume(&mut self, channel: usize, volume: f32, seconds: f32) {
+        self.gains.entry(channel).or_default().volume = volume;
+        self.update_targets(seconds);
+    }
+
+    pub fn set_muted(&mut self, channel: usize, muted: bool, seconds: f32) {
+        self.gains.entry(channel).or_default().muted = muted;
+        self.update_targets(seconds);
+    }
+
+    /// Solo a channel; while any channel is soloed, all others are silenced
+    pub fn set_soloed(&mut self, channel: usize, soloed: bool, seconds: f32) {
+        self.gains.entry(channel).or_default().soloed = soloed;
+        self.update_targets(seconds);
+    }
+
+    /// Settings of each channel that differs from the defaults
+    pub fn settings(&self) -> Vec<ChannelMixSettings> {
+        let mut settings: Vec<ChannelMixSettings> = self
+            .gains
+            .iter()
+            .filter(|(_, gain)| !gain.has_default_settings())
+            .map(|(channel, gain)| ChannelMixSettings {
+                channel: *channel,
+                volume: gain.volume,
+                muted: gain.muted,
+                soloed: gain.soloed,
+                fade: (gain.frames_remaining > 0).then_some(ChannelFade {
+                    gain: gain.current,
+                    frames_remaining: gain.frames_remaining,
+                }),
+            })
+            .collect();
+        settings.sort_by_key(|setting| setting.channel);
+        settings
+    }
+
+    /// Replace all settings, applying them immediately apart from fades that
+    /// were still in progress, which carry on from where they had reached
+    pub fn restore_settings(&mut self, settings: &[ChannelMixSettings]) {
+        for gain in self.gains.values_mut() {
+            *gain = ChannelGain::default();
+        }
+        for setting in settings.iter() {
+            let gain = self.gains.entry(setting.channel).or_default();
+            gain.volume = setting.volume;
+            gain.muted = setting.muted;
+            gain.soloed = setting.soloed;
+        }
+        self.update_targets(0.0);
+        for setting in settings.iter() {
+            let (Some(fade), Some(gain)) = (&setting.fade, self.gains.get_mut(&setting.channel))
+            else {
+                continue;
+            };
+            let target = gain.target;
+            gain.current = fade.gain;
+            gain.fade_over_frames(target, fade.frames_remaining);
+        }
+    }
+
+    /// Take on another mix's settings and the fades it has in progress
+    pub fn copy_from(&mut self, other: &ChannelMix) {
+        for (channel, gain) in self.gains.iter_mut() {
+            match other.gains.get(channel) {
+                Some(other_gain) => gain.clone_from(other_gain),
+                None => *gain = ChannelGain::default(),
+            }
+        }
+        for (channel, other_gain) in other.gains.iter() {
+            if let Entry::Vacant(entry) = self.gains.entry(*channel) {
+                entry.insert(other_gain.clone());
+            }
+        }
+    }
+
+    /// Render a channel through its gain. The fill function should add the
+    /// channel's output into the buffer it is given, and may be called for
+    /// several consecutive parts of the buffer. A channel that was never
+    /// prepared is rendered at full volume.
+    pub fn fill_channel(
+        &mut self,
+        channel: usize,
+        buffer: &mut [f32],
+        mut fill: impl FnMut(&mut [f32]),
+    ) {
+        let Some(gain) = self.gains.get_mut(&channel) else {
+            fill(buffer);
+            return;
+        };
+        if gain.is_unity() {
+            fill(buffer);
+            return;
+        }
+        for output_chunk in buffer.chunks_mut(self.intermediate_buffer.len()) {
+            let intermediate_buffer = &mut self.intermediate_buffer[0..output_chunk.len()];
+            intermediate_buffer.fill(0.0);
+            fill(intermediate_buffer);
+            if !gain.is_silent() {
+                gain.mix_into(intermediate_buffer, output_chunk);
+            }
+        }
+    }
+
+    fn update_targets(&mut self, seconds: f32) {
+        let any_soloed = self.gains.values().any(|gain| gain.soloed);
+        for gain in self.gains.values_mut() {
+            let audible = !gain.muted && (gain.soloed || !any_soloed);
+            let target = match audible {
+                true => gain.volume,
+                false => 0.0,
+            };
+            gain.fade_to(target, seconds);
+        }
+    }
+}
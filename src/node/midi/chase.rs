@@ -0,0 +1,182 @@
+use crate::{Event, Message, midi::MidiEvent, node::midi::controller};
+
+/// Controller numbers below the channel mode messages
+const CONTROLLER_COUNT: usize = 120;
+/// Events between the chase states recorded when a sequence is loaded, which
+/// bounds how many events a seek scans
+const CHECKPOINT_INTERVAL: usize = 256;
+const MAX_SOUNDING_NOTES: usize = 128;
+
+/// State of a channel that a seek restores
+#[derive(Clone)]
+struct ChannelState {
+    program: Option<u8>,
+    controllers: [Option<u8>; CONTROLLER_COUNT],
+    pitch_bend_range: Option<f32>,
+    pitch_bend: Option<f32>,
+    pressure: Option<f32>,
+    sounding_notes: Vec<(u8, f32)>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            program: None,
+            controllers: [None; CONTROLLER_COUNT],
+            pitch_bend_range: None,
+            pitch_bend: None,
+            pressure: None,
+            sounding_notes: Vec::with_capacity(MAX_SOUNDING_NOTES),
+        }
+    }
+}
+
+impl ChannelState {
+    /// Copy another state, keeping this state's note capacity
+    fn copy_from(&mut self, other: &ChannelState) {
+        self.program = other.program;
+        self.controllers = other.controllers;
+        self.pitch_bend_range = other.pitch_bend_range;
+        self.pitch_bend = other.pitch_bend;
+        self.pressure = other.pressure;
+        self.sounding_notes.clone_from(&other.sounding_notes);
+    }
+
+    fn apply(&mut self, data: &Event) {
+        match *data {
+            Event::NoteOn { note, vel } => {
+                self.sounding_notes
+                    .retain(|(sounding, _)| *sounding != note);
+                if vel > 0.0 && self.sounding_notes.len() < MAX_SOUNDING_NOTES {
+                    self.sounding_notes.push((note, vel));
+                }
+            }
+            Event::NoteOff { note, .. } => {
+                self.sounding_notes
+                    .retain(|(sounding, _)| *sounding != note);
+            }
+            Event::ProgramChange(program) => self.program = Some(program),
+            Event::Controller { controller, value } => match controller {
+                controller::CC_ALL_SOUND_OFF | controller::CC_ALL_NOTES_OFF => {
+                    self.sounding_notes.clear();
+                }
+                controller::CC_RESET_ALL_CONTROLLERS => {
+                    self.controllers = [None; CONTROLLER_COUNT];
+                    self.pitch_bend = Some(0.0);
+                    self.pressure = None;
+                }
+                _ => {
+                    if let Some(latest) = self.controllers.get_mut(controller as usize) {
+                        *latest = Some(value);
+                    }
+                }
+            },
+            Event::PitchBendRange(range) => self.pitch_bend_range = Some(range),
+            Event::PitchBend(bend) => self.pitch_bend = Some(bend),
+            Event::ChannelPressure(pressure) => self.pressure = Some(pressure),
+            _ => {}
+        }
+    }
+
+    /// Events restoring the state: program first, then controllers, pitch
+    /// bend and pressure, then the notes sounding
+    fn push_events(&self, channel: usize, events: &mut Vec<MidiEvent>) {
+        let controllers = self
+            .controllers
+            .iter()
+            .enumerate()
+            .filter_map(|(controller, value)| {
+                value.map(|value| Event::Controller {
+                    controller: controller as u8,
+                    value,
+                })
+            });
+        let notes = self.sounding_notes.iter().map(|(note, vel)| Event::NoteOn {
+            note: *note,
+            vel: *vel,
+        });
+        let data = self
+            .program
+            .map(Event::ProgramChange)
+            .into_iter()
+            .chain(controllers)
+            .chain(self.pitch_bend_range.map(Event::PitchBendRange))
+            .chain(self.pitch_bend.map(Event::PitchBend))
+            .chain(self.pressure.map(Event::ChannelPressure))
+            .chain(notes);
+        events.extend(data.map(|data| MidiEvent {
+            delta_ticks: 0,
+            channel,
+            message: Message::broadcast(data),
+        }));
+    }
+}
+
+/// Channel states recorded at regular points of a sequence as it is loaded,
+/// so that a seek only scans the events since the nearest point before its
+/// target. The working state and event buffer are reused by every seek, so
+/// seeking does not allocate on the audio thread.
+pub(crate) struct ChaseStates {
+    checkpoints: Vec<Vec<ChannelState>>,
+    working: Vec<ChannelState>,
+    events: Vec<MidiEvent>,
+}
+
+impl ChaseStates {
+    pub(crate) fn new(midi_events: &[MidiEvent]) -> Self {
+        let channel_count = midi_events
+            .iter()
+            .map(|event| event.channel + 1)
+            .max()
+            .unwrap_or(0);
+        let mut channels = vec![ChannelState::default(); channel_count];
+        let mut checkpoints = Vec::with_capacity(midi_events.len() / CHECKPOINT_INTERVAL + 1);
+        for chunk in midi_events.chunks(CHECKPOINT_INTERVAL) {
+            checkpoints.push(channels.clone());
+            for event in chunk.iter() {
+                channels[event.channel].apply(&event.message.data);
+            }
+        }
+        let event_capacity = channel_count * (CONTROLLER_COUNT + MAX_SOUNDING_NOTES + 4);
+        Self {
+            checkpoints,
+            working: vec![ChannelState::default(); channel_count],
+            events: Vec::with_capacity(event_capacity),
+        }
+    }
+
+    /// Take the events that restore each channel's state from before the
+    /// given event. The buffer should be handed back with restore_buffer.
+    pub(crate) fn take_events_before(
+        &mut self,
+        midi_events: &[MidiEvent],
+        index: usize,
+    ) -> Vec<MidiEvent> {
+        let index = index.min(midi_events.len());
+        let mut events = std::mem::take(&mut self.events);
+        events.clear();
+        // Seeking to the end of a sequence uses the last recorded state
+        let Some(last_checkpoint) = self.checkpoints.len().checked_sub(1) else {
+            return events;
+        };
+        let checkpoint_index = (index / CHECKPOINT_INTERVAL).min(last_checkpoint);
+        let checkpoint = &self.checkpoints[checkpoint_index];
+        for (working, recorded) in self.working.iter_mut().zip(checkpoint.iter()) {
+            working.copy_from(recorded);
+        }
+        let checkpoint_start = checkpoint_index * CHECKPOINT_INTERVAL;
+        for event in midi_events[checkpoint_start..index].iter() {
+            self.working[event.channel].apply(&event.message.data);
+        }
+        for (channel, state) in self.working.iter().enumerate() {
+            state.push_events(channel, &mut events);
+        }
+        events
+    }
+
+    /// Hand back the buffer from take_events_before, to reuse for the next
+    /// seek
+    pub(crate) fn restore_buffer(&mut self, events: Vec<MidiEvent>) {
+        self.events = events;
+    }
+}
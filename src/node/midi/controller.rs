@@ -0,0 +1,303 @@
+use crate::{
+    Error, Event, EventTarget, Message, abstraction::defaults, effect::ModulationProperty,
+    node::midi::event::MIDI_CHANNEL_COUNT,
+};
+use serde::{Deserialize, Deserializer};
+
+pub const CC_MODULATION_WHEEL: u8 = 1;
+pub const CC_VOLUME: u8 = 7;
+pub const CC_PAN: u8 = 10;
+pub const CC_EXPRESSION: u8 = 11;
+pub const CC_SUSTAIN: u8 = 64;
+pub const CC_SOSTENUTO: u8 = 66;
+pub const CC_BRIGHTNESS: u8 = 74;
+pub const CC_ALL_SOUND_OFF: u8 = 120;
+pub const CC_RESET_ALL_CONTROLLERS: u8 = 121;
+pub const CC_ALL_NOTES_OFF: u8 = 123;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub enum ControllerSource {
+    /// Controller (CC) messages with this controller number
+    Controller(u8),
+    /// Channel aftertouch, affecting every note on the channel
+    ChannelPressure,
+    /// Polyphonic aftertouch, affecting only the note being pressed; applied
+    /// by Polyphony nodes to the voice playing that note
+    KeyPressure,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum ControllerTarget {
+    /// Send the property's usual event, such as Volume for ModulationProperty::Volume
+    Property(ModulationProperty),
+    /// Send an Event::Parameter with this name, for custom nodes to handle
+    Parameter(String),
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+pub enum ControllerCurve {
+    #[default]
+    Linear,
+    /// Squared controller position, matching the General MIDI volume response
+    Quadratic,
+    /// Even ratios between steps; both ends of the range must be positive
+    Exponential,
+}
+
+/// Source of a binding as written in a config: either a source, or the
+/// controller number alone, as bindings were first written
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SourceConfig {
+    Controller(u8),
+    Source(ControllerSource),
+}
+
+fn deserialize_source<'de, D>(deserializer: D) -> Result<ControllerSource, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match SourceConfig::deserialize(deserializer)? {
+        SourceConfig::Controller(controller) => ControllerSource::Controller(controller),
+        SourceConfig::Source(source) => source,
+    })
+}
+
+/// Binding of a MIDI controller (CC) number or aftertouch to a graph event.
+/// Controller values from 0 to 127 are mapped along the curve onto the range
+/// from min to max.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ControllerBinding {
+    #[serde(alias = "controller", deserialize_with = "deserialize_source")]
+    pub source: ControllerSource,
+    pub target: ControllerTarget,
+    #[serde(default = "defaults::controller_min")]
+    pub min: f32,
+    #[serde(default = "defaults::controller_max")]
+    pub max: f32,
+    #[serde(default)]
+    pub curve: ControllerCurve,
+    #[serde(default = "defaults::none_id")]
+    pub node_id: Option<u64>,
+}
+
+impl ControllerBinding {
+    pub fn new(source: ControllerSource, target: ControllerTarget, min: f32, max: f32) -> Self {
+        Self {
+            source,
+            target,
+            min,
+            max,
+            curve: ControllerCurve::Linear,
+            node_id: None,
+        }
+    }
+
+    pub fn with_curve(mut self, curve: ControllerCurve) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    /// Bindings for the controllers defined by General MIDI which have a
+    /// matching modulation property
+    pub fn general_midi() -> Vec<Self> {
+        vec![
+            Self::new(
+                ControllerSource::Controller(CC_MODULATION_WHEEL),
+                ControllerTarget::Property(ModulationProperty::LfoDepth),
+                0.0,
+                1.0,
+            ),
+            Self::new(
+                ControllerSource::Controller(CC_VOLUME),
+                ControllerTarget::Property(ModulationProperty::Volume),
+                0.0,
+                1.0,
+            )
+            .with_curve(ControllerCurve::Quadratic),
+            Self::new(
+                ControllerSource::Controller(CC_PAN),
+                ControllerTarget::Property(ModulationProperty::Pan),
+                0.0,
+                1.0,
+            ),
+            Self::new(
+                ControllerSource::Controller(CC_EXPRESSION),
+                ControllerTarget::Property(ModulationProperty::Volume),
+                0.0,
+                1.0,
+            )
+            .with_curve(ControllerCurve::Quadratic),
+            Self::new(
+                ControllerSource::Controller(CC_BRIGHTNESS),
+                ControllerTarget::Property(ModulationProperty::FilterFrequencyShift),
+                -2000.0,
+                2000.0,
+            ),
+        ]
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if matches!(self.curve, ControllerCurve::Exponential)
+            && (self.min <= 0.0 || self.max <= 0.0)
+        {
+            return Err(Error::User(format!(
+                "ERROR: Controller: Exponential binding for {:?} needs a positive min and max, not {} and {}",
+                self.source, self.min, self.max
+            )));
+        }
+        Ok(())
+    }
+
+    /// Value for a controller position from 0.0 to 1.0
+    pub fn value_for(&self, position: f32) -> f32 {
+        let position = position.clamp(0.0, 1.0);
+        match self.curve {
+            ControllerCurve::Linear => self.min + (self.max - self.min) * position,
+            ControllerCurve::Quadratic => self.min + (self.max - self.min) * position * position,
+            ControllerCurve::Exponential => self.min * (self.max / self.min).powf(position),
+        }
+    }
+}
+
+/// Applies controller bindings to the controller messages on each channel.
+/// Where several bindings on a channel control volume (such as CC7 volume and
+/// CC11 expression), the latest values from each are multiplied together.
+#[derive(Clone)]
+pub struct ControllerMapper {
+    bindings: Vec<ControllerBinding>,
+    channel_count: usize,
+    latest_values: Vec<Option<f32>>,
+}
+
+impl ControllerMapper {
+    pub fn new(bindings: Vec<ControllerBinding>) -> Result<Self, Error> {
+        Self::new_with_channel_count(bindings, MIDI_CHANNEL_COUNT)
+    }
+
+    /// Set up a mapper tracking some number of independent channels, such as
+    /// the voices of a Polyphony node
+    pub fn new_with_channel_count(
+        bindings: Vec<ControllerBinding>,
+        channel_count: usize,
+    ) -> Result<Self, Error> {
+        for binding in bindings.iter() {
+            binding.validate()?;
+        }
+        let channel_count = channel_count.max(1);
+        let latest_values = vec![None; bindings.len() * channel_count];
+        Ok(Self {
+            bindings,
+            channel_count,
+            latest_values,
+        })
+    }
+
+    pub fn bindings(&self) -> &[ControllerBinding] {
+        &self.bindings
+    }
+
+    pub fn reset_channel(&mut self, channel: usize) {
+        let start = (channel % self.channel_count) * self.bindings.len();
+        self.latest_values[start..(start + self.bindings.len())].fill(None);
+    }
+
+    /// Pass each message resulting from a channel event to the handler, for
+    /// controller changes and channel pressure. Channel mode messages that
+    /// silence notes or reset controllers are handled here too.
+    pub fn apply_event<F>(&mut self, channel: usize, event: &Event, mut handler: F)
+    where
+        F: FnMut(&Message),
+    {
+        match *event {
+            Event::Controller { controller, value } => {
+                match controller {
+                    CC_ALL_SOUND_OFF | CC_ALL_NOTES_OFF => {
+                        handler(&Message::broadcast(Event::AllNotesOff));
+                    }
+                    CC_RESET_ALL_CONTROLLERS => {
+                        self.reset_channel(channel);
+                        handler(&Message::broadcast(Event::PitchBend(0.0)));
+                    }
+                    _ => {}
+                }
+                self.map_controller(channel, controller, value, handler);
+            }
+            Event::ChannelPressure(pressure) => {
+                self.map_source(
+                    channel,
+                    ControllerSource::ChannelPressure,
+                    pressure,
+                    handler,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Pass each message resulting from a controller change to the handler
+    pub fn map_controller<F>(&mut self, channel: usize, controller: u8, value: u8, handler: F)
+    where
+        F: FnMut(&Message),
+    {
+        let position = value.min(127) as f32 / 127.0;
+        self.map_source(
+            channel,
+            ControllerSource::Controller(controller),
+            position,
+            handler,
+        );
+    }
+
+    /// Pass each message resulting from a change of some source, at a position
+    /// from 0.0 to 1.0, to the handler
+    pub fn map_source<F>(
+        &mut self,
+        channel: usize,
+        source: ControllerSource,
+        position: f32,
+        mut handler: F,
+    ) where
+        F: FnMut(&Message),
+    {
+        let channel_start = (channel % self.channel_count) * self.bindings.len();
+        for (index, binding) in self.bindings.iter().enumerate() {
+            if binding.source != source {
+                continue;
+            }
+            let mapped_value = binding.value_for(position);
+            self.latest_values[channel_start + index] = Some(mapped_value);
+            let data = match &binding.target {
+                ControllerTarget::Property(ModulationProperty::Volume) => {
+                    let combined_volume = self
+                        .bindings
+                        .iter()
+                        .zip(self.latest_values[channel_start..].iter())
+                        .filter(|(other, _)| {
+                            matches!(
+                                other.target,
+                                ControllerTarget::Property(ModulationProperty::Volume)
+                            )
+                        })
+                        .filter_map(|(_, latest)| *latest)
+                        .product();
+                    Event::Volume(combined_volume)
+                }
+                ControllerTarget::Property(property) => property.event_for_value(mapped_value),
+                ControllerTarget::Parameter(name) => Event::Parameter {
+                    name: name.clone(),
+                    value: mapped_value,
+                },
+            };
+            let target = match binding.node_id {
+                Some(node_id) => EventTarget::SpecificNode(node_id),
+                None => EventTarget::Broadcast,
+            };
+            handler(&Message {
+                target,
+                data,
+                ..Message::default()
+            });
+        }
+    }
+}
@@ -1,85 +1,289 @@
 use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Clone, Debug)]
+/// Anchor that cues can seek to, identified by a number or a name
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Anchor {
+    Number(u32),
+    Name(String),
+}
+
+impl From<u32> for Anchor {
+    fn from(number: u32) -> Self {
+        Self::Number(number)
+    }
+}
+
+impl From<&str> for Anchor {
+    fn from(name: &str) -> Self {
+        Self::Name(name.to_owned())
+    }
+}
+
+impl std::fmt::Display for Anchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{}", number),
+            Self::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Test of a cue variable, which the game can set on a MIDI node
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum CueCondition {
+    /// Variable is set to a non-zero value
+    IsSet(String),
+    /// Variable is zero or has never been set
+    IsNotSet(String),
+    Equals(String, f32),
+    LessThan(String, f32),
+    GreaterThan(String, f32),
+}
+
+impl CueCondition {
+    /// Whether the condition holds; variables never set count as zero
+    pub fn is_met(&self, variables: &HashMap<String, f32>) -> bool {
+        let value_of = |name: &String| variables.get(name).copied().unwrap_or(0.0);
+        match self {
+            Self::IsSet(name) => value_of(name) != 0.0,
+            Self::IsNotSet(name) => value_of(name) == 0.0,
+            Self::Equals(name, value) => value_of(name) == *value,
+            Self::LessThan(name, value) => value_of(name) < *value,
+            Self::GreaterThan(name, value) => value_of(name) > *value,
+        }
+    }
+
+    fn to_label(&self) -> String {
+        match self {
+            Self::IsSet(name) => format!("{{{}}}", name),
+            Self::IsNotSet(name) => format!("{{!{}}}", name),
+            Self::Equals(name, value) => format!("{{{}={}}}", name, value),
+            Self::LessThan(name, value) => format!("{{{}<{}}}", name, value),
+            Self::GreaterThan(name, value) => format!("{{{}>{}}}", name, value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum CueData {
-    TargetMarker(u32),
+    TargetMarker(Anchor),
     GoodPointToSeekFrom,
-    SeekNowToTarget(u32),
-    SeekWhenIdeal(u32),
+    SeekNowToTarget(Anchor),
+    SeekWhenIdeal(Anchor),
     ClearQueuedSeek,
+    /// Seek to the target each time this cue is reached, until it has done so
+    /// the given number of times in a row; then continue past it once
+    LoopToTarget {
+        target: Anchor,
+        times: u32,
+    },
+    SeekToRandomTarget(Vec<Anchor>),
+    /// Apply the inner cue only if the condition holds when it is reached
+    Conditional {
+        condition: CueCondition,
+        cue: Box<CueData>,
+    },
 }
 
 impl CueData {
+    /// Label text for this cue, in the syntax read by from_label, or None if
+    /// the cue has no label syntax
+    pub fn to_label(&self) -> Option<String> {
+        match self {
+            Self::TargetMarker(anchor) => Some(format!("#{}", anchor)),
+            Self::GoodPointToSeekFrom => Some("?".to_owned()),
+            Self::SeekNowToTarget(anchor) => Some(format!(">{}", anchor)),
+            Self::SeekWhenIdeal(_) | Self::ClearQueuedSeek => None,
+            Self::LoopToTarget { target, times } => Some(format!(">{}*{}", target, times)),
+            Self::SeekToRandomTarget(targets) => {
+                let targets: Vec<String> =
+                    targets.iter().map(|target| target.to_string()).collect();
+                Some(format!(">({})", targets.join("|")))
+            }
+            Self::Conditional { condition, cue } => cue
+                .to_label()
+                .map(|label| format!("{}{}", condition.to_label(), label)),
+        }
+    }
+
+    /// Parse the cues in a marker or cue point label. See the README for the
+    /// label syntax.
     pub fn from_label(label: &[u8]) -> Result<Vec<Self>, Error> {
         let string = std::str::from_utf8(label)
             .map_err(|_| Error::Internal("Cannot parse event label in MIDI data".to_owned()))?;
-        let mut cues: Vec<Self> = vec![];
-        let length = string.chars().count();
-        let mut index = 0;
-        while index < length {
-            match string.chars().nth(index) {
-                Some('#') => {
-                    let start_index = index + 1;
-                    let mut end_index = start_index;
-                    while end_index < length {
-                        if let Some(ch) = string.chars().nth(end_index) {
-                            if ch.is_numeric() {
-                                end_index += 1;
-                                continue;
-                            }
-                        }
-                        break;
-                    }
-                    if end_index == start_index {
-                        println!("WARNING: MIDI: Cannot parse anchor in label {}", string);
-                    } else {
-                        let anchor_index =
-                            &string[start_index..end_index].parse().map_err(|_| {
-                                Error::User(format!(
-                                    "Failed parsing anchor index in label {}",
-                                    string
-                                ))
-                            })?;
-                        cues.push(Self::TargetMarker(*anchor_index));
-                    }
-                    index = end_index;
-                }
-                Some('>') => {
-                    let start_index = index + 1;
-                    let mut end_index = start_index;
-                    while end_index < length {
-                        if let Some(ch) = string.chars().nth(end_index) {
-                            if ch.is_numeric() {
-                                end_index += 1;
-                                continue;
-                            }
-                        }
-                        break;
-                    }
-                    if end_index == start_index {
-                        println!("WARNING: MIDI: Cannot parse seek label");
-                    } else {
-                        let anchor_index =
-                            &string[start_index..end_index].parse().map_err(|_| {
-                                Error::User(format!(
-                                    "Failed parsing seek index in label {}",
-                                    string
-                                ))
-                            })?;
-                        cues.push(Self::SeekNowToTarget(*anchor_index));
+        LabelParser::new(string).parse()
+    }
+}
+
+struct LabelParser<'a> {
+    label: &'a str,
+    chars: Vec<char>,
+    index: usize,
+}
+
+impl<'a> LabelParser<'a> {
+    fn new(label: &'a str) -> Self {
+        Self {
+            label,
+            chars: label.chars().collect(),
+            index: 0,
+        }
+    }
+
+    fn error(&self, problem: &str) -> Error {
+        Error::User(format!(
+            "ERROR: MIDI: Cue label \"{}\": {} at character {}",
+            self.label,
+            problem,
+            self.index + 1
+        ))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.index += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        match self.peek() {
+            Some(ch) if ch == expected => {
+                self.index += 1;
+                Ok(())
+            }
+            _ => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<CueData>, Error> {
+        let mut cues: Vec<CueData> = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.peek().is_none() {
+                return Ok(cues);
+            }
+            let cue = match self.peek() {
+                Some('{') => {
+                    let condition = self.parse_condition()?;
+                    self.skip_whitespace();
+                    let cue = match self.peek() {
+                        Some('>') | Some('?') => self.parse_action()?,
+                        _ => return Err(self.error("expected '>' or '?' after a condition")),
+                    };
+                    CueData::Conditional {
+                        condition,
+                        cue: Box::new(cue),
                     }
-                    index = end_index;
                 }
-                Some('?') => {
-                    cues.push(Self::GoodPointToSeekFrom);
-                    index += 1;
+                _ => self.parse_action()?,
+            };
+            cues.push(cue);
+        }
+    }
+
+    fn parse_action(&mut self) -> Result<CueData, Error> {
+        match self.peek() {
+            Some('#') => {
+                self.index += 1;
+                Ok(CueData::TargetMarker(self.parse_anchor()?))
+            }
+            Some('?') => {
+                self.index += 1;
+                Ok(CueData::GoodPointToSeekFrom)
+            }
+            Some('>') => {
+                self.index += 1;
+                if self.peek() == Some('(') {
+                    return self.parse_random_targets();
                 }
-                _ => {
-                    println!("WARNING: MIDI: Unknown data in cue point label");
-                    break;
+                let target = self.parse_anchor()?;
+                if self.peek() != Some('*') {
+                    return Ok(CueData::SeekNowToTarget(target));
                 }
+                self.index += 1;
+                let times = self.parse_number()?;
+                Ok(CueData::LoopToTarget { target, times })
             }
+            Some(ch) => Err(self.error(&format!("unexpected '{}'", ch))),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn parse_random_targets(&mut self) -> Result<CueData, Error> {
+        self.expect('(')?;
+        let mut targets = vec![self.parse_anchor()?];
+        while self.peek() == Some('|') {
+            self.index += 1;
+            targets.push(self.parse_anchor()?);
+        }
+        self.expect(')')?;
+        Ok(CueData::SeekToRandomTarget(targets))
+    }
+
+    fn parse_condition(&mut self) -> Result<CueCondition, Error> {
+        self.expect('{')?;
+        let negated = self.peek() == Some('!');
+        if negated {
+            self.index += 1;
+        }
+        let name = self.parse_name()?;
+        let comparison = match self.peek() {
+            Some('}') => None,
+            Some(op @ ('=' | '<' | '>')) if !negated => {
+                self.index += 1;
+                Some((op, self.parse_value()?))
+            }
+            _ => return Err(self.error("expected '}', or a comparison with '=', '<' or '>'")),
+        };
+        self.expect('}')?;
+        Ok(match comparison {
+            None if negated => CueCondition::IsNotSet(name),
+            None => CueCondition::IsSet(name),
+            Some(('=', value)) => CueCondition::Equals(name, value),
+            Some(('<', value)) => CueCondition::LessThan(name, value),
+            Some((_, value)) => CueCondition::GreaterThan(name, value),
+        })
+    }
+
+    fn parse_anchor(&mut self) -> Result<Anchor, Error> {
+        match self.peek() {
+            Some(ch) if ch.is_ascii_digit() => Ok(Anchor::Number(self.parse_number()?)),
+            Some(ch) if ch.is_alphabetic() || ch == '_' => Ok(Anchor::Name(self.parse_name()?)),
+            _ => Err(self.error("expected an anchor number or name")),
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let start_index = self.index;
+        while self.peek().is_some_and(&predicate) {
+            self.index += 1;
+        }
+        self.chars[start_index..self.index].iter().collect()
+    }
+
+    fn parse_number(&mut self) -> Result<u32, Error> {
+        let digits = self.take_while(|ch| ch.is_ascii_digit());
+        digits
+            .parse()
+            .map_err(|_| self.error("expected a whole number"))
+    }
+
+    fn parse_value(&mut self) -> Result<f32, Error> {
+        let text = self.take_while(|ch| ch.is_ascii_digit() || ch == '.' || ch == '-');
+        text.parse().map_err(|_| self.error("expected a number"))
+    }
+
+    fn parse_name(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(ch) if ch.is_alphabetic() || ch == '_' => {}
+            _ => return Err(self.error("expected a name")),
         }
-        Ok(cues)
+        Ok(self.take_while(|ch| ch.is_alphanumeric() || ch == '_'))
     }
 }
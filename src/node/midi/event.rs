@@ -1,7 +1,23 @@
-use crate::{Error, Event, EventTarget, EventTiming, Message, midi::CueData};
+use crate::{
+    Error, Event, EventTarget, EventTiming, Message,
+    abstraction::defaults,
+    midi::{CueData, MidiTextKind},
+    node::midi::text,
+};
 use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
 
-pub fn midi_events_from_midi(smf: Smf, track_index: usize) -> Result<Vec<MidiEvent>, Error> {
+const CC_DATA_ENTRY_MSB: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_NRPN_LSB: u8 = 98;
+const CC_NRPN_MSB: u8 = 99;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+const RPN_PITCH_BEND_RANGE: (u8, u8) = (0, 0);
+const RPN_NULL: (u8, u8) = (127, 127);
+pub(crate) const MIDI_CHANNEL_COUNT: usize = 16;
+
+/// Decode one track of a parsed MIDI file.
+pub fn midi_events_from_midi(smf: &Smf, track_index: usize) -> Result<Vec<MidiEvent>, Error> {
     let mut midi_events: Vec<MidiEvent> = vec![];
     let track = smf
         .tracks
@@ -12,20 +28,39 @@ pub fn midi_events_from_midi(smf: Smf, track_index: usize) -> Result<Vec<MidiEve
         event_delta += u32::from(event.delta) as isize;
         match event.kind {
             // Special cases for markers and cue labels since they encode multiple events
-            TrackEventKind::Meta(MetaMessage::Marker(label)) => {
-                let cue_data = CueData::from_label(label)?;
-                let events = MidiEvent::from_cue_data(event_delta, cue_data);
-                for cue_event in events.into_iter() {
-                    midi_events.push(cue_event);
+            TrackEventKind::Meta(
+                meta @ (MetaMessage::Marker(label) | MetaMessage::CuePoint(label)),
+            ) if text::is_cue_label(label) => {
+                match CueData::from_label(label) {
+                    Ok(cue_data) => {
+                        midi_events.extend(MidiEvent::from_cue_data(event_delta, cue_data));
+                    }
+                    // Markers are often prose, which may start like a cue label
+                    Err(error) => {
+                        println!("WARNING: MIDI: Keeping label as text: {:?}", error);
+                        midi_events.extend(MidiEvent::from_text_meta(event_delta, &meta));
+                    }
                 }
                 event_delta = 0;
             }
-            TrackEventKind::Meta(MetaMessage::CuePoint(label)) => {
-                let cue_data = CueData::from_label(label)?;
-                let events = MidiEvent::from_cue_data(event_delta, cue_data);
-                for cue_event in events.into_iter() {
-                    midi_events.push(cue_event);
+            TrackEventKind::Meta(meta) if MidiEvent::text_kind(&meta).is_some() => {
+                if let Some(text_event) = MidiEvent::from_text_meta(event_delta, &meta) {
+                    midi_events.push(text_event);
+                    event_delta = 0;
                 }
+            }
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Controller { controller, value },
+            } => {
+                midi_events.push(MidiEvent {
+                    delta_ticks: event_delta,
+                    channel: u8::from(channel) as usize,
+                    message: Message::broadcast(Event::Controller {
+                        controller: u8::from(controller),
+                        value: u8::from(value),
+                    }),
+                });
                 event_delta = 0;
             }
             _ => {
@@ -79,10 +114,87 @@ impl MidiEvent {
                     timing: EventTiming::Imprecise,
                 },
             }),
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::PitchBend { bend },
+            } => Some(MidiEvent {
+                delta_ticks: event_delta,
+                channel: u8::from(*channel) as usize,
+                message: Message {
+                    target: EventTarget::Broadcast,
+                    data: Event::PitchBend(bend.as_f32()),
+                    timing: EventTiming::Imprecise,
+                },
+            }),
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Aftertouch { key, vel },
+            } => Some(MidiEvent {
+                delta_ticks: event_delta,
+                channel: u8::from(*channel) as usize,
+                message: Message {
+                    target: EventTarget::Broadcast,
+                    data: Event::KeyPressure {
+                        note: u8::from(*key),
+                        pressure: u8::from(*vel) as f32 / 127.0,
+                    },
+                    timing: EventTiming::Imprecise,
+                },
+            }),
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::ChannelAftertouch { vel },
+            } => Some(MidiEvent {
+                delta_ticks: event_delta,
+                channel: u8::from(*channel) as usize,
+                message: Message {
+                    target: EventTarget::Broadcast,
+                    data: Event::ChannelPressure(u8::from(*vel) as f32 / 127.0),
+                    timing: EventTiming::Imprecise,
+                },
+            }),
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::ProgramChange { program },
+            } => Some(MidiEvent {
+                delta_ticks: event_delta,
+                channel: u8::from(*channel) as usize,
+                message: Message {
+                    target: EventTarget::Broadcast,
+                    data: Event::ProgramChange(u8::from(*program)),
+                    timing: EventTiming::Imprecise,
+                },
+            }),
+            _ => None,
+        }
+    }
+
+    fn text_kind<'a>(meta: &MetaMessage<'a>) -> Option<(MidiTextKind, &'a [u8])> {
+        match meta {
+            MetaMessage::Text(text) => Some((MidiTextKind::Text, text)),
+            MetaMessage::Lyric(text) => Some((MidiTextKind::Lyric, text)),
+            MetaMessage::TrackName(text) => Some((MidiTextKind::TrackName, text)),
+            MetaMessage::Marker(text) => Some((MidiTextKind::Marker, text)),
+            MetaMessage::CuePoint(text) => Some((MidiTextKind::CuePoint, text)),
             _ => None,
         }
     }
 
+    /// Text event for a text, lyric, track name, marker or cue point meta
+    /// event, decoding the text leniently since MIDI files don't specify an
+    /// encoding
+    pub fn from_text_meta(event_delta: isize, meta: &MetaMessage) -> Option<Self> {
+        let (kind, text) = Self::text_kind(meta)?;
+        Some(MidiEvent {
+            delta_ticks: event_delta,
+            channel: 0, // Ignored for text
+            message: Message::broadcast(Event::Text {
+                kind,
+                text: String::from_utf8_lossy(text).into_owned(),
+            }),
+        })
+    }
+
     pub fn from_cue_data(event_delta: isize, cue_data: Vec<CueData>) -> Vec<Self> {
         let mut event_delta = event_delta;
         let mut midi_events = vec![];
@@ -101,3 +213,106 @@ impl MidiEvent {
         midi_events
     }
 }
+
+/// Registered parameter (RPN) selections for each channel, used to decode
+/// data entry controller messages into parameter changes
+pub struct RegisteredParameters {
+    selected: [(u8, u8); MIDI_CHANNEL_COUNT],
+    pitch_bend_range: [(u8, u8); MIDI_CHANNEL_COUNT],
+}
+
+impl Default for RegisteredParameters {
+    fn default() -> Self {
+        Self::new(defaults::pitch_bend_range())
+    }
+}
+
+impl RegisteredParameters {
+    /// Start every channel at a pitch bend range in semitones, which data
+    /// entry for only the coarse or fine part of RPN 0 leaves the rest of
+    pub fn new(pitch_bend_range: f32) -> Self {
+        let pitch_bend_range = pitch_bend_range.clamp(0.0, 127.99);
+        let semitones = pitch_bend_range.trunc();
+        let cents = ((pitch_bend_range - semitones) * 100.0).round().min(99.0);
+        Self {
+            selected: [RPN_NULL; MIDI_CHANNEL_COUNT],
+            pitch_bend_range: [(semitones as u8, cents as u8); MIDI_CHANNEL_COUNT],
+        }
+    }
+
+    /// Insert the parameter changes that data entry messages complete ahead
+    /// of those messages, in events that hold raw controller messages
+    pub fn decode_events(&mut self, midi_events: Vec<MidiEvent>) -> Vec<MidiEvent> {
+        let mut decoded_events = Vec::with_capacity(midi_events.len());
+        for event in midi_events.into_iter() {
+            let Event::Controller { controller, value } = event.message.data else {
+                decoded_events.push(event);
+                continue;
+            };
+            match self.on_controller(event.channel, controller, value) {
+                Some(data) => {
+                    decoded_events.push(MidiEvent {
+                        delta_ticks: event.delta_ticks,
+                        channel: event.channel,
+                        message: Message::broadcast(data),
+                    });
+                    decoded_events.push(MidiEvent {
+                        delta_ticks: 0,
+                        ..event
+                    });
+                }
+                None => decoded_events.push(event),
+            }
+        }
+        decoded_events
+    }
+
+    /// Events for a controller message on a channel: the controller change
+    /// itself, preceded by any parameter change it completes
+    pub fn controller_events(
+        &mut self,
+        channel: usize,
+        controller: u8,
+        value: u8,
+    ) -> impl Iterator<Item = Event> + use<> {
+        let parameter_event = self.on_controller(channel, controller, value);
+        parameter_event
+            .into_iter()
+            .chain(std::iter::once(Event::Controller { controller, value }))
+    }
+
+    /// Track a controller message on a channel, returning an event if it
+    /// completes a change to a supported parameter
+    pub fn on_controller(&mut self, channel: usize, controller: u8, value: u8) -> Option<Event> {
+        let channel = channel % MIDI_CHANNEL_COUNT;
+        match controller {
+            CC_RPN_MSB => {
+                self.selected[channel].0 = value;
+                None
+            }
+            CC_RPN_LSB => {
+                self.selected[channel].1 = value;
+                None
+            }
+            CC_NRPN_LSB | CC_NRPN_MSB => {
+                // Data entry now applies to a non-registered parameter
+                self.selected[channel] = RPN_NULL;
+                None
+            }
+            CC_DATA_ENTRY_MSB | CC_DATA_ENTRY_LSB => {
+                if self.selected[channel] != RPN_PITCH_BEND_RANGE {
+                    return None;
+                }
+                let range = &mut self.pitch_bend_range[channel];
+                match controller {
+                    CC_DATA_ENTRY_MSB => range.0 = value,
+                    _ => range.1 = value,
+                };
+                Some(Event::PitchBendRange(
+                    range.0 as f32 + range.1.min(99) as f32 / 100.0,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
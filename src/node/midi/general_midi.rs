@@ -0,0 +1,352 @@
+use crate::{
+    AssetLoader, Error, Event, GraphNode, Message, Node, NoteRange, SampleBuffer,
+    abstraction::{ChildConfig, NodeConfig, defaults},
+    generator::SampleLoopNode,
+    group::{FontNode, FontNodeBuilder, PolyphonyNode},
+    midi::{ControllerBinding, MidiDataSource, MidiEvent},
+    node::{
+        group::font::{self, FontFileMetadata, PresetMetadata},
+        midi::event::MIDI_CHANNEL_COUNT,
+    },
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// Zero-based channel 10, which General MIDI reserves for percussion
+const PERCUSSION_CHANNEL: usize = 9;
+/// SoundFont bank holding percussion kits
+const PERCUSSION_BANK: u16 = 128;
+const CC_BANK_SELECT: u8 = 0;
+
+/// Plays any General MIDI sequence with the presets of a General MIDI
+/// SoundFont, chosen per channel by bank select and program change messages
+#[derive(Deserialize, Clone)]
+pub struct GeneralMidi {
+    #[serde(default = "defaults::none_id")]
+    pub node_id: Option<u64>,
+    pub source: MidiDataSource,
+    /// Path of the SF2 file
+    pub soundfont: String,
+    /// Voices for each key range of each preset
+    #[serde(default = "defaults::soundfont_polyphony_voices")]
+    pub polyphony_voices: usize,
+    #[serde(default = "defaults::pitch_bend_range")]
+    pub pitch_bend_range: f32,
+}
+
+impl NodeConfig for GeneralMidi {
+    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
+        let mut midi_builder = self.source.to_builder(self.node_id, asset_loader)?;
+        let (metadata, sample_buffer) = font::load_sf2_file(asset_loader, &self.soundfont)?;
+        let metadata = Arc::new(metadata);
+        let mut presets_used = presets_used_by_channel(&midi_builder.midi_events);
+        for channel in 0..MIDI_CHANNEL_COUNT {
+            let mut source = GeneralMidiChannelNode::new(
+                channel == PERCUSSION_CHANNEL,
+                metadata.clone(),
+                sample_buffer.clone(),
+                self.polyphony_voices,
+            );
+            for (bank, program) in presets_used.remove(&channel).unwrap_or_default() {
+                source.prepare_preset(bank, program)?;
+            }
+            midi_builder = midi_builder.add_program_selecting_source(channel, Box::new(source));
+        }
+        let node = midi_builder
+            .set_pitch_bend_range(self.pitch_bend_range)
+            .set_controller_bindings(ControllerBinding::general_midi())
+            .build()?;
+        Ok(Box::new(node))
+    }
+
+    fn clone_child_configs(&self) -> Option<Vec<ChildConfig>> {
+        None
+    }
+
+    fn asset_source(&self) -> Option<&str> {
+        Some(&self.soundfont)
+    }
+
+    fn duplicate(&self) -> Box<dyn NodeConfig + Send + Sync + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+/// Bank and program numbers that each channel selects, including the
+/// presets selected before any program change
+fn presets_used_by_channel(midi_events: &[MidiEvent]) -> HashMap<usize, HashSet<(u16, u8)>> {
+    let mut presets_used: HashMap<usize, HashSet<(u16, u8)>> = (0..MIDI_CHANNEL_COUNT)
+        .map(|channel| (channel, HashSet::from([(0, 0)])))
+        .collect();
+    let mut selected_banks: HashMap<usize, u16> = HashMap::new();
+    for event in midi_events.iter() {
+        match event.message.data {
+            Event::Controller {
+                controller: CC_BANK_SELECT,
+                value,
+            } => {
+                selected_banks.insert(event.channel, value as u16);
+            }
+            Event::ProgramChange(program) => {
+                let bank = selected_banks.get(&event.channel).copied().unwrap_or(0);
+                presets_used
+                    .entry(event.channel)
+                    .or_default()
+                    .insert((bank, program));
+            }
+            _ => {}
+        }
+    }
+    presets_used
+}
+
+/// Instrument for one channel of a General MIDI player. Presets are built
+/// from the SoundFont with prepare_preset before playback, as building them
+/// allocates; a program change to a preset that wasn't prepared is ignored.
+/// Bank select messages (controller 0) take effect at the next program
+/// change, as in the MIDI specification. Notes held across a program change
+/// carry on in the preset that started them until their own note-offs.
+pub struct GeneralMidiChannelNode {
+    node_id: u64,
+    is_percussion: bool,
+    metadata: Arc<FontFileMetadata>,
+    sample_buffer: SampleBuffer,
+    polyphony_voices: usize,
+    selected_bank: u16,
+    current_preset: (u16, u8),
+    /// Built presets by the bank and program that selected them, or None
+    /// where the SoundFont has no preset to play
+    presets: HashMap<(u16, u8), Option<FontNode>>,
+    /// Notes sounding on the channel, with the preset that started each
+    held_notes: Vec<(u8, (u16, u8))>,
+}
+
+impl GeneralMidiChannelNode {
+    pub(crate) fn new(
+        is_percussion: bool,
+        metadata: Arc<FontFileMetadata>,
+        sample_buffer: SampleBuffer,
+        polyphony_voices: usize,
+    ) -> Self {
+        Self {
+            node_id: <Self as Node>::new_node_id(),
+            is_percussion,
+            metadata,
+            sample_buffer,
+            polyphony_voices,
+            selected_bank: 0,
+            current_preset: (0, 0),
+            presets: HashMap::new(),
+            held_notes: Vec::with_capacity(128),
+        }
+    }
+
+    /// Preset for a bank and program, falling back to bank 0 for melodic
+    /// channels and to the standard kit for percussion
+    fn find_preset(&self, bank: u16, program: u8) -> Option<&PresetMetadata> {
+        let program = program as u16;
+        match self.is_percussion {
+            true => self
+                .metadata
+                .find_preset(PERCUSSION_BANK, program)
+                .or_else(|| self.metadata.find_preset(PERCUSSION_BANK, 0)),
+            false => self
+                .metadata
+                .find_preset(bank, program)
+                .or_else(|| self.metadata.find_preset(0, program)),
+        }
+    }
+
+    /// Build the preset for a bank and program, if not built already
+    pub fn prepare_preset(&mut self, bank: u16, program: u8) -> Result<(), Error> {
+        if self.presets.contains_key(&(bank, program)) {
+            return Ok(());
+        }
+        let font_node = match self.find_preset(bank, program) {
+            Some(preset) => Some(self.build_preset(preset)?),
+            None => {
+                println!(
+                    "WARNING: SF2: No {} preset for bank {} program {}",
+                    match self.is_percussion {
+                        true => "percussion",
+                        false => "melodic",
+                    },
+                    bank,
+                    program
+                );
+                None
+            }
+        };
+        self.presets.insert((bank, program), font_node);
+        Ok(())
+    }
+
+    /// Font of a preset's instrument samples over the key and velocity ranges
+    /// where the preset and instrument zones overlap, each with its own voices,
+    /// so that velocity layers play one at a time
+    fn build_preset(&self, preset: &PresetMetadata) -> Result<FontNode, Error> {
+        let mut font_builder = FontNodeBuilder::new(None);
+        for zone in preset.zones.iter() {
+            let Some(instrument) = self.metadata.instruments.get(zone.instrument_index) else {
+                println!(
+                    "WARNING: SF2: Instrument {} not found for preset zone",
+                    zone.instrument_index
+                );
+                continue;
+            };
+            for range in instrument.ranges.iter() {
+                let lower = zone
+                    .note_range
+                    .lower_inclusive
+                    .max(range.note_range.lower_inclusive);
+                let upper = zone
+                    .note_range
+                    .upper_inclusive
+                    .min(range.note_range.upper_inclusive);
+                let lower_velocity = zone
+                    .velocity_range
+                    .lower_inclusive
+                    .max(range.velocity_range.lower_inclusive);
+                let upper_velocity = zone
+                    .velocity_range
+                    .upper_inclusive
+                    .min(range.velocity_range.upper_inclusive);
+                if lower > upper || lower_velocity > upper_velocity {
+                    continue;
+                }
+                let loop_range = Some(range.loop_range.clone()).filter(|loop_range| {
+                    loop_range.start_frame < loop_range.end_frame
+                        && loop_range.end_frame * range.channel_count <= range.buffer_length
+                });
+                let source = SampleLoopNode::new(
+                    None,
+                    range.sample_rate,
+                    range.channel_count,
+                    range.base_note,
+                    loop_range,
+                    range.balance,
+                    self.sample_buffer.clone(),
+                    range.buffer_index,
+                    range.buffer_length,
+                )?;
+                let polyphony =
+                    PolyphonyNode::new(None, self.polyphony_voices.max(1), Box::new(source))?;
+                font_builder = font_builder.add_velocity_layer(
+                    NoteRange::new_inclusive_range(lower, upper),
+                    NoteRange::new_inclusive_range(lower_velocity, upper_velocity),
+                    Box::new(polyphony),
+                )?;
+            }
+        }
+        Ok(font_builder.build())
+    }
+
+    fn change_program(&mut self, program: u8) {
+        let next_preset = (self.selected_bank, program);
+        if next_preset == self.current_preset {
+            return;
+        }
+        if !self.presets.contains_key(&next_preset) {
+            println!(
+                "WARNING: SF2: Bank {} program {} was not prepared; keeping the current preset",
+                next_preset.0, next_preset.1
+            );
+            return;
+        }
+        self.current_preset = next_preset;
+    }
+}
+
+impl Node for GeneralMidiChannelNode {
+    fn get_node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: u64) {
+        self.node_id = node_id;
+    }
+
+    fn duplicate(&self) -> Result<GraphNode, Error> {
+        Err(Error::User(
+            "GeneralMidiChannelNode cannot be duplicated".to_owned(),
+        ))
+    }
+
+    fn try_consume_event(&mut self, event: &Message) -> bool {
+        match event.data {
+            Event::Controller {
+                controller: CC_BANK_SELECT,
+                value,
+            } => {
+                self.selected_bank = value as u16;
+                true
+            }
+            Event::ProgramChange(program) => {
+                self.change_program(program);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn propagate(&mut self, event: &Message) {
+        let preset = match event.data {
+            Event::NoteOn { note, vel } if vel > 0.0 => {
+                self.held_notes.retain(|(held, _)| *held != note);
+                self.held_notes.push((note, self.current_preset));
+                Some(self.current_preset)
+            }
+            Event::NoteOn { note, .. } | Event::NoteOff { note, .. } => self
+                .held_notes
+                .iter()
+                .position(|(held, _)| *held == note)
+                .map(|index| self.held_notes.remove(index).1),
+            Event::KeyPressure { note, .. } => self
+                .held_notes
+                .iter()
+                .find(|(held, _)| *held == note)
+                .map(|(_, preset)| *preset),
+            Event::AllNotesOff => {
+                self.held_notes.clear();
+                None
+            }
+            _ => None,
+        };
+        match event.data {
+            Event::NoteOn { .. } | Event::NoteOff { .. } | Event::KeyPressure { .. } => {
+                let preset = preset.unwrap_or(self.current_preset);
+                if let Some(Some(preset)) = self.presets.get_mut(&preset) {
+                    preset.on_event(event);
+                }
+            }
+            _ => {
+                for preset in self.presets.values_mut().flatten() {
+                    preset.on_event(event);
+                }
+            }
+        }
+    }
+
+    fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        for preset in self.presets.values_mut().flatten() {
+            preset.fill_buffer(buffer);
+        }
+    }
+
+    fn replace_children(&mut self, _children: &[GraphNode]) -> Result<(), Error> {
+        Err(Error::User(
+            "GeneralMidiChannelNode does not support replacing its children".to_owned(),
+        ))
+    }
+
+    fn get_state_snapshot(&self, _for_node_id: u64) -> Option<Result<Value, Error>> {
+        Some(Err(Error::User(
+            "GeneralMidiChannelNode does not support propagating requests to its children"
+                .to_owned(),
+        )))
+    }
+}
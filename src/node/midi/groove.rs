@@ -0,0 +1,184 @@
+use crate::{Event, Message, midi::MidiEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const MAX_SWING: f32 = 0.9;
+
+/// Note length whose off-beats are delayed by swing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum SwingGrid {
+    #[default]
+    Eighths,
+    Sixteenths,
+}
+
+impl SwingGrid {
+    fn steps_per_beat(&self) -> f64 {
+        match self {
+            Self::Eighths => 2.0,
+            Self::Sixteenths => 4.0,
+        }
+    }
+}
+
+/// Timing and velocity variation applied to the notes of a sequence. Notes are
+/// quantised first, then swung, then humanised. Other events keep their
+/// timing, and the events of each note keep their order.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Groove {
+    /// How far off-beats are delayed, from 0 (straight) towards 1 (onto the
+    /// next beat); about 0.33 gives a triplet feel
+    #[serde(default)]
+    pub swing: f32,
+    #[serde(default)]
+    pub swing_grid: SwingGrid,
+    /// Grid to snap notes to, in divisions per beat, such as 4 for sixteenths
+    #[serde(default)]
+    pub quantize: Option<u32>,
+    /// Largest random timing offset of a note-on, in beats
+    #[serde(default)]
+    pub humanize_timing: f32,
+    /// Largest random change to a note-on velocity, in the 0 to 1 range
+    #[serde(default)]
+    pub humanize_velocity: f32,
+    /// Seed for humanisation, so that the same seed gives the same variation
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl Default for Groove {
+    fn default() -> Self {
+        Self {
+            swing: 0.0,
+            swing_grid: SwingGrid::Eighths,
+            quantize: None,
+            humanize_timing: 0.0,
+            humanize_velocity: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl Groove {
+    /// Whether the groove would leave every event unchanged
+    pub fn is_neutral(&self) -> bool {
+        self.swing == 0.0
+            && self.quantize.is_none()
+            && self.humanize_timing == 0.0
+            && self.humanize_velocity == 0.0
+    }
+
+    /// Apply the groove to a sequence, giving the changed events along with
+    /// the absolute tick of each. Events are put in order of their new ticks,
+    /// keeping their previous order where ticks are equal.
+    pub fn apply(&self, events: &[MidiEvent], ticks_per_beat: u16) -> (Vec<MidiEvent>, Vec<u64>) {
+        let ticks_per_beat = ticks_per_beat as f64;
+        let mut random_state = self.seed | 1;
+        let mut random_unit =
+            move || (xorshift(&mut random_state) >> 11) as f64 / (1u64 << 53) as f64;
+        let mut source_tick = 0u64;
+        // Latest tick of each channel and note's events, so that a note's
+        // events are never reordered
+        let mut note_ticks: HashMap<(usize, u8), u64> = HashMap::new();
+        let mut timed_events: Vec<(u64, MidiEvent)> = Vec::with_capacity(events.len());
+        for event in events.iter() {
+            source_tick += event.delta_ticks.max(0) as u64;
+            let mut data = event.message.data.clone();
+            let mut tick = source_tick as f64;
+            let note = match &mut data {
+                Event::NoteOn { note, vel } => {
+                    tick = self.grid_tick(tick, ticks_per_beat);
+                    if *vel > 0.0 {
+                        let offset = (random_unit() * 2.0 - 1.0) * self.humanize_timing as f64;
+                        tick += offset * ticks_per_beat;
+                        let change = (random_unit() * 2.0 - 1.0) as f32 * self.humanize_velocity;
+                        *vel = (*vel + change).clamp(0.001, 1.0);
+                    }
+                    Some(*note)
+                }
+                Event::NoteOff { note, .. } => {
+                    tick = self.grid_tick(tick, ticks_per_beat);
+                    Some(*note)
+                }
+                _ => None,
+            };
+            let mut tick = tick.round().max(0.0) as u64;
+            if let Some(note) = note {
+                let note_tick = note_ticks.entry((event.channel, note)).or_default();
+                tick = tick.max(*note_tick);
+                *note_tick = tick;
+            }
+            timed_events.push((
+                tick,
+                MidiEvent {
+                    delta_ticks: 0,
+                    channel: event.channel,
+                    message: Message {
+                        data,
+                        ..event.message.clone()
+                    },
+                },
+            ));
+        }
+        timed_events.sort_by_key(|(tick, _)| *tick);
+
+        let mut previous_tick = 0u64;
+        let mut event_ticks: Vec<u64> = Vec::with_capacity(timed_events.len());
+        let grooved_events = timed_events
+            .into_iter()
+            .map(|(tick, event)| {
+                event_ticks.push(tick);
+                let delta_ticks = (tick - previous_tick) as isize;
+                previous_tick = tick;
+                MidiEvent {
+                    delta_ticks,
+                    ..event
+                }
+            })
+            .collect();
+        (grooved_events, event_ticks)
+    }
+
+    fn grid_tick(&self, tick: f64, ticks_per_beat: f64) -> f64 {
+        self.swung_tick(self.quantized_tick(tick, ticks_per_beat), ticks_per_beat)
+    }
+
+    fn quantized_tick(&self, tick: f64, ticks_per_beat: f64) -> f64 {
+        match self.quantize {
+            Some(divisions) if divisions > 0 => {
+                let step = ticks_per_beat / divisions as f64;
+                (tick / step).round() * step
+            }
+            _ => tick,
+        }
+    }
+
+    /// Stretch the first half of each pair of grid steps and compress the
+    /// second, so that the off-beat lands later
+    fn swung_tick(&self, tick: f64, ticks_per_beat: f64) -> f64 {
+        let swing = self.swing.clamp(0.0, MAX_SWING) as f64;
+        if swing == 0.0 {
+            return tick;
+        }
+        let step = ticks_per_beat / self.swing_grid.steps_per_beat();
+        let pair = step * 2.0;
+        let pair_start = (tick / pair).floor() * pair;
+        let phase = tick - pair_start;
+        let swung_off_beat = step * (1.0 + swing);
+        let swung_phase = match phase < step {
+            true => phase / step * swung_off_beat,
+            false => swung_off_beat + (phase - step) / step * (pair - swung_off_beat),
+        };
+        pair_start + swung_phase
+    }
+}
+
+/// Next value of a xorshift generator; the state must not be zero
+pub(crate) fn xorshift(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    x
+}
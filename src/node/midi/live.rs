@@ -0,0 +1,201 @@
+use crate::{
+    Error, EventTarget, EventTiming, Message, MessageSender,
+    abstraction::defaults,
+    consts,
+    midi::{ChannelRouting, ControllerBinding, ControllerMapper, MidiEvent},
+    node::midi::{event::RegisteredParameters, routing::ChannelRoutes},
+};
+use midly::{MidiMessage, TrackEventKind, live::LiveEvent, stream::MidiStream};
+use std::{io::Read, sync::Arc};
+
+const READ_CHUNK_BYTES: usize = 256;
+
+/// Parser for a live MIDI 1.0 byte stream, such as from a hardware bridge or a
+/// pipe from another process, which sends channel messages into the graph.
+/// Messages are mapped the same way as MIDI file data played by a MidiNode,
+/// and channels are routed to nodes with the same routing tables as a
+/// MidiNode's channel sources. Channels without a target node are ignored.
+pub struct MidiInput {
+    sender: Arc<MessageSender>,
+    stream: MidiStream,
+    channel_routes: ChannelRoutes,
+    /// Node IDs by destination key
+    target_nodes: Vec<u64>,
+    registered_parameters: RegisteredParameters,
+    controller_mapper: ControllerMapper,
+    latency_frames: u64,
+}
+
+impl MidiInput {
+    pub fn new(sender: Arc<MessageSender>) -> Self {
+        Self {
+            sender,
+            stream: MidiStream::new(),
+            channel_routes: ChannelRoutes::default(),
+            target_nodes: vec![],
+            registered_parameters: RegisteredParameters::default(),
+            controller_mapper: ControllerMapper::new(defaults::controller_bindings())
+                .expect("General MIDI controller bindings are valid"),
+            latency_frames: consts::BUFFER_SIZE as u64,
+        }
+    }
+
+    /// Send messages on a MIDI channel (0 to 15) to the node with this ID
+    pub fn add_channel_target(mut self, channel: usize, node_id: u64) -> Self {
+        let destination = match self.target_nodes.iter().position(|id| *id == node_id) {
+            Some(destination) => destination,
+            None => {
+                self.target_nodes.push(node_id);
+                self.target_nodes.len() - 1
+            }
+        };
+        self.channel_routes.add_route(channel, destination);
+        self
+    }
+
+    /// Route channels by a table, sending the channels of each destination to
+    /// the node with the ID at the same index, in place of any targets added
+    /// before
+    pub fn set_channel_routing(
+        mut self,
+        routing: &ChannelRouting,
+        node_ids: &[u64],
+    ) -> Result<Self, Error> {
+        if routing.destinations.len() != node_ids.len() {
+            return Err(Error::User(format!(
+                "ERROR: MIDI input: Routing has {} destinations but {} node IDs were given",
+                routing.destinations.len(),
+                node_ids.len()
+            )));
+        }
+        self.channel_routes = ChannelRoutes::from_routing(routing)?;
+        self.target_nodes = node_ids.to_vec();
+        Ok(self)
+    }
+
+    /// Set the pitch bend range, in semitones, that RPN 0 messages changing
+    /// only its coarse or fine part start from. This should match the range
+    /// the channel targets were set up with.
+    pub fn set_pitch_bend_range(mut self, semitones: f32) -> Self {
+        self.registered_parameters = RegisteredParameters::new(semitones);
+        self
+    }
+
+    /// Set how controller (CC) messages map to events sent to the channel
+    /// targets, replacing the General MIDI defaults
+    pub fn set_controller_bindings(
+        mut self,
+        bindings: Vec<ControllerBinding>,
+    ) -> Result<Self, Error> {
+        self.controller_mapper = ControllerMapper::new(bindings)?;
+        Ok(self)
+    }
+
+    /// Set how far ahead of the audio thread incoming messages are scheduled.
+    /// Enough latency keeps the spacing between messages steady, regardless of
+    /// when the audio thread picks them up.
+    pub fn set_latency_frames(mut self, frames: u64) -> Self {
+        self.latency_frames = frames;
+        self
+    }
+
+    /// Parse bytes that have just arrived, scheduling their messages after the
+    /// configured latency
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let absolute_frame = self.sender.current_rendering_absolute_frame() + self.latency_frames;
+        self.push_bytes_at(bytes, absolute_frame)
+    }
+
+    /// Parse bytes, scheduling their messages at a particular frame
+    pub fn push_bytes_at(&mut self, bytes: &[u8], absolute_frame: u64) -> Result<(), Error> {
+        let mut midi_events: Vec<MidiEvent> = vec![];
+        let registered_parameters = &mut self.registered_parameters;
+        self.stream.feed(bytes, |live_event| {
+            Self::collect_events(registered_parameters, live_event, &mut midi_events);
+        });
+        for event in midi_events.into_iter() {
+            self.send_event(event, absolute_frame)?;
+        }
+        Ok(())
+    }
+
+    /// Read and parse bytes until the reader is exhausted, scheduling messages
+    /// as their bytes arrive
+    pub fn read_from<R: Read>(&mut self, mut reader: R) -> Result<(), Error> {
+        let mut buffer = [0u8; READ_CHUNK_BYTES];
+        loop {
+            let bytes_read = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.push_bytes(&buffer[0..bytes_read])?;
+        }
+    }
+
+    fn collect_events(
+        registered_parameters: &mut RegisteredParameters,
+        live_event: LiveEvent,
+        midi_events: &mut Vec<MidiEvent>,
+    ) {
+        let LiveEvent::Midi { channel, message } = live_event else {
+            return;
+        };
+        let channel_index = u8::from(channel) as usize;
+        if let MidiMessage::Controller { controller, value } = message {
+            for data in registered_parameters.controller_events(
+                channel_index,
+                u8::from(controller),
+                u8::from(value),
+            ) {
+                midi_events.push(MidiEvent {
+                    delta_ticks: 0,
+                    channel: channel_index,
+                    message: Message::broadcast(data),
+                });
+            }
+            return;
+        }
+        if let Some(event) =
+            MidiEvent::from_midi_event(0, &TrackEventKind::Midi { channel, message })
+        {
+            midi_events.push(event);
+        }
+    }
+
+    fn send_event(&mut self, event: MidiEvent, absolute_frame: u64) -> Result<(), Error> {
+        let Some(node_id) = self
+            .channel_routes
+            .route(event.channel)
+            .and_then(|destination| self.target_nodes.get(destination))
+        else {
+            return Ok(());
+        };
+        let target = EventTarget::SpecificNode(*node_id);
+        let timing = EventTiming::AtAbsoluteFrame(absolute_frame);
+        let mut mapped_messages: Vec<Message> = vec![];
+        self.controller_mapper
+            .apply_event(event.channel, &event.message.data, |message| {
+                mapped_messages.push(message.clone())
+            });
+        mapped_messages.push(event.message);
+        for message in mapped_messages.into_iter() {
+            // Bindings aimed at a specific node keep their own target
+            let target = match message.target {
+                EventTarget::Broadcast => target,
+                specific_target => specific_target,
+            };
+            self.sender
+                .send(Message {
+                    target,
+                    data: message.data,
+                    timing,
+                })
+                .map_err(|_| {
+                    Error::Internal("MIDI input: audio thread is not receiving".to_owned())
+                })?;
+        }
+        Ok(())
+    }
+}
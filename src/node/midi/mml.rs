@@ -0,0 +1,376 @@
+use crate::{
+    Error, Event, Message,
+    abstraction::defaults,
+    midi::{CueData, MidiEvent, MidiTextKind, TempoMap},
+    node::midi::{event::MIDI_CHANNEL_COUNT, text},
+};
+
+const DEFAULT_OCTAVE: i32 = 4;
+const DEFAULT_LENGTH: u32 = 4;
+const DEFAULT_VOLUME: u32 = 15;
+const MAX_VOLUME: u32 = 15;
+const DEFAULT_GATE: u32 = 8;
+const MAX_GATE: u32 = 8;
+const DEFAULT_LOOP_COUNT: u32 = 2;
+
+/// Event from one MML channel, at an absolute tick
+struct TimedEvent {
+    tick: u64,
+    channel: usize,
+    data: Event,
+}
+
+/// Open loop while interpreting, with where to jump back to
+struct LoopState {
+    start: usize,
+    iteration: u32,
+}
+
+/// Parse MML (Music Macro Language) text into the events played by a
+/// MidiNode, along with the tempo map of its tempo commands. Channels are
+/// separated by `;`, starting at channel 0. See the README for the commands.
+pub fn parse_mml(text: &str) -> Result<(Vec<MidiEvent>, TempoMap), Error> {
+    let ticks_per_beat = defaults::ticks_per_beat();
+    let mut timed_events: Vec<TimedEvent> = vec![];
+    let mut tempo_changes: Vec<(u64, f64)> = vec![];
+    for (channel, channel_text) in text.split(';').enumerate() {
+        if channel >= MIDI_CHANNEL_COUNT {
+            return Err(Error::User(format!(
+                "ERROR: MML: More than {} channels",
+                MIDI_CHANNEL_COUNT
+            )));
+        }
+        let mut parser = MmlChannelParser::new(channel, channel_text, ticks_per_beat);
+        parser.parse()?;
+        timed_events.extend(parser.events);
+        tempo_changes.extend(parser.tempo_changes);
+    }
+
+    // Stable sorts keep each channel's order of events at the same tick
+    timed_events.sort_by_key(|event| event.tick);
+    tempo_changes.sort_by_key(|(tick, _)| *tick);
+
+    let mut previous_tick = 0u64;
+    let midi_events = timed_events
+        .into_iter()
+        .map(|event| {
+            let delta_ticks = (event.tick - previous_tick) as isize;
+            previous_tick = event.tick;
+            MidiEvent {
+                delta_ticks,
+                channel: event.channel,
+                message: Message::broadcast(event.data),
+            }
+        })
+        .collect();
+    let tempo_map = TempoMap::from_tempo_changes(ticks_per_beat, &tempo_changes);
+    Ok((midi_events, tempo_map))
+}
+
+struct MmlChannelParser<'a> {
+    channel: usize,
+    text: &'a str,
+    chars: Vec<char>,
+    index: usize,
+    ticks_per_beat: u16,
+    tick: u64,
+    octave: i32,
+    length_ticks: u64,
+    volume: u32,
+    gate: u32,
+    /// Note sounding from the last note command, with its on and off ticks,
+    /// so that a tie can extend it
+    last_note: Option<(u8, u64, u64)>,
+    loops: Vec<LoopState>,
+    events: Vec<TimedEvent>,
+    tempo_changes: Vec<(u64, f64)>,
+}
+
+impl<'a> MmlChannelParser<'a> {
+    fn new(channel: usize, text: &'a str, ticks_per_beat: u16) -> Self {
+        Self {
+            channel,
+            text,
+            chars: text.chars().collect(),
+            index: 0,
+            ticks_per_beat,
+            tick: 0,
+            octave: DEFAULT_OCTAVE,
+            length_ticks: ticks_per_beat as u64 * 4 / DEFAULT_LENGTH as u64,
+            volume: DEFAULT_VOLUME,
+            gate: DEFAULT_GATE,
+            last_note: None,
+            loops: vec![],
+            events: vec![],
+            tempo_changes: vec![],
+        }
+    }
+
+    fn error(&self, problem: &str) -> Error {
+        Error::User(format!(
+            "ERROR: MML: Channel {}: {} at character {} of \"{}\"",
+            self.channel,
+            problem,
+            self.index + 1,
+            self.text.trim()
+        ))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn parse(&mut self) -> Result<(), Error> {
+        while let Some(ch) = self.peek() {
+            self.index += 1;
+            match ch.to_ascii_lowercase() {
+                ch if ch.is_whitespace() => {}
+                'c' | 'd' | 'e' | 'f' | 'g' | 'a' | 'b' => {
+                    self.parse_note(ch.to_ascii_lowercase())?
+                }
+                'n' => {
+                    let note = self.parse_number()?;
+                    if note > 127 {
+                        return Err(self.error("note number above 127"));
+                    }
+                    let length_ticks = self.parse_length()?;
+                    self.play_note(note as u8, length_ticks);
+                }
+                'r' => {
+                    self.end_last_note();
+                    let length_ticks = self.parse_length()?;
+                    self.tick += length_ticks;
+                }
+                '^' => {
+                    let length_ticks = self.parse_length()?;
+                    self.extend_last_note(length_ticks);
+                }
+                'o' => self.octave = self.parse_number()? as i32,
+                '>' => self.octave += 1,
+                '<' => self.octave -= 1,
+                'l' => self.length_ticks = self.parse_length_value()?,
+                'v' => self.volume = self.parse_number()?.min(MAX_VOLUME),
+                'q' => self.gate = self.parse_number()?.clamp(1, MAX_GATE),
+                't' => {
+                    let beats_per_minute = self.parse_number()?;
+                    if beats_per_minute == 0 {
+                        return Err(self.error("tempo must be above zero"));
+                    }
+                    self.tempo_changes
+                        .push((self.tick, 60000000.0 / beats_per_minute as f64));
+                }
+                '[' => self.loops.push(LoopState {
+                    start: self.index,
+                    iteration: 0,
+                }),
+                ':' => self.parse_loop_break()?,
+                ']' => self.parse_loop_end()?,
+                '\'' => self.parse_cue()?,
+                _ => {
+                    self.index -= 1;
+                    return Err(self.error(&format!("unknown command '{}'", ch)));
+                }
+            }
+        }
+        if !self.loops.is_empty() {
+            return Err(self.error("loop is not closed with ']'"));
+        }
+        self.end_last_note();
+        Ok(())
+    }
+
+    fn parse_note(&mut self, letter: char) -> Result<(), Error> {
+        let mut semitone = match letter {
+            'c' => 0,
+            'd' => 2,
+            'e' => 4,
+            'f' => 5,
+            'g' => 7,
+            'a' => 9,
+            _ => 11,
+        };
+        loop {
+            match self.peek() {
+                Some('+') | Some('#') => semitone += 1,
+                Some('-') => semitone -= 1,
+                _ => break,
+            }
+            self.index += 1;
+        }
+        let note = (self.octave + 1) * 12 + semitone;
+        if !(0..=127).contains(&note) {
+            return Err(self.error("note is outside the MIDI note range"));
+        }
+        let length_ticks = self.parse_length()?;
+        self.play_note(note as u8, length_ticks);
+        Ok(())
+    }
+
+    fn play_note(&mut self, note: u8, length_ticks: u64) {
+        self.end_last_note();
+        // A silent note is a rest, as a NoteOn at velocity 0 is a NoteOff
+        if self.volume == 0 {
+            self.tick += length_ticks;
+            return;
+        }
+        self.events.push(TimedEvent {
+            tick: self.tick,
+            channel: self.channel,
+            data: Event::NoteOn {
+                note,
+                vel: self.volume as f32 / MAX_VOLUME as f32,
+            },
+        });
+        self.last_note = Some((note, self.tick, self.tick + length_ticks));
+        self.tick += length_ticks;
+    }
+
+    fn extend_last_note(&mut self, length_ticks: u64) {
+        self.tick += length_ticks;
+        if let Some((note, on_tick, _)) = self.last_note {
+            self.last_note = Some((note, on_tick, self.tick));
+        }
+    }
+
+    /// Release the last note, shortened by the gate setting
+    fn end_last_note(&mut self) {
+        let Some((note, on_tick, off_tick)) = self.last_note.take() else {
+            return;
+        };
+        let sounding_ticks = (off_tick - on_tick) * self.gate as u64 / MAX_GATE as u64;
+        self.events.push(TimedEvent {
+            tick: on_tick + sounding_ticks.max(1),
+            channel: self.channel,
+            data: Event::NoteOff { note, vel: 0.0 },
+        });
+    }
+
+    fn parse_cue(&mut self) -> Result<(), Error> {
+        let start_index = self.index;
+        while self.peek().is_some_and(|ch| ch != '\'') {
+            self.index += 1;
+        }
+        if self.peek().is_none() {
+            return Err(self.error("cue label is not closed with '"));
+        }
+        let label: String = self.chars[start_index..self.index].iter().collect();
+        self.index += 1;
+        if !text::is_cue_label(label.as_bytes()) {
+            self.events.push(TimedEvent {
+                tick: self.tick,
+                channel: self.channel,
+                data: Event::Text {
+                    kind: MidiTextKind::CuePoint,
+                    text: label,
+                },
+            });
+            return Ok(());
+        }
+        for cue in CueData::from_label(label.as_bytes())?.into_iter() {
+            self.events.push(TimedEvent {
+                tick: self.tick,
+                channel: self.channel,
+                data: Event::CueData(cue),
+            });
+        }
+        Ok(())
+    }
+
+    /// On the last pass through a loop, skip the rest of it after a ':'
+    fn parse_loop_break(&mut self) -> Result<(), Error> {
+        let Some(loop_state) = self.loops.last() else {
+            return Err(self.error("':' outside of a loop"));
+        };
+        let iteration = loop_state.iteration;
+        let end_index = self.find_loop_end()?;
+        let resume_index = self.index;
+        self.index = end_index + 1;
+        let count = self.parse_loop_count()?;
+        if iteration + 1 < count {
+            self.index = resume_index;
+        } else {
+            self.loops.pop();
+        }
+        Ok(())
+    }
+
+    fn parse_loop_end(&mut self) -> Result<(), Error> {
+        let count = self.parse_loop_count()?;
+        let Some(loop_state) = self.loops.last_mut() else {
+            return Err(self.error("']' without a matching '['"));
+        };
+        loop_state.iteration += 1;
+        if loop_state.iteration < count {
+            self.index = loop_state.start;
+        } else {
+            self.loops.pop();
+        }
+        Ok(())
+    }
+
+    fn parse_loop_count(&mut self) -> Result<u32, Error> {
+        if !self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            return Ok(DEFAULT_LOOP_COUNT);
+        }
+        let count = self.parse_number()?;
+        if count == 0 {
+            return Err(self.error("loop count must be at least 1; use cues for endless loops"));
+        }
+        Ok(count)
+    }
+
+    /// Index of the ']' closing the innermost open loop
+    fn find_loop_end(&self) -> Result<usize, Error> {
+        let mut depth = 0;
+        for (index, ch) in self.chars.iter().enumerate().skip(self.index) {
+            match ch {
+                '[' => depth += 1,
+                ']' if depth == 0 => return Ok(index),
+                ']' => depth -= 1,
+                _ => {}
+            }
+        }
+        Err(self.error("loop is not closed with ']'"))
+    }
+
+    fn parse_number(&mut self) -> Result<u32, Error> {
+        let start_index = self.index;
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.index += 1;
+        }
+        let digits: String = self.chars[start_index..self.index].iter().collect();
+        digits.parse().map_err(|_| self.error("expected a number"))
+    }
+
+    /// Length of a note or rest, which is the default length if none is given
+    fn parse_length(&mut self) -> Result<u64, Error> {
+        match self.peek() {
+            Some(ch) if ch.is_ascii_digit() => self.parse_length_value(),
+            _ => {
+                let length_ticks = self.length_ticks;
+                Ok(self.apply_dots(length_ticks))
+            }
+        }
+    }
+
+    /// Length in ticks of a note value such as 4 (quarter) or 8. (dotted eighth)
+    fn parse_length_value(&mut self) -> Result<u64, Error> {
+        let division = self.parse_number()?;
+        if division == 0 {
+            return Err(self.error("note length must be above zero"));
+        }
+        let length_ticks = self.ticks_per_beat as u64 * 4 / division as u64;
+        Ok(self.apply_dots(length_ticks))
+    }
+
+    fn apply_dots(&mut self, length_ticks: u64) -> u64 {
+        let mut total_ticks = length_ticks;
+        let mut dot_ticks = length_ticks;
+        while self.peek() == Some('.') {
+            self.index += 1;
+            dot_ticks /= 2;
+            total_ticks += dot_ticks;
+        }
+        total_ticks
+    }
+}
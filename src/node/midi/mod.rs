@@ -1,5 +1,22 @@
+pub mod channel_mix;
+mod chase;
+pub mod controller;
 pub mod cue;
 pub mod event;
+pub mod general_midi;
+pub mod groove;
+pub mod live;
+pub mod mml;
+pub mod prepared;
+pub mod program;
+pub mod recorder;
+pub mod routing;
+pub mod sequence;
+pub mod stinger;
+pub mod tempo;
+pub mod text;
+pub mod tracker;
+pub mod transform;
 pub mod util;
 
 use crate::{
@@ -7,17 +24,136 @@ use crate::{
     MidiPlaybackState, Node,
     abstraction::{ChildConfig, NodeConfig, defaults},
     consts,
-    midi::{CueData, MidiEvent},
-    node::log,
+    midi::{
+        Anchor, ChannelMix, ChannelMixSettings, ControllerBinding, ControllerMapper, CueData,
+        Groove, MidiEvent, MidiText, NoteTransform, ProgramBank, SeekPosition, SequenceEvent,
+        TempoMap,
+    },
+    node::{
+        log,
+        midi::{
+            chase::ChaseStates,
+            event::RegisteredParameters,
+            prepared::PreparedMidiFile,
+            routing::{ChannelNumbering, ChannelRoutes, ChannelRouting, FALLBACK_DESTINATION},
+            stinger::{Stinger, StingerAlignment, StingerConfig, StingerStart, Stingers},
+            tempo::TempoOverride,
+            text::TextSubscribers,
+            tracker::TrackerModule,
+        },
+    },
 };
 use midly::Smf;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// Fraction of a sample by which an event time may be passed and still count
+/// as falling on that sample, absorbing floating point error
+const SAMPLE_TOLERANCE: f64 = 0.000001;
+
+/// Non-zero seed for random cue targets, varied between runs where the
+/// platform provides randomness to hashers
+fn initial_random_state() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let seed = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    seed | 1
+}
 
 #[derive(Deserialize, Clone)]
 pub enum MidiDataSource {
-    FilePath { path: String, track_index: usize },
+    FilePath {
+        path: String,
+        track_index: usize,
+    },
+    /// Standard MIDI File data embedded in the config
+    Bytes {
+        bytes: Vec<u8>,
+        track_index: usize,
+    },
+    /// ProTracker module (.MOD) file, whose samples are played as the
+    /// programs of each channel
+    ModFile {
+        path: String,
+    },
+    /// Music Macro Language text, with channels separated by ';'
+    Mml {
+        text: String,
+    },
+    /// Events written directly in the config, at a constant tempo
+    Events {
+        #[serde(default = "defaults::ticks_per_beat")]
+        ticks_per_beat: u16,
+        #[serde(default = "defaults::beats_per_minute")]
+        beats_per_minute: f64,
+        events: Vec<SequenceEvent>,
+    },
+}
+
+impl MidiDataSource {
+    /// Decode or load the events and tempo map of the source
+    pub fn to_builder(
+        &self,
+        node_id: Option<u64>,
+        asset_loader: &mut dyn AssetLoader,
+    ) -> Result<MidiNodeBuilder, Error> {
+        let builder = match self {
+            MidiDataSource::FilePath { path, track_index } => {
+                let prepared_file = match asset_loader.load_asset_data(path)? {
+                    AssetLoadPayload::RawAssetData(bytes) => {
+                        let smf = Smf::parse(&bytes)?;
+                        if DebugLogging::get_log_on_init() {
+                            log::log_loaded_midi_track(&smf, *track_index);
+                        }
+                        let prepared_file = PreparedMidiFile::from_smf(&smf)?;
+                        let raw_metadata = Arc::new(serde_json::to_vec(&prepared_file)?);
+                        asset_loader.store_prepared_data(path, raw_metadata, Arc::new(vec![]));
+                        prepared_file
+                    }
+                    AssetLoadPayload::PreparedData((raw_metadata, _)) => {
+                        serde_json::from_slice(&raw_metadata)?
+                    }
+                };
+                MidiNodeBuilder::from_prepared_file(node_id, &prepared_file, *track_index)?
+            }
+            MidiDataSource::ModFile { path } => {
+                let bytes = match asset_loader.load_asset_data(path)? {
+                    AssetLoadPayload::RawAssetData(bytes) => bytes,
+                    AssetLoadPayload::PreparedData(_) => {
+                        return Err(Error::User(
+                            "ERROR: MOD: Module files cannot be prepared.".to_owned(),
+                        ));
+                    }
+                };
+                MidiNodeBuilder::from_mod(node_id, &bytes)?
+            }
+            MidiDataSource::Bytes { bytes, track_index } => {
+                let smf = Smf::parse(bytes)?;
+                MidiNodeBuilder::new(node_id, smf, *track_index)?
+            }
+            MidiDataSource::Mml { text } => {
+                let (midi_events, tempo_map) = mml::parse_mml(text)?;
+                MidiNodeBuilder::from_events(node_id, midi_events, tempo_map)
+            }
+            MidiDataSource::Events {
+                ticks_per_beat,
+                beats_per_minute,
+                events,
+            } => {
+                let micros_per_beat = 60000000.0 / beats_per_minute.max(f64::EPSILON);
+                let tempo_map =
+                    TempoMap::from_tempo_changes(*ticks_per_beat, &[(0, micros_per_beat)]);
+                let midi_events = sequence::midi_events_from_sequence(events, &tempo_map)?;
+                MidiNodeBuilder::from_events(node_id, midi_events, tempo_map)
+            }
+        };
+        Ok(builder)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -26,7 +162,12 @@ struct MidiPlaybackPosition {
     pub has_finished: bool,
     pub cumulative_samples: u64,
     pub next_event_index: usize,
-    pub event_samples_progress: isize,
+    pub event_samples_progress: f64,
+    #[serde(default)]
+    pub channel_mix: Vec<ChannelMixSettings>,
+    /// Tempo at the time of the snapshot; reported only, and not restored
+    #[serde(default)]
+    pub beats_per_minute: Option<f64>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -35,29 +176,101 @@ pub struct Midi {
     pub node_id: Option<u64>,
     pub source: MidiDataSource,
     pub channels: HashMap<usize, ChildConfig>,
+    /// Numbering of the channels used as keys in this config
+    #[serde(default)]
+    pub channel_numbering: ChannelNumbering,
+    /// Channels that play the source of another channel, by that channel
+    #[serde(default)]
+    pub shared_channels: HashMap<usize, usize>,
+    /// Source for every channel without one of its own
+    #[serde(default)]
+    pub other_channels: Option<ChildConfig>,
+    /// Instruments for each channel, by program number, for ProgramChange
+    /// messages to switch between
+    #[serde(default)]
+    pub programs: HashMap<usize, HashMap<u8, ChildConfig>>,
+    #[serde(default = "defaults::pitch_bend_range")]
+    pub pitch_bend_range: f32,
+    #[serde(default = "defaults::controller_bindings")]
+    pub controllers: Vec<ControllerBinding>,
+    /// Transposition, velocity curve and note remapping for each channel
+    #[serde(default)]
+    pub transforms: HashMap<usize, NoteTransform>,
+    /// Swing, quantisation and humanisation of note timing
+    #[serde(default)]
+    pub groove: Groove,
+    /// Phrases that can be layered over the music, by name
+    #[serde(default)]
+    pub stingers: HashMap<String, StingerConfig>,
 }
 
-impl NodeConfig for Midi {
-    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
-        let mut midi_builder = match &self.source {
-            MidiDataSource::FilePath { path, track_index } => {
-                let bytes = match asset_loader.load_asset_data(path)? {
-                    AssetLoadPayload::RawAssetData(bytes) => bytes,
-                    AssetLoadPayload::PreparedData(_) => {
-                        return Err(Error::User(
-                            "ERROR: MIDI: MIDI files cannot be prepared.".to_owned(),
-                        ));
-                    }
-                };
-                let smf = Smf::parse(&bytes)?;
-                MidiNodeBuilder::new(self.node_id, smf, *track_index)?
-            }
-        };
+impl Midi {
+    /// Config playing a source with the given channel sources, with every
+    /// other setting at its default
+    pub fn new(
+        node_id: Option<u64>,
+        source: MidiDataSource,
+        channels: HashMap<usize, ChildConfig>,
+    ) -> Self {
+        Self {
+            node_id,
+            source,
+            channels,
+            channel_numbering: ChannelNumbering::default(),
+            shared_channels: HashMap::new(),
+            other_channels: None,
+            programs: HashMap::new(),
+            pitch_bend_range: defaults::pitch_bend_range(),
+            controllers: defaults::controller_bindings(),
+            transforms: HashMap::new(),
+            groove: Groove::default(),
+            stingers: HashMap::new(),
+        }
+    }
+
+    pub fn to_midi_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<MidiNode, Error> {
+        let mut midi_builder = self.source.to_builder(self.node_id, asset_loader)?;
+        let numbering = self.channel_numbering;
         for (channel, source) in self.channels.iter() {
             let source = source.0.to_node(asset_loader)?;
-            midi_builder = midi_builder.add_channel_source(*channel, source);
+            midi_builder =
+                midi_builder.add_channel_source(numbering.to_zero_based(*channel)?, source);
+        }
+        for (channel, source_channel) in self.shared_channels.iter() {
+            midi_builder = midi_builder.share_channel_source(
+                numbering.to_zero_based(*channel)?,
+                numbering.to_zero_based(*source_channel)?,
+            );
+        }
+        if let Some(source) = &self.other_channels {
+            midi_builder = midi_builder.set_other_channels_source(source.0.to_node(asset_loader)?);
+        }
+        for (channel, programs) in self.programs.iter() {
+            let channel = numbering.to_zero_based(*channel)?;
+            for (program, source) in programs.iter() {
+                let source = source.0.to_node(asset_loader)?;
+                midi_builder = midi_builder.add_channel_program(channel, *program, source);
+            }
+        }
+        midi_builder = midi_builder
+            .set_pitch_bend_range(self.pitch_bend_range)
+            .set_controller_bindings(self.controllers.clone());
+        midi_builder = midi_builder.set_groove(self.groove.clone());
+        for (channel, transform) in self.transforms.iter() {
+            midi_builder = midi_builder
+                .set_channel_transform(numbering.to_zero_based(*channel)?, transform.clone());
+        }
+        for (name, stinger) in self.stingers.iter() {
+            midi_builder =
+                midi_builder.add_stinger(name.clone(), stinger.to_stinger(asset_loader)?);
         }
-        let source = midi_builder.build()?;
+        midi_builder.build()
+    }
+}
+
+impl NodeConfig for Midi {
+    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GraphNode, Error> {
+        let source = self.to_midi_node(asset_loader)?;
         let source: GraphNode = Box::new(source);
         Ok(source)
     }
@@ -67,6 +280,13 @@ impl NodeConfig for Midi {
             self.channels
                 .iter()
                 .map(|(_, config)| config.clone())
+                .chain(self.other_channels.iter().cloned())
+                .chain(self.stingers.values().map(StingerConfig::to_child_config))
+                .chain(
+                    self.programs
+                        .values()
+                        .flat_map(|programs| programs.values().cloned()),
+                )
                 .collect(),
         )
     }
@@ -77,6 +297,10 @@ impl NodeConfig for Midi {
                 path,
                 track_index: _,
             } => Some(path),
+            MidiDataSource::ModFile { path } => Some(path),
+            MidiDataSource::Bytes { .. }
+            | MidiDataSource::Mml { .. }
+            | MidiDataSource::Events { .. } => None,
         }
     }
 
@@ -90,7 +314,15 @@ pub struct MidiNodeBuilder {
     node_id: Option<u64>,
     midi_events: Vec<MidiEvent>,
     channel_sources: HashMap<usize, GraphNode>,
-    samples_per_tick: f64,
+    channel_routes: ChannelRoutes,
+    channel_programs: HashMap<usize, HashMap<u8, GraphNode>>,
+    program_selecting_channels: HashSet<usize>,
+    tempo_map: TempoMap,
+    pitch_bend_range: f32,
+    controller_bindings: Vec<ControllerBinding>,
+    note_transforms: HashMap<usize, NoteTransform>,
+    groove: Groove,
+    stingers: Stingers,
 }
 
 impl MidiNodeBuilder {
@@ -109,61 +341,255 @@ impl MidiNodeBuilder {
             );
         }
 
-        let samples_per_tick = util::get_samples_per_tick(&smf)?;
-        let midi_events = event::midi_events_from_midi(smf, track_index)?;
+        let tempo_map = TempoMap::from_smf(&smf)?;
+        let midi_events = event::midi_events_from_midi(&smf, track_index)?;
         Ok(Self {
             from_track_index: track_index,
             node_id,
             midi_events,
             channel_sources: HashMap::new(),
-            samples_per_tick,
+            channel_routes: ChannelRoutes::default(),
+            channel_programs: HashMap::new(),
+            program_selecting_channels: HashSet::new(),
+            tempo_map,
+            pitch_bend_range: defaults::pitch_bend_range(),
+            controller_bindings: defaults::controller_bindings(),
+            note_transforms: HashMap::new(),
+            groove: Groove::default(),
+            stingers: Stingers::default(),
         })
     }
 
+    /// Set up a builder for a track of a MIDI file decoded earlier, such as
+    /// one stored by an asset loader
+    pub fn from_prepared_file(
+        node_id: Option<u64>,
+        prepared_file: &PreparedMidiFile,
+        track_index: usize,
+    ) -> Result<Self, Error> {
+        let midi_events = prepared_file.track_events(track_index)?;
+        let contains_notes = midi_events
+            .iter()
+            .any(|event| matches!(event.message.data, Event::NoteOn { .. }));
+        if !contains_notes {
+            println!(
+                "WARNING: MIDI: Track {} does not contain any notes",
+                track_index
+            );
+        }
+        let mut builder =
+            Self::from_events(node_id, midi_events, prepared_file.tempo_map().clone());
+        builder.from_track_index = track_index;
+        Ok(builder)
+    }
+
+    /// Set up a builder for events prepared in code rather than loaded from a
+    /// MIDI file, such as from midi_events_from_sequence
+    pub fn from_events(
+        node_id: Option<u64>,
+        midi_events: Vec<MidiEvent>,
+        tempo_map: TempoMap,
+    ) -> Self {
+        Self::new_empty_from_prepared_data(
+            node_id,
+            midi_events,
+            tempo_map,
+            defaults::pitch_bend_range(),
+            defaults::controller_bindings(),
+        )
+    }
+
+    /// Set up a builder for a ProTracker module, with each tracker channel
+    /// playing its samples as programs. Channel sources added afterwards play
+    /// notes whose sample the channel has no program for.
+    pub fn from_mod(node_id: Option<u64>, bytes: &[u8]) -> Result<Self, Error> {
+        let module = TrackerModule::parse(bytes)?;
+        if DebugLogging::get_log_on_init() {
+            println!(
+                "MOD: \"{}\": {} channels, {} events",
+                module.title,
+                module.channel_count,
+                module.midi_events.len()
+            );
+        }
+        let mut builder = Self::from_events(
+            node_id,
+            module.midi_events.clone(),
+            module.tempo_map.clone(),
+        );
+        for channel in 0..module.channel_count {
+            for (program, source) in module.channel_programs(channel)?.into_iter() {
+                builder = builder.add_channel_program(channel, program, source);
+            }
+        }
+        Ok(builder)
+    }
+
     /// Set up a builder using ready-to-go properties, but without any channel sources assigned
     fn new_empty_from_prepared_data(
         node_id: Option<u64>,
         midi_events: Vec<MidiEvent>,
-        samples_per_tick: f64,
+        tempo_map: TempoMap,
+        pitch_bend_range: f32,
+        controller_bindings: Vec<ControllerBinding>,
     ) -> Self {
         Self {
             from_track_index: 69,
             node_id,
             midi_events,
             channel_sources: HashMap::new(),
-            samples_per_tick,
+            channel_routes: ChannelRoutes::default(),
+            channel_programs: HashMap::new(),
+            program_selecting_channels: HashSet::new(),
+            tempo_map,
+            pitch_bend_range,
+            controller_bindings,
+            note_transforms: HashMap::new(),
+            groove: Groove::default(),
+            stingers: Stingers::default(),
         }
     }
 
+    /// Play a MIDI channel (zero-based) with a source
     pub fn add_channel_source(mut self, channel: usize, source: GraphNode) -> Self {
         self.channel_sources.insert(channel, source);
+        self.channel_routes.add_route(channel, channel);
+        self
+    }
+
+    /// Play a MIDI channel (zero-based) with a source that chooses its own
+    /// instruments, which is sent the channel's ProgramChange messages
+    pub fn add_program_selecting_source(mut self, channel: usize, source: GraphNode) -> Self {
+        self.program_selecting_channels.insert(channel);
+        self.add_channel_source(channel, source)
+    }
+
+    /// Play a MIDI channel with the source of another channel, which receives
+    /// the events of both. Channels are zero-based.
+    pub fn share_channel_source(mut self, channel: usize, source_channel: usize) -> Self {
+        self.channel_routes.add_route(channel, source_channel);
+        self
+    }
+
+    /// Play every channel without a source of its own with this source
+    pub fn set_other_channels_source(mut self, source: GraphNode) -> Self {
+        self.channel_sources.insert(FALLBACK_DESTINATION, source);
+        self.channel_routes.set_fallback(Some(FALLBACK_DESTINATION));
+        self
+    }
+
+    /// Add an instrument that a ProgramChange message on the channel can switch to
+    pub fn add_channel_program(mut self, channel: usize, program: u8, source: GraphNode) -> Self {
+        self.channel_programs
+            .entry(channel)
+            .or_default()
+            .insert(program, source);
+        self.channel_routes.add_route(channel, channel);
+        self
+    }
+
+    /// Set the range, in semitones, of full pitch bend on every channel, until
+    /// the MIDI data changes it with an RPN 0 message
+    pub fn set_pitch_bend_range(mut self, semitones: f32) -> Self {
+        self.pitch_bend_range = semitones;
+        self
+    }
+
+    /// Set how controller (CC) messages map to events sent to the channel
+    /// sources, replacing the General MIDI defaults
+    pub fn set_controller_bindings(mut self, bindings: Vec<ControllerBinding>) -> Self {
+        self.controller_bindings = bindings;
+        self
+    }
+
+    /// Set how notes on a channel are transposed, remapped and have their
+    /// velocities reshaped before reaching the channel source
+    pub fn set_channel_transform(mut self, channel: usize, transform: NoteTransform) -> Self {
+        self.note_transforms.insert(channel, transform);
+        self
+    }
+
+    /// Set swing, quantisation and humanisation of note timing
+    pub fn set_groove(mut self, groove: Groove) -> Self {
+        self.groove = groove;
+        self
+    }
+
+    /// Add a phrase that a Stinger event can layer over the music
+    pub fn add_stinger(mut self, name: String, stinger: Stinger) -> Self {
+        self.stingers.add(name, stinger);
         self
     }
 
     pub fn build(self) -> Result<MidiNode, Error> {
-        MidiNode::new(
+        let midi_events =
+            RegisteredParameters::new(self.pitch_bend_range).decode_events(self.midi_events);
+        let mut node = MidiNode::new(
             self.from_track_index,
             self.node_id,
-            self.midi_events,
+            midi_events,
             self.channel_sources,
-            self.samples_per_tick,
-        )
+            self.tempo_map,
+            self.pitch_bend_range,
+            self.controller_bindings,
+        )?;
+        node.set_program_banks(self.channel_programs);
+        node.program_selecting_sources = self.program_selecting_channels;
+        if !self.channel_routes.is_empty() {
+            self.channel_routes
+                .warn_unrouted(node.routed_event_channels());
+        }
+        node.channel_routes = self.channel_routes;
+        node.note_transforms = self.note_transforms;
+        node.stingers = self.stingers;
+        node.set_groove(self.groove);
+        Ok(node)
     }
 }
 
 pub struct MidiNode {
     from_track_index: usize,
     cumulative_samples: u64,
+    /// Events as loaded, before the groove was applied
+    source_events: Vec<MidiEvent>,
     midi_events: Vec<MidiEvent>,
+    event_ticks: Vec<u64>,
+    /// Channel states through the events, restored by seeks
+    chase_states: ChaseStates,
+    groove: Groove,
     node_id: u64,
-    queued_ideal_seek: Option<u32>,
+    queued_ideal_seek: Option<Anchor>,
+    cue_variables: HashMap<String, f32>,
+    text_subscribers: TextSubscribers,
+    loop_counts: HashMap<usize, u32>,
+    random_state: u64,
+    stingers: Stingers,
+    /// Sources by destination key, which MIDI channels are routed to
     channel_sources: HashMap<usize, GraphNode>,
+    channel_routes: ChannelRoutes,
+    child_routing: Option<ChannelRouting>,
+    program_banks: HashMap<usize, ProgramBank>,
+    /// Sources, by destination key, that choose their own instruments from
+    /// ProgramChange messages
+    program_selecting_sources: HashSet<usize>,
+    channel_mix: ChannelMix,
+    note_transforms: HashMap<usize, NoteTransform>,
+    /// Channel, played note and note sent to the source, for each note that
+    /// is sounding
+    active_notes: Vec<(usize, u8, u8)>,
     is_playing: bool,
     has_finished: bool,
-    samples_per_tick: f64,
+    tempo_map: TempoMap,
+    pitch_bend_range: f32,
+    controller_mapper: ControllerMapper,
     next_event_index: usize,
-    event_samples_progress: isize,
+    /// Samples of the tempo map played since the last event reached, kept
+    /// fractional so that event timing does not drift; independent of the
+    /// time dilation
+    event_samples_progress: f64,
     time_dilation: f32,
+    /// Tempo set by a Tempo event, which the time dilation follows
+    tempo_override: Option<TempoOverride>,
 }
 
 impl MidiNode {
@@ -172,7 +598,9 @@ impl MidiNode {
         node_id: Option<u64>,
         midi_events: Vec<MidiEvent>,
         channel_sources: HashMap<usize, GraphNode>,
-        samples_per_tick: f64,
+        tempo_map: TempoMap,
+        pitch_bend_range: f32,
+        controller_bindings: Vec<ControllerBinding>,
     ) -> Result<Self, Error> {
         let mut sources: HashMap<usize, GraphNode> = HashMap::new();
 
@@ -182,83 +610,677 @@ impl MidiNode {
             }
         }
 
+        let bend_range_message = Message::broadcast(Event::PitchBendRange(pitch_bend_range));
+        for source in sources.values_mut() {
+            source.on_event(&bend_range_message);
+        }
+        let mut channel_routes = ChannelRoutes::default();
+        for channel in sources.keys() {
+            channel_routes.add_route(*channel, *channel);
+        }
+        let mut channel_mix = ChannelMix::new();
+        channel_mix.prepare_channels(sources.keys().copied());
+
+        let event_ticks = midi_events
+            .iter()
+            .scan(0u64, |tick, event| {
+                *tick += event.delta_ticks.max(0) as u64;
+                Some(*tick)
+            })
+            .collect();
+
+        let midi_events_empty = midi_events.is_empty();
         Ok(Self {
             from_track_index,
             cumulative_samples: 0,
+            source_events: midi_events.clone(),
+            chase_states: ChaseStates::new(&midi_events),
             midi_events,
+            event_ticks,
+            groove: Groove::default(),
             node_id: node_id.unwrap_or_else(<Self as Node>::new_node_id),
             queued_ideal_seek: None,
+            cue_variables: HashMap::new(),
+            text_subscribers: TextSubscribers::default(),
+            loop_counts: HashMap::new(),
+            random_state: initial_random_state(),
+            stingers: Stingers::default(),
             channel_sources: sources,
+            channel_routes,
+            child_routing: None,
+            program_banks: HashMap::new(),
+            program_selecting_sources: HashSet::new(),
+            channel_mix,
+            note_transforms: HashMap::new(),
+            active_notes: vec![],
             is_playing: true,
-            has_finished: false,
-            samples_per_tick,
+            has_finished: midi_events_empty,
+            tempo_map,
+            pitch_bend_range,
+            controller_mapper: ControllerMapper::new(controller_bindings)?,
             next_event_index: 0,
-            event_samples_progress: 0,
+            event_samples_progress: 0.0,
             time_dilation: 1.0,
+            tempo_override: None,
         })
     }
 
     pub fn duplicate_without_sources(&self) -> MidiNodeBuilder {
-        MidiNodeBuilder::new_empty_from_prepared_data(
+        let mut builder = MidiNodeBuilder::new_empty_from_prepared_data(
             Some(self.node_id),
-            self.midi_events.clone(),
-            self.samples_per_tick,
-        )
+            self.source_events.clone(),
+            self.tempo_map.clone(),
+            self.pitch_bend_range,
+            self.controller_mapper.bindings().to_vec(),
+        );
+        builder.note_transforms = self.note_transforms.clone();
+        builder.groove = self.groove.clone();
+        builder
     }
 
-    fn seek_to_anchor(&mut self, anchor: u32) {
+    /// Whether playback has passed the last event
+    pub fn has_finished(&self) -> bool {
+        self.has_finished
+    }
+
+    /// Play from the beginning, clearing queued seeks and loop counts
+    pub fn restart(&mut self) {
         self.queued_ideal_seek = None;
-        if let Some(index) = self.midi_events.iter().position(|c| match c.message {
+        self.loop_counts.clear();
+        self.seek_to_event_index(0, 0);
+        self.is_playing = true;
+    }
+
+    /// Add a phrase that can be layered over the music with play_stinger
+    pub fn add_stinger(&mut self, name: String, stinger: Stinger) {
+        self.stingers.add(name, stinger);
+    }
+
+    /// Start a stinger on the next beat, bar or anchor reached by playback,
+    /// mixed over the music until it finishes. It starts straight away if
+    /// playback is paused or has finished, or the point cannot be found.
+    pub fn play_stinger(&mut self, name: &str, alignment: &StingerAlignment) {
+        let start = match self.is_playing && !self.has_finished {
+            true => self.stinger_start(alignment),
+            false => StingerStart::Now,
+        };
+        self.stingers.schedule(name, start);
+    }
+
+    fn stinger_start(&self, alignment: &StingerAlignment) -> StingerStart {
+        let current_tick = self.playback_tick();
+        let start = match alignment {
+            StingerAlignment::Immediate => return StingerStart::Now,
+            StingerAlignment::Beat => self
+                .tempo_map
+                .next_beat_tick(current_tick)
+                .map(StingerStart::Beat),
+            StingerAlignment::Bar => self
+                .tempo_map
+                .next_bar_tick(current_tick)
+                .map(StingerStart::Bar),
+            // Playback may reach the anchor behind it by a seek
+            StingerAlignment::Anchor(anchor) => self
+                .midi_events
+                .iter()
+                .any(|event| {
+                    matches!(
+                        &event.message.data,
+                        Event::CueData(CueData::TargetMarker(a)) if a == anchor
+                    )
+                })
+                .then(|| StingerStart::Anchor(anchor.clone())),
+        };
+        start.unwrap_or_else(|| {
+            println!(
+                "WARNING: MIDI: Cannot find {:?} to align a stinger to; starting it now",
+                alignment
+            );
+            StingerStart::Now
+        })
+    }
+
+    /// Start stingers waiting for a tick that playback reaches within the
+    /// given output samples, or on the event they end on
+    fn start_stingers_reached(&mut self, samples: usize, ends_on_event: bool) {
+        let mut stingers = std::mem::take(&mut self.stingers);
+        stingers.start_ticks_reached(samples, ends_on_event, |tick| self.samples_until_tick(tick));
+        self.stingers = stingers;
+    }
+
+    /// Tempo that playback is running at, in beats per minute, or None if
+    /// the timing has no musical beats
+    pub fn beats_per_minute(&self) -> Option<f64> {
+        if let Some(tempo_override) = &self.tempo_override {
+            return Some(tempo_override.beats_per_minute());
+        }
+        let tick = self.playback_tick().max(0.0) as u64;
+        let file_beats_per_minute = self.tempo_map.beats_per_minute_at(tick)?;
+        Some(file_beats_per_minute * self.time_dilation as f64)
+    }
+
+    /// Set or ramp the tempo in beats per minute, in place of the tempo map's
+    /// own tempo. A TimeDilation event returns to the tempo map.
+    pub fn set_beats_per_minute(&mut self, beats_per_minute: f32, seconds: f32) {
+        let Some(current) = self.beats_per_minute() else {
+            println!("WARNING: MIDI: Cannot set a tempo without metrical timing");
+            return;
+        };
+        self.tempo_override = Some(TempoOverride::new(
+            current,
+            beats_per_minute.max(0.0) as f64,
+            seconds,
+        ));
+        self.follow_tempo_override();
+    }
+
+    /// Set the time dilation that gives the overriding tempo at the playback
+    /// position, against the tempo map's tempo there
+    fn follow_tempo_override(&mut self) {
+        let Some(tempo_override) = &self.tempo_override else {
+            return;
+        };
+        let tick = self.playback_tick().max(0.0) as u64;
+        if let Some(file_beats_per_minute) = self.tempo_map.beats_per_minute_at(tick) {
+            self.time_dilation = (tempo_override.beats_per_minute() / file_beats_per_minute) as f32;
+        }
+    }
+
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    /// Samples of the tempo map that playback has reached
+    fn playback_samples(&self) -> f64 {
+        self.tempo_map.samples_at_tick(self.current_tick()) + self.event_samples_progress
+    }
+
+    /// Tick position (possibly fractional) that playback has reached
+    pub fn playback_tick(&self) -> f64 {
+        self.tempo_map.tick_at_samples(self.playback_samples())
+    }
+
+    /// Tick of the next event yet to be reached whose data matches
+    pub fn next_event_tick(&self, matches: impl Fn(&Event) -> bool) -> Option<f64> {
+        self.midi_events[self.next_event_index.min(self.midi_events.len())..]
+            .iter()
+            .position(|event| matches(&event.message.data))
+            .map(|offset| self.event_ticks[self.next_event_index + offset] as f64)
+    }
+
+    /// Tick of the last event
+    pub fn end_tick(&self) -> f64 {
+        self.event_ticks.last().copied().unwrap_or(0) as f64
+    }
+
+    /// Output samples until playback reaches a tick at the current time
+    /// dilation, assuming no seeks on the way
+    pub fn samples_until_tick(&self, tick: f64) -> usize {
+        let target_samples = self.tempo_map.samples_at_fractional_tick(tick);
+        self.output_samples_for(target_samples - self.playback_samples())
+    }
+
+    /// Whole output samples, at the current time dilation, until the given
+    /// number of tempo map samples have been played; an event falls on the
+    /// first output sample at or past its time
+    fn output_samples_for(&self, tempo_map_samples: f64) -> usize {
+        (tempo_map_samples / self.time_dilation as f64 - SAMPLE_TOLERANCE)
+            .ceil()
+            .max(0.0) as usize
+    }
+
+    /// Seek to the event after an anchor's target marker
+    pub fn seek_to_anchor(&mut self, anchor: &Anchor) {
+        self.queued_ideal_seek = None;
+        match self.midi_events.iter().position(|c| match &c.message {
             Message {
                 data: Event::CueData(CueData::TargetMarker(a)),
                 ..
             } => a == anchor,
             _ => false,
         }) {
-            self.event_samples_progress = 0;
-            self.next_event_index = index + 1;
-            let broadcast_cutoff = Message::broadcast(Event::NoteOff { note: 0, vel: 1.0 });
-            for (_, source) in self.channel_sources.iter_mut() {
-                source.on_event(&broadcast_cutoff);
+            Some(index) => {
+                self.seek_to_event_index(index + 1, self.event_ticks[index]);
+                self.stingers.anchor_reached(anchor);
             }
+            None => println!("WARNING: MIDI: No anchor #{} to seek to", anchor),
         };
     }
 
+    /// Next value from a xorshift generator, for picking random cue targets
+    fn next_random(&mut self) -> u64 {
+        groove::xorshift(&mut self.random_state)
+    }
+
+    /// Apply a groove to the loaded events. Playback continues from the same
+    /// place, with events that the groove moved before it counted as reached:
+    /// sounding notes are released, and notes held at that place are chased
+    /// as they would be by a seek. The grooved events are built here, so this
+    /// allocates and is best done between sections rather than every buffer.
+    pub fn set_groove(&mut self, groove: Groove) {
+        let ticks_per_beat = match self.tempo_map.ticks_per_beat() {
+            Some(ticks_per_beat) => ticks_per_beat,
+            None if groove.is_neutral() => 1,
+            None => {
+                println!("WARNING: MIDI: Cannot apply a groove without metrical timing");
+                return;
+            }
+        };
+        let (midi_events, event_ticks) = match groove.is_neutral() {
+            true => {
+                let event_ticks = self
+                    .source_events
+                    .iter()
+                    .scan(0u64, |tick, event| {
+                        *tick += event.delta_ticks.max(0) as u64;
+                        Some(*tick)
+                    })
+                    .collect();
+                (self.source_events.clone(), event_ticks)
+            }
+            false => groove.apply(&self.source_events, ticks_per_beat),
+        };
+        let has_started = self.next_event_index > 0 || self.event_samples_progress > 0.0;
+        let playback_samples = self.playback_samples();
+        let playback_tick = self.tempo_map.tick_at_samples(playback_samples);
+        self.chase_states = ChaseStates::new(&midi_events);
+        self.midi_events = midi_events;
+        self.event_ticks = event_ticks;
+        self.groove = groove;
+        if !has_started {
+            self.next_event_index = 0;
+            self.has_finished = self.midi_events.is_empty();
+            return;
+        }
+        let index = self
+            .event_ticks
+            .partition_point(|event_tick| (*event_tick as f64) < playback_tick);
+        let reached_tick = match index {
+            0 => 0,
+            index => self.event_ticks[index - 1],
+        };
+        self.seek_to_event_index(index, reached_tick);
+        self.event_samples_progress =
+            (playback_samples - self.tempo_map.samples_at_tick(reached_tick)).max(0.0);
+    }
+
+    /// Seek to a tick, beat or time. Sounding notes are released, then the
+    /// notes, controllers, pitch bend and programs in effect at the target are
+    /// restored.
+    pub fn seek(&mut self, position: &SeekPosition) {
+        let Some(tick) = self.tempo_map.tick_at_position(position) else {
+            println!(
+                "WARNING: MIDI: Cannot seek to {:?} without metrical timing",
+                position
+            );
+            return;
+        };
+        let index = self
+            .event_ticks
+            .partition_point(|event_tick| *event_tick < tick);
+        self.seek_to_event_index(index, tick);
+    }
+
+    /// Seek to the tick that another node's playback has reached, for
+    /// sequences written on the same timeline. Events up to and including the
+    /// tick count as reached, and the exact position within the tick is kept.
+    /// The leader's queued seek, loop counts and random state are taken too,
+    /// so that both take the same branches at the cues ahead, along with its
+    /// tempo and channel mix, including any ramps and fades under way.
+    pub fn align_to(&mut self, leader: &MidiNode) {
+        let tick = leader.playback_tick();
+        let index = self
+            .event_ticks
+            .partition_point(|event_tick| *event_tick as f64 <= tick);
+        let reached_tick = match index {
+            0 => 0,
+            index => self.event_ticks[index - 1],
+        };
+        self.seek_to_event_index(index, reached_tick);
+        let progress_samples = self.tempo_map.samples_at_fractional_tick(tick)
+            - self.tempo_map.samples_at_tick(reached_tick);
+        self.event_samples_progress = progress_samples.max(0.0);
+        self.is_playing = leader.is_playing;
+        self.queued_ideal_seek = leader.queued_ideal_seek.clone();
+        self.random_state = leader.random_state;
+        self.tempo_override.clone_from(&leader.tempo_override);
+        self.time_dilation = leader.time_dilation;
+        self.channel_mix.copy_from(&leader.channel_mix);
+        self.loop_counts.clear();
+        for (leader_index, count) in leader.loop_counts.iter() {
+            if let Some(index) = self.matching_cue_index(leader, *leader_index) {
+                self.loop_counts.insert(index, *count);
+            }
+        }
+    }
+
+    /// Index of the cue event matching one of another node's cue events,
+    /// being the same cue at the same tick
+    fn matching_cue_index(&self, other: &MidiNode, other_index: usize) -> Option<usize> {
+        let tick = *other.event_ticks.get(other_index)?;
+        let Event::CueData(cue) = &other.midi_events.get(other_index)?.message.data else {
+            return None;
+        };
+        let start = self
+            .event_ticks
+            .partition_point(|event_tick| *event_tick < tick);
+        self.event_ticks[start..]
+            .iter()
+            .zip(self.midi_events[start..].iter())
+            .take_while(|(event_tick, _)| **event_tick == tick)
+            .position(|(_, event)| {
+                matches!(&event.message.data, Event::CueData(other_cue) if other_cue == cue)
+            })
+            .map(|offset| start + offset)
+    }
+
+    /// Move playback so that the given event is next, at the given tick
+    fn seek_to_event_index(&mut self, index: usize, tick: u64) {
+        self.release_active_notes();
+        let mut chase_events = self
+            .chase_states
+            .take_events_before(&self.midi_events, index);
+        self.next_event_index = index.min(self.midi_events.len());
+        self.has_finished = self.next_event_index >= self.midi_events.len();
+        let progress_samples = self.tempo_map.samples_at_tick(tick)
+            - self.tempo_map.samples_at_tick(self.current_tick());
+        self.event_samples_progress = progress_samples.max(0.0);
+        for event in chase_events.drain(..) {
+            self.on_internal_event_reached(event);
+        }
+        self.chase_states.restore_buffer(chase_events);
+        self.stingers.realign(
+            self.tempo_map.next_beat_tick(tick as f64),
+            self.tempo_map.next_bar_tick(tick as f64),
+        );
+    }
+
+    /// Send note-offs for every note played and not yet released, lifting any
+    /// pedals that would hold them
+    fn release_active_notes(&mut self) {
+        for (channel, _, note) in self.active_notes.drain(..) {
+            let Some(destination) = self.channel_routes.route(channel) else {
+                continue;
+            };
+            let note_off = Event::NoteOff { note, vel: 1.0 };
+            if let Some(bank) = self.program_banks.get_mut(&destination)
+                && bank.on_channel_event(&note_off)
+            {
+                continue;
+            }
+            if let Some(source) = self.channel_sources.get_mut(&destination) {
+                source.on_event(&Message::broadcast(note_off));
+            }
+        }
+        for controller in [controller::CC_SUSTAIN, controller::CC_SOSTENUTO] {
+            let pedal_up = Event::Controller {
+                controller,
+                value: 0,
+            };
+            for source in self.channel_sources.values_mut() {
+                source.on_event(&Message::broadcast(pedal_up.clone()));
+            }
+            for bank in self.program_banks.values_mut() {
+                bank.on_channel_event(&pedal_up);
+            }
+        }
+    }
+
+    /// Tick position of the most recently reached event
+    fn current_tick(&self) -> u64 {
+        match self.next_event_index {
+            0 => 0,
+            index => self.event_ticks[index - 1],
+        }
+    }
+
     fn on_internal_event_reached(&mut self, event: MidiEvent) {
         if let Event::CueData(cue) = &event.message.data {
-            self.process_cue_event(cue);
+            self.process_cue_event(cue, Some(self.next_event_index - 1));
             return;
         }
-        let Some(source) = self.channel_sources.get_mut(&event.channel) else {
+        if let Event::ProgramChange(program) = event.message.data {
+            self.change_program(event.channel, program);
+            return;
+        }
+        if let Event::Text { kind, text } = event.message.data {
+            let tick = self.current_tick();
+            self.text_subscribers.send(MidiText {
+                node_id: self.node_id,
+                kind,
+                text,
+                tick,
+            });
+            return;
+        }
+        let Some(destination) = self.channel_routes.route(event.channel) else {
             return;
         };
+        if !self.channel_sources.contains_key(&destination)
+            && !self.program_banks.contains_key(&destination)
+        {
+            return;
+        }
         if DebugLogging::get_log_on_midi_event() {
             println!(
                 "MIDI event: track {} after {} samples: {:?}",
                 self.from_track_index, self.cumulative_samples, &event.message,
             );
         }
-        source.on_event(&event.message);
+        let Some(data) = self.transform_note_event(event.channel, &event.message.data) else {
+            return;
+        };
+        let message = Message {
+            data,
+            ..event.message
+        };
+        if let Some(bank) = self.program_banks.get_mut(&destination)
+            && bank.on_channel_event(&message.data)
+        {
+            return;
+        }
+        let Some(source) = self.channel_sources.get_mut(&destination) else {
+            return;
+        };
+        self.controller_mapper
+            .apply_event(event.channel, &message.data, |message| {
+                source.on_event(message)
+            });
+        source.on_event(&message);
+    }
+
+    /// Apply the channel's note transform to a note event, tracking sounding
+    /// notes so that releases reach the note that was played even if the
+    /// transform has changed since. None if the note should not be played.
+    fn transform_note_event(&mut self, channel: usize, data: &Event) -> Option<Event> {
+        let transform = self.note_transforms.get(&channel);
+        let sounding_note = |active_notes: &[(usize, u8, u8)], note: u8| {
+            active_notes.iter().position(|(active_channel, played, _)| {
+                *active_channel == channel && *played == note
+            })
+        };
+        match *data {
+            Event::NoteOn { note, vel } if vel > 0.0 => {
+                let (output_note, vel) = match transform {
+                    Some(transform) => (transform.map_note(note)?, transform.velocity.apply(vel)),
+                    None => (note, vel),
+                };
+                self.active_notes.push((channel, note, output_note));
+                Some(Event::NoteOn {
+                    note: output_note,
+                    vel,
+                })
+            }
+            Event::NoteOn { note, vel } | Event::NoteOff { note, vel } => {
+                // Releases of notes not being tracked pass through unchanged
+                let Some(index) = sounding_note(&self.active_notes, note) else {
+                    return Some(data.clone());
+                };
+                let (_, _, output_note) = self.active_notes.remove(index);
+                Some(match data {
+                    Event::NoteOn { .. } => Event::NoteOn {
+                        note: output_note,
+                        vel,
+                    },
+                    _ => Event::NoteOff {
+                        note: output_note,
+                        vel,
+                    },
+                })
+            }
+            Event::KeyPressure { note, pressure } => {
+                let index = sounding_note(&self.active_notes, note)?;
+                Some(Event::KeyPressure {
+                    note: self.active_notes[index].2,
+                    pressure,
+                })
+            }
+            _ => Some(data.clone()),
+        }
+    }
+
+    fn set_program_banks(&mut self, channel_programs: HashMap<usize, HashMap<u8, GraphNode>>) {
+        self.program_banks = channel_programs
+            .into_iter()
+            .map(|(channel, programs)| (channel, ProgramBank::new(programs)))
+            .collect();
+        self.channel_mix
+            .prepare_channels(self.program_banks.keys().copied());
+        let bend_range_message = Message::broadcast(Event::PitchBendRange(self.pitch_bend_range));
+        for bank in self.program_banks.values_mut() {
+            for source in bank.idle_sources_mut() {
+                source.on_event(&bend_range_message);
+            }
+        }
+    }
+
+    fn change_program(&mut self, channel: usize, program: u8) {
+        let Some(destination) = self.channel_routes.route(channel) else {
+            return;
+        };
+        if self.program_selecting_sources.contains(&destination) {
+            if let Some(source) = self.channel_sources.get_mut(&destination) {
+                source.on_event(&Message::broadcast(Event::ProgramChange(program)));
+            }
+            return;
+        }
+        let Some(bank) = self.program_banks.get_mut(&destination) else {
+            return;
+        };
+        let active = self.channel_sources.remove(&destination);
+        let previous_program = bank.current_program();
+        let Some(mut source) = bank.select(program, active) else {
+            return;
+        };
+        if bank.current_program() != previous_program {
+            for data in bank.channel_state_events() {
+                self.controller_mapper
+                    .apply_event(channel, &data, |message| source.on_event(message));
+                source.on_event(&Message::broadcast(data));
+            }
+        }
+        self.channel_sources.insert(destination, source);
+    }
+
+    /// Channel of each event that needs a destination to be heard,
+    /// for warning about channels left without one
+    fn routed_event_channels(&self) -> impl Iterator<Item = usize> + '_ {
+        self.midi_events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event.message.data,
+                    Event::NoteOn { .. }
+                        | Event::ProgramChange(_)
+                        | Event::Controller { .. }
+                        | Event::PitchBend(_)
+                        | Event::KeyPressure { .. }
+                        | Event::ChannelPressure(_)
+                )
+            })
+            .map(|event| event.channel)
+    }
+
+    /// Destination whose mix settings apply to a channel; a channel without a
+    /// route keeps settings of its own, for when it gets one
+    fn mix_destination(&self, channel: usize) -> usize {
+        self.channel_routes.route(channel).unwrap_or(channel)
+    }
+
+    /// Set which channels each child given to replace_children plays, in
+    /// place of each child playing the zero-based channel of its index
+    pub fn set_child_routing(&mut self, routing: ChannelRouting) {
+        self.child_routing = Some(routing);
     }
 
-    fn process_cue_event(&mut self, cue: &CueData) {
+    /// Render each channel's source, along with instruments still releasing
+    /// after a program change, through the channel's mix settings
+    fn fill_sources(&mut self, buffer: &mut [f32]) {
+        for (channel, source) in self.channel_sources.iter_mut() {
+            let mut bank = self.program_banks.get_mut(channel);
+            self.channel_mix
+                .fill_channel(*channel, buffer, |channel_buffer| {
+                    source.fill_buffer(channel_buffer);
+                    if let Some(bank) = bank.as_mut() {
+                        bank.fill_releasing(channel_buffer);
+                    }
+                });
+        }
+        for (channel, bank) in self.program_banks.iter_mut() {
+            if self.channel_sources.contains_key(channel) {
+                continue;
+            }
+            self.channel_mix
+                .fill_channel(*channel, buffer, |channel_buffer| {
+                    bank.fill_releasing(channel_buffer)
+                });
+        }
+    }
+
+    /// Act on a cue. Loop counts are kept per cue event, so cues arriving
+    /// from outside the sequence (with no event index) seek unconditionally.
+    fn process_cue_event(&mut self, cue: &CueData, event_index: Option<usize>) {
         match cue {
-            CueData::TargetMarker(_) => { /* Marker, no action */ }
+            CueData::TargetMarker(anchor) => self.stingers.anchor_reached(anchor),
             CueData::GoodPointToSeekFrom => {
-                if let Some(anchor) = self.queued_ideal_seek {
-                    self.seek_to_anchor(anchor);
-                    return;
+                if let Some(anchor) = self.queued_ideal_seek.clone() {
+                    self.seek_to_anchor(&anchor);
                 }
             }
             CueData::SeekNowToTarget(anchor) => {
-                self.seek_to_anchor(*anchor);
+                self.seek_to_anchor(anchor);
             }
             CueData::SeekWhenIdeal(anchor) => {
-                self.queued_ideal_seek = Some(*anchor);
+                self.queued_ideal_seek = Some(anchor.clone());
             }
             CueData::ClearQueuedSeek => {
                 self.queued_ideal_seek = None;
             }
+            CueData::LoopToTarget { target, times } => {
+                let Some(index) = event_index else {
+                    self.seek_to_anchor(target);
+                    return;
+                };
+                let count = self.loop_counts.entry(index).or_insert(0);
+                if *count < *times {
+                    *count += 1;
+                    self.seek_to_anchor(target);
+                } else {
+                    self.loop_counts.remove(&index);
+                }
+            }
+            CueData::SeekToRandomTarget(targets) => {
+                if targets.is_empty() {
+                    return;
+                }
+                let choice = (self.next_random() % targets.len() as u64) as usize;
+                self.seek_to_anchor(&targets[choice]);
+            }
+            CueData::Conditional { condition, cue } => {
+                if condition.is_met(&self.cue_variables) {
+                    self.process_cue_event(cue, event_index);
+                }
+            }
         }
     }
 
@@ -270,47 +1292,72 @@ impl MidiNode {
         #[cfg(debug_assertions)]
         assert_eq!(consts::CHANNEL_COUNT, 2);
 
+        if self.next_event_index >= self.midi_events.len() {
+            self.has_finished = true;
+            return;
+        }
+        let frame_count = buffer.len() / consts::CHANNEL_COUNT;
         let mut output_buffer: &mut [f32] = buffer;
         loop {
+            self.follow_tempo_override();
             let reached_note_event = {
-                let next_channel_event = &self.midi_events[self.next_event_index];
-                let delta_samples = (next_channel_event.delta_ticks as f64
-                    * (self.samples_per_tick / self.time_dilation as f64))
-                    as isize;
-                let samples_until_event = delta_samples - self.event_samples_progress;
+                let next_channel_event = self.midi_events[self.next_event_index].clone();
+                let next_event_tick = self.event_ticks[self.next_event_index];
+                let delta_samples = self.tempo_map.samples_at_tick(next_event_tick)
+                    - self.tempo_map.samples_at_tick(self.current_tick());
+                let samples_until_event =
+                    self.output_samples_for(delta_samples - self.event_samples_progress);
                 let samples_available_per_channel = output_buffer.len() / consts::CHANNEL_COUNT;
+                let time_dilation = self.time_dilation as f64;
+                self.stingers
+                    .set_frame(frame_count - samples_available_per_channel);
+                self.start_stingers_reached(
+                    samples_until_event.min(samples_available_per_channel),
+                    samples_until_event <= samples_available_per_channel,
+                );
 
                 {
-                    if samples_until_event > samples_available_per_channel as isize {
+                    if samples_until_event > samples_available_per_channel {
                         self.cumulative_samples += samples_available_per_channel as u64;
-                        for (_, source) in self.channel_sources.iter_mut() {
-                            source.fill_buffer(output_buffer);
-                        }
-                        self.event_samples_progress += samples_available_per_channel as isize;
+                        self.fill_sources(output_buffer);
+                        self.event_samples_progress +=
+                            samples_available_per_channel as f64 * time_dilation;
                         return;
                     }
 
-                    let buffer_samples_to_fill =
-                        samples_until_event as usize * consts::CHANNEL_COUNT;
+                    let buffer_samples_to_fill = samples_until_event * consts::CHANNEL_COUNT;
                     self.cumulative_samples += samples_until_event as u64;
-                    for (_, source) in self.channel_sources.iter_mut() {
-                        source.fill_buffer(&mut output_buffer[0..buffer_samples_to_fill]);
-                    }
+                    self.fill_sources(&mut output_buffer[0..buffer_samples_to_fill]);
                 }
 
-                self.event_samples_progress = 0;
+                // Keep the part of a sample played past the event, so that
+                // rounding to whole samples never accumulates
+                self.event_samples_progress = (self.event_samples_progress
+                    + samples_until_event as f64 * time_dilation
+                    - delta_samples)
+                    .max(0.0);
+                let remaining_samples_per_channel =
+                    samples_available_per_channel - samples_until_event;
+                self.stingers
+                    .set_frame(frame_count - remaining_samples_per_channel);
                 self.next_event_index += 1;
                 if self.next_event_index >= self.midi_events.len() {
                     self.has_finished = true;
+                    self.on_internal_event_reached(next_channel_event);
                     return;
                 }
 
-                output_buffer = &mut buffer[((samples_available_per_channel
-                    - samples_until_event as usize)
-                    * consts::CHANNEL_COUNT)..];
+                let buffer_length = buffer.len();
+                output_buffer = &mut buffer
+                    [(buffer_length - remaining_samples_per_channel * consts::CHANNEL_COUNT)..];
                 next_channel_event
             };
-            self.on_internal_event_reached(reached_note_event.clone());
+            self.on_internal_event_reached(reached_note_event);
+            // A cue may have sought to the end of the sequence
+            if self.has_finished || self.next_event_index >= self.midi_events.len() {
+                self.has_finished = true;
+                return;
+            }
         }
     }
 }
@@ -325,16 +1372,23 @@ impl Node for MidiNode {
     }
 
     fn duplicate(&self) -> Result<GraphNode, Error> {
-        if !self.channel_sources.is_empty() {
+        if !self.channel_sources.is_empty()
+            || !self.program_banks.is_empty()
+            || !self.stingers.is_empty()
+        {
             return Err(Error::User("MidiSource cannot be duplicated".to_owned()));
         }
-        let source = Self::new(
+        let mut source = Self::new(
             self.from_track_index,
             Some(self.node_id),
-            self.midi_events.clone(),
+            self.source_events.clone(),
             HashMap::new(),
-            self.samples_per_tick,
+            self.tempo_map.clone(),
+            self.pitch_bend_range,
+            self.controller_mapper.bindings().to_vec(),
         )?;
+        source.note_transforms = self.note_transforms.clone();
+        source.set_groove(self.groove.clone());
         Ok(Box::new(source))
     }
 
@@ -358,6 +1412,7 @@ impl Node for MidiNode {
                         self.cumulative_samples = state.cumulative_samples;
                         self.next_event_index = state.next_event_index;
                         self.event_samples_progress = state.event_samples_progress;
+                        self.channel_mix.restore_settings(&state.channel_mix);
                     }
                     Err(e) => {
                         println!("JSON error restoring MIDI state: {:?}", e);
@@ -366,7 +1421,70 @@ impl Node for MidiNode {
                 true
             }
             Event::CueData(cue) => {
-                self.process_cue_event(cue);
+                self.process_cue_event(cue, None);
+                true
+            }
+            Event::SubscribeText(sender) => {
+                self.text_subscribers.add(sender.clone());
+                true
+            }
+            Event::CueVariable { name, value } => {
+                self.cue_variables.insert(name.clone(), *value);
+                true
+            }
+            Event::Stinger { name, alignment } => {
+                self.play_stinger(name, alignment);
+                true
+            }
+            Event::Groove(groove) => {
+                self.set_groove(groove.clone());
+                true
+            }
+            Event::Transpose { channel, semitones } => {
+                self.note_transforms.entry(*channel).or_default().transpose = *semitones;
+                true
+            }
+            Event::VelocityCurve { channel, curve } => {
+                self.note_transforms.entry(*channel).or_default().velocity = *curve;
+                true
+            }
+            Event::NoteRemap { channel, from, to } => {
+                let note_map = &mut self.note_transforms.entry(*channel).or_default().note_map;
+                match to {
+                    Some(to) => note_map.insert(*from, *to),
+                    None => note_map.remove(from),
+                };
+                true
+            }
+            Event::ChannelVolume {
+                channel,
+                volume,
+                seconds,
+            } => {
+                let destination = self.mix_destination(*channel);
+                self.channel_mix.set_volume(destination, *volume, *seconds);
+                true
+            }
+            Event::ChannelMute {
+                channel,
+                muted,
+                seconds,
+            } => {
+                let destination = self.mix_destination(*channel);
+                self.channel_mix.set_muted(destination, *muted, *seconds);
+                true
+            }
+            Event::ChannelSolo {
+                channel,
+                soloed,
+                seconds,
+            } => {
+                let destination = self.mix_destination(*channel);
+                self.channel_mix.set_soloed(destination, *soloed, *seconds);
+                true
+            }
+            Event::Seek(position) => {
+                self.seek(position);
                 true
             }
             Event::MidiPlayback(playback) => {
@@ -377,9 +1495,14 @@ impl Node for MidiNode {
                 true
             }
             Event::TimeDilation(value) => {
+                self.tempo_override = None;
                 self.time_dilation = *value;
                 true
             }
+            Event::Tempo { bpm, seconds } => {
+                self.set_beats_per_minute(*bpm, *seconds);
+                true
+            }
             _ => false,
         }
     }
@@ -388,13 +1511,24 @@ impl Node for MidiNode {
         for (_, source) in self.channel_sources.iter_mut() {
             source.on_event(event);
         }
+        for (_, bank) in self.program_banks.iter_mut() {
+            for source in bank.idle_sources_mut() {
+                source.on_event(event);
+            }
+        }
     }
 
     fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        if self.is_playing && !self.has_finished {
+            self.fill_all_channels(buffer);
+            if let Some(tempo_override) = &mut self.tempo_override {
+                tempo_override.advance(buffer.len() / consts::CHANNEL_COUNT);
+            }
+        }
         if !self.is_playing || self.has_finished {
-            return;
+            self.stingers.start_waiting();
         }
-        self.fill_all_channels(buffer);
+        self.stingers.fill_buffer(buffer);
     }
 
     fn replace_children(&mut self, children: &[GraphNode]) -> Result<(), Error> {
@@ -403,17 +1537,38 @@ impl Node for MidiNode {
                 "MidiSource does not support replacing its children".to_owned(),
             ));
         }
-        println!(
-            "MIDI Graph: Assigning channel sources to MIDI source; assuming sequential channel numbers starting at 1."
-        );
-        println!(
-            "This is a current limitation. Please check your source file channel numbers if needed."
-        );
+        let channel_routes = match &self.child_routing {
+            Some(routing) => {
+                if routing.destinations.len() != children.len() {
+                    return Err(Error::User(format!(
+                        "ERROR: MIDI: Channel routing has {} destinations for {} children",
+                        routing.destinations.len(),
+                        children.len()
+                    )));
+                }
+                ChannelRoutes::from_routing(routing)?
+            }
+            None => {
+                let mut channel_routes = ChannelRoutes::default();
+                for index in 0..children.len() {
+                    channel_routes.add_route(index, index);
+                }
+                channel_routes
+            }
+        };
         self.channel_sources = children
             .iter()
             .enumerate()
-            .map(|(index, source)| source.duplicate().map(|copy| (index + 1, copy)))
+            .map(|(index, source)| source.duplicate().map(|copy| (index, copy)))
             .collect::<Result<HashMap<usize, GraphNode>, Error>>()?;
+        self.channel_mix
+            .prepare_channels(self.channel_sources.keys().copied());
+        channel_routes.warn_unrouted(self.routed_event_channels());
+        self.channel_routes = channel_routes;
+        let bend_range_message = Message::broadcast(Event::PitchBendRange(self.pitch_bend_range));
+        for source in self.channel_sources.values_mut() {
+            source.on_event(&bend_range_message);
+        }
         Ok(())
     }
 
@@ -429,6 +1584,8 @@ impl Node for MidiNode {
                     cumulative_samples: self.cumulative_samples,
                     next_event_index: self.next_event_index,
                     event_samples_progress: self.event_samples_progress,
+                    channel_mix: self.channel_mix.settings(),
+                    beats_per_minute: self.beats_per_minute(),
                 })
                 .map_err(|e| Error::Json(e));
                 Some(result)
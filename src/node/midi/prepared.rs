@@ -0,0 +1,141 @@
+use crate::{
+    Error, Event, Message,
+    midi::{CueData, MidiEvent, MidiTextKind, TempoMap},
+    node::midi::event,
+};
+use midly::Smf;
+use serde::{Deserialize, Serialize};
+
+/// Event data that MIDI files are decoded into, in a form that can be stored
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum PreparedEventData {
+    NoteOn { note: u8, vel: f32 },
+    NoteOff { note: u8, vel: f32 },
+    PitchBend(f32),
+    PitchBendRange(f32),
+    KeyPressure { note: u8, pressure: f32 },
+    ChannelPressure(f32),
+    ProgramChange(u8),
+    Controller { controller: u8, value: u8 },
+    Text { kind: MidiTextKind, text: String },
+    CueData(CueData),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct PreparedMidiEvent {
+    delta_ticks: isize,
+    channel: usize,
+    data: PreparedEventData,
+}
+
+impl PreparedMidiEvent {
+    fn from_midi_event(midi_event: MidiEvent) -> Result<Self, Error> {
+        let data = match midi_event.message.data {
+            Event::NoteOn { note, vel } => PreparedEventData::NoteOn { note, vel },
+            Event::NoteOff { note, vel } => PreparedEventData::NoteOff { note, vel },
+            Event::PitchBend(bend) => PreparedEventData::PitchBend(bend),
+            Event::PitchBendRange(range) => PreparedEventData::PitchBendRange(range),
+            Event::KeyPressure { note, pressure } => {
+                PreparedEventData::KeyPressure { note, pressure }
+            }
+            Event::ChannelPressure(pressure) => PreparedEventData::ChannelPressure(pressure),
+            Event::ProgramChange(program) => PreparedEventData::ProgramChange(program),
+            Event::Controller { controller, value } => {
+                PreparedEventData::Controller { controller, value }
+            }
+            Event::Text { kind, text } => PreparedEventData::Text { kind, text },
+            Event::CueData(cue) => PreparedEventData::CueData(cue),
+            data => {
+                return Err(Error::Internal(format!(
+                    "MIDI event cannot be prepared: {:?}",
+                    data
+                )));
+            }
+        };
+        Ok(Self {
+            delta_ticks: midi_event.delta_ticks,
+            channel: midi_event.channel,
+            data,
+        })
+    }
+
+    fn to_midi_event(&self) -> MidiEvent {
+        let data = match self.data.clone() {
+            PreparedEventData::NoteOn { note, vel } => Event::NoteOn { note, vel },
+            PreparedEventData::NoteOff { note, vel } => Event::NoteOff { note, vel },
+            PreparedEventData::PitchBend(bend) => Event::PitchBend(bend),
+            PreparedEventData::PitchBendRange(range) => Event::PitchBendRange(range),
+            PreparedEventData::KeyPressure { note, pressure } => {
+                Event::KeyPressure { note, pressure }
+            }
+            PreparedEventData::ChannelPressure(pressure) => Event::ChannelPressure(pressure),
+            PreparedEventData::ProgramChange(program) => Event::ProgramChange(program),
+            PreparedEventData::Controller { controller, value } => {
+                Event::Controller { controller, value }
+            }
+            PreparedEventData::Text { kind, text } => Event::Text { kind, text },
+            PreparedEventData::CueData(cue) => Event::CueData(cue),
+        };
+        MidiEvent {
+            delta_ticks: self.delta_ticks,
+            channel: self.channel,
+            message: Message::broadcast(data),
+        }
+    }
+}
+
+/// Events of every track of a MIDI file with its tempo map, decoded once and
+/// stored by an asset loader so that later program builds skip parsing.
+/// Tracks that cannot be decoded are kept as None, so that they only fail
+/// the builds that play them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PreparedMidiFile {
+    tempo_map: TempoMap,
+    tracks: Vec<Option<Vec<PreparedMidiEvent>>>,
+}
+
+impl PreparedMidiFile {
+    pub fn from_smf(smf: &Smf) -> Result<Self, Error> {
+        let tempo_map = TempoMap::from_smf(smf)?;
+        let tracks = (0..smf.tracks.len())
+            .map(|track_index| match Self::prepare_track(smf, track_index) {
+                Ok(track) => Some(track),
+                Err(error) => {
+                    println!(
+                        "WARNING: MIDI: Skipping track no. {} that cannot be decoded: {:?}",
+                        track_index, error
+                    );
+                    None
+                }
+            })
+            .collect();
+        Ok(Self { tempo_map, tracks })
+    }
+
+    fn prepare_track(smf: &Smf, track_index: usize) -> Result<Vec<PreparedMidiEvent>, Error> {
+        event::midi_events_from_midi(smf, track_index)?
+            .into_iter()
+            .map(PreparedMidiEvent::from_midi_event)
+            .collect()
+    }
+
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    /// Events of a track, as played by a MidiNode
+    pub fn track_events(&self, track_index: usize) -> Result<Vec<MidiEvent>, Error> {
+        let track = self
+            .tracks
+            .get(track_index)
+            .ok_or_else(|| Error::User(format!("ERROR: MIDI: No track no. {}", track_index)))?
+            .as_ref()
+            .ok_or_else(|| {
+                Error::User(format!(
+                    "ERROR: MIDI: Track no. {} could not be decoded",
+                    track_index
+                ))
+            })?;
+        Ok(track.iter().map(PreparedMidiEvent::to_midi_event).collect())
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    Event, GraphNode, Message, consts,
+    node::midi::controller::{
+        CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF, CC_RESET_ALL_CONTROLLERS, CC_SOSTENUTO, CC_SUSTAIN,
+    },
+};
+use std::collections::HashMap;
+
+const SILENCE_THRESHOLD: f32 = 0.00001;
+/// Controller numbers below the channel mode messages
+const CONTROLLER_COUNT: usize = 120;
+
+/// Controller, pitch bend and pressure state of the channel, which an
+/// instrument switched to is brought up to date with
+struct ChannelState {
+    controllers: [Option<u8>; CONTROLLER_COUNT],
+    pitch_bend_range: Option<f32>,
+    pitch_bend: Option<f32>,
+    pressure: Option<f32>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            controllers: [None; CONTROLLER_COUNT],
+            pitch_bend_range: None,
+            pitch_bend: None,
+            pressure: None,
+        }
+    }
+}
+
+/// Instruments available to one MIDI channel, selected by ProgramChange
+/// messages. Program numbers without an instrument fall back to the channel's
+/// default source. Instruments switched away from keep being rendered until
+/// their output falls silent, so that release tails can finish, and notes
+/// still held on them are released by their own note-offs.
+pub struct ProgramBank {
+    current_program: Option<u8>,
+    idle_sources: HashMap<Option<u8>, GraphNode>,
+    releasing: Vec<Option<u8>>,
+    release_buffer: Vec<f32>,
+    /// Notes sounding on the channel, with the program that started each
+    held_notes: Vec<(u8, Option<u8>)>,
+    channel_state: ChannelState,
+}
+
+impl ProgramBank {
+    pub fn new(programs: HashMap<u8, GraphNode>) -> Self {
+        let program_count = programs.len();
+        Self {
+            current_program: None,
+            idle_sources: programs
+                .into_iter()
+                .map(|(program, source)| (Some(program), source))
+                .collect(),
+            releasing: Vec::with_capacity(program_count + 1),
+            release_buffer: vec![0.0; consts::BUFFER_SIZE * consts::CHANNEL_COUNT],
+            held_notes: Vec::with_capacity(128),
+            channel_state: ChannelState::default(),
+        }
+    }
+
+    /// Switch to a program, given the channel's active source, returning the
+    /// source that should now be active
+    pub fn select(&mut self, program: u8, active: Option<GraphNode>) -> Option<GraphNode> {
+        // The current program's instrument is the active source, so is not
+        // among the idle ones
+        let is_available =
+            self.current_program == Some(program) || self.idle_sources.contains_key(&Some(program));
+        let next_program = match is_available {
+            true => Some(program),
+            false => None,
+        };
+        if next_program == self.current_program {
+            return active;
+        }
+        if let Some(previous) = active {
+            self.idle_sources.insert(self.current_program, previous);
+            self.releasing.push(self.current_program);
+        }
+        self.releasing
+            .retain(|releasing| *releasing != next_program);
+        self.current_program = next_program;
+        self.idle_sources.remove(&next_program)
+    }
+
+    pub fn current_program(&self) -> Option<u8> {
+        self.current_program
+    }
+
+    /// Track a message on the channel before it is sent to the active source.
+    /// Returns true where the message has instead been delivered to an
+    /// instrument switched away from, such as the note-off for a note it
+    /// started.
+    pub fn on_channel_event(&mut self, data: &Event) -> bool {
+        match *data {
+            Event::NoteOn { note, vel } if vel > 0.0 => {
+                self.held_notes.retain(|(held, _)| *held != note);
+                self.held_notes.push((note, self.current_program));
+                false
+            }
+            Event::NoteOn { note, .. } | Event::NoteOff { note, .. } => {
+                let Some(index) = self.held_notes.iter().position(|(held, _)| *held == note) else {
+                    return false;
+                };
+                let (_, program) = self.held_notes.remove(index);
+                if program == self.current_program {
+                    return false;
+                }
+                if let Some(source) = self.idle_sources.get_mut(&program) {
+                    source.on_event(&Message::broadcast(data.clone()));
+                }
+                true
+            }
+            Event::Controller { controller, value } => {
+                match controller {
+                    CC_SUSTAIN | CC_SOSTENUTO => {
+                        self.send_to_releasing(data);
+                    }
+                    CC_ALL_SOUND_OFF | CC_ALL_NOTES_OFF => {
+                        self.held_notes.clear();
+                        self.send_to_releasing(&Event::AllNotesOff);
+                    }
+                    CC_RESET_ALL_CONTROLLERS => {
+                        self.channel_state.controllers = [None; CONTROLLER_COUNT];
+                        self.channel_state.pitch_bend = Some(0.0);
+                        self.channel_state.pressure = None;
+                    }
+                    _ => {}
+                }
+                if let Some(latest) = self.channel_state.controllers.get_mut(controller as usize) {
+                    *latest = Some(value);
+                }
+                false
+            }
+            Event::PitchBendRange(range) => {
+                self.channel_state.pitch_bend_range = Some(range);
+                false
+            }
+            Event::PitchBend(bend) => {
+                self.channel_state.pitch_bend = Some(bend);
+                false
+            }
+            Event::ChannelPressure(pressure) => {
+                self.channel_state.pressure = Some(pressure);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Events that bring an instrument switched to up to date with the
+    /// controllers, pitch bend and pressure of the channel
+    pub fn channel_state_events(&self) -> impl Iterator<Item = Event> + '_ {
+        let state = &self.channel_state;
+        let controllers = state
+            .controllers
+            .iter()
+            .enumerate()
+            .filter_map(|(controller, value)| {
+                value.map(|value| Event::Controller {
+                    controller: controller as u8,
+                    value,
+                })
+            });
+        controllers
+            .chain(state.pitch_bend_range.map(Event::PitchBendRange))
+            .chain(state.pitch_bend.map(Event::PitchBend))
+            .chain(state.pressure.map(Event::ChannelPressure))
+    }
+
+    fn send_to_releasing(&mut self, data: &Event) {
+        let message = Message::broadcast(data.clone());
+        for program in self.releasing.iter() {
+            if let Some(source) = self.idle_sources.get_mut(program) {
+                source.on_event(&message);
+            }
+        }
+    }
+
+    pub fn idle_sources_mut(&mut self) -> impl Iterator<Item = &mut GraphNode> {
+        self.idle_sources.values_mut()
+    }
+
+    /// Mix the output of instruments still releasing into the buffer
+    pub fn fill_releasing(&mut self, buffer: &mut [f32]) {
+        let idle_sources = &mut self.idle_sources;
+        let release_buffer = &mut self.release_buffer;
+        let held_notes = &self.held_notes;
+        self.releasing.retain(|program| {
+            let Some(source) = idle_sources.get_mut(program) else {
+                return false;
+            };
+            let mut is_audible = held_notes.iter().any(|(_, held)| held == program);
+            for output_chunk in buffer.chunks_mut(release_buffer.len()) {
+                let release_chunk = &mut release_buffer[0..output_chunk.len()];
+                release_chunk.fill(0.0);
+                source.fill_buffer(release_chunk);
+                for (output, sample) in output_chunk.iter_mut().zip(release_chunk.iter()) {
+                    *output += sample;
+                    is_audible |= sample.abs() > SILENCE_THRESHOLD;
+                }
+            }
+            is_audible
+        });
+    }
+}
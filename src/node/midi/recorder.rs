@@ -0,0 +1,239 @@
+use crate::{Error, Event, GraphNode, Message, Node, consts, midi::TempoMap};
+use crossbeam_channel::{Receiver, Sender, unbounded};
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+    num::{u4, u7, u15, u24, u28},
+};
+use serde_json::Value;
+use std::path::Path;
+
+const DEFAULT_BEATS_PER_MINUTE: f64 = 120.0;
+const DEFAULT_TICKS_PER_BEAT: u16 = 480;
+
+/// Event captured by a MidiRecorderNode, with the frame at which it arrived
+#[derive(Clone, Debug)]
+pub struct RecordedEvent {
+    pub frame: u64,
+    pub event: Event,
+}
+
+/// Node that passes everything through to its consumer, while capturing the
+/// note and cue events going into it for a MidiRecording
+pub struct MidiRecorderNode {
+    node_id: u64,
+    frames_rendered: u64,
+    event_sender: Sender<RecordedEvent>,
+    consumer: GraphNode,
+}
+
+impl MidiRecorderNode {
+    /// Create a recorder node, along with the recording that receives its events
+    pub fn new(node_id: Option<u64>, consumer: GraphNode) -> (Self, MidiRecording) {
+        let (event_sender, event_receiver) = unbounded();
+        let node = Self {
+            node_id: node_id.unwrap_or_else(<Self as Node>::new_node_id),
+            frames_rendered: 0,
+            event_sender,
+            consumer,
+        };
+        (node, MidiRecording::new(event_receiver))
+    }
+}
+
+impl Node for MidiRecorderNode {
+    fn get_node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: u64) {
+        self.node_id = node_id;
+    }
+
+    fn duplicate(&self) -> Result<GraphNode, Error> {
+        let recorder = Self {
+            node_id: self.node_id,
+            frames_rendered: self.frames_rendered,
+            event_sender: self.event_sender.clone(),
+            consumer: self.consumer.duplicate()?,
+        };
+        Ok(Box::new(recorder))
+    }
+
+    fn try_consume_event(&mut self, event: &Message) -> bool {
+        match event.data {
+            Event::NoteOn { .. } | Event::NoteOff { .. } | Event::CueData(_) => {
+                // The recording may have been dropped, which stops recording
+                let _ = self.event_sender.send(RecordedEvent {
+                    frame: self.frames_rendered,
+                    event: event.data.clone(),
+                });
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn propagate(&mut self, event: &Message) {
+        self.consumer.on_event(event);
+    }
+
+    fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        self.consumer.fill_buffer(buffer);
+        self.frames_rendered += (buffer.len() / consts::CHANNEL_COUNT) as u64;
+    }
+
+    fn replace_children(&mut self, children: &[GraphNode]) -> Result<(), Error> {
+        if children.len() != 1 {
+            return Err(Error::User("MidiRecorder requires one child".to_owned()));
+        }
+        self.consumer = children[0].duplicate()?;
+        Ok(())
+    }
+
+    fn get_state_snapshot(&self, for_node_id: u64) -> Option<Result<Value, Error>> {
+        self.consumer.get_state_snapshot(for_node_id)
+    }
+}
+
+/// Events captured by a MidiRecorderNode, which can be written out as a
+/// single-track Standard MIDI File
+pub struct MidiRecording {
+    event_receiver: Receiver<RecordedEvent>,
+    events: Vec<RecordedEvent>,
+    beats_per_minute: f64,
+    ticks_per_beat: u16,
+    channel: u8,
+}
+
+impl MidiRecording {
+    fn new(event_receiver: Receiver<RecordedEvent>) -> Self {
+        Self {
+            event_receiver,
+            events: vec![],
+            beats_per_minute: DEFAULT_BEATS_PER_MINUTE,
+            ticks_per_beat: DEFAULT_TICKS_PER_BEAT,
+            channel: 0,
+        }
+    }
+
+    /// Set the tempo written to the file, which sets the tick position of events
+    pub fn set_tempo(&mut self, beats_per_minute: f64) {
+        self.beats_per_minute = beats_per_minute;
+    }
+
+    /// Set the resolution of the file, in ticks per beat (at most 32767)
+    pub fn set_ticks_per_beat(&mut self, ticks_per_beat: u16) {
+        self.ticks_per_beat = ticks_per_beat.clamp(1, u16::from(u15::max_value()));
+    }
+
+    /// Set the MIDI channel (0 to 15) that notes are written on
+    pub fn set_channel(&mut self, channel: u8) {
+        self.channel = channel.min(15);
+    }
+
+    /// All events recorded so far
+    pub fn events(&mut self) -> &[RecordedEvent] {
+        self.events.extend(self.event_receiver.try_iter());
+        &self.events
+    }
+
+    /// Discard the events recorded so far
+    pub fn clear(&mut self) {
+        self.events();
+        self.events.clear();
+    }
+
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let bytes = self.to_smf_bytes()?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn to_smf_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        self.events();
+        let micros_per_beat = 60000000.0 / self.beats_per_minute;
+        let tempo_map = TempoMap::from_tempo_changes(self.ticks_per_beat, &[(0, micros_per_beat)]);
+        let tick_at_frame = |frame: u64| tempo_map.tick_at_samples(frame as f64).round() as u64;
+
+        // Cues arriving at the same tick are combined into one label
+        let mut cue_labels: Vec<String> = vec![];
+        let mut timed_messages: Vec<(u64, Option<MidiMessage>)> = vec![];
+        for recorded in self.events.iter() {
+            let tick = tick_at_frame(recorded.frame);
+            let message = match &recorded.event {
+                // A note-on at zero velocity is a note-off; any other keeps a
+                // velocity of at least 1 so that it still sounds
+                Event::NoteOn { note, vel } if *vel <= 0.0 => MidiMessage::NoteOff {
+                    key: u7::new((*note).min(127)),
+                    vel: u7::new(0),
+                },
+                Event::NoteOn { note, vel } => MidiMessage::NoteOn {
+                    key: u7::new((*note).min(127)),
+                    vel: u7::new(((vel * 127.0).round() as u8).clamp(1, 127)),
+                },
+                Event::NoteOff { note, vel } => MidiMessage::NoteOff {
+                    key: u7::new((*note).min(127)),
+                    vel: u7::new(((vel * 127.0).round() as u8).min(127)),
+                },
+                Event::CueData(cue) => {
+                    let Some(label) = cue.to_label() else {
+                        println!("WARNING: MIDI: Cue {:?} has no label form, not saved", cue);
+                        continue;
+                    };
+                    match timed_messages.last() {
+                        Some((last_tick, None)) if *last_tick == tick => {
+                            cue_labels.last_mut().unwrap().push_str(&label);
+                        }
+                        _ => {
+                            cue_labels.push(label);
+                            timed_messages.push((tick, None));
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            timed_messages.push((tick, Some(message)));
+        }
+
+        let channel = u4::new(self.channel);
+        let mut cue_labels_iter = cue_labels.iter();
+        let mut timed_events: Vec<(u64, TrackEventKind)> = vec![(
+            0,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(micros_per_beat.round() as u32))),
+        )];
+        for (tick, message) in timed_messages.into_iter() {
+            let kind = match message {
+                Some(message) => TrackEventKind::Midi { channel, message },
+                None => match cue_labels_iter.next() {
+                    Some(label) => TrackEventKind::Meta(MetaMessage::CuePoint(label.as_bytes())),
+                    None => continue,
+                },
+            };
+            timed_events.push((tick, kind));
+        }
+        let end_tick = timed_events.last().map(|(tick, _)| *tick).unwrap_or(0);
+        timed_events.push((end_tick, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+
+        let mut previous_tick: u64 = 0;
+        let track: Vec<TrackEvent> = timed_events
+            .into_iter()
+            .map(|(tick, kind)| {
+                let delta = (tick - previous_tick).min(u32::from(u28::max_value()) as u64);
+                previous_tick = tick;
+                TrackEvent {
+                    delta: u28::new(delta as u32),
+                    kind,
+                }
+            })
+            .collect();
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(self.ticks_per_beat)),
+        ));
+        smf.tracks.push(track);
+        let mut bytes: Vec<u8> = vec![];
+        smf.write_std(&mut bytes)?;
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{Error, node::midi::event::MIDI_CHANNEL_COUNT};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Destination key for sources that play every channel without a route of
+/// its own
+pub(crate) const FALLBACK_DESTINATION: usize = MIDI_CHANNEL_COUNT;
+
+/// How channel numbers are written, for routing tables and config. MIDI data
+/// itself always uses zero-based channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum ChannelNumbering {
+    /// Channels 0 to 15, as used by MidiEvent
+    #[default]
+    ZeroBased,
+    /// Channels 1 to 16, as shown by most DAWs
+    OneBased,
+}
+
+impl ChannelNumbering {
+    /// Convert a channel number written this way to a zero-based channel
+    pub fn to_zero_based(&self, channel: usize) -> Result<usize, Error> {
+        let zero_based = match self {
+            Self::ZeroBased => Some(channel),
+            Self::OneBased => channel.checked_sub(1),
+        };
+        match zero_based {
+            Some(zero_based) if zero_based < MIDI_CHANNEL_COUNT => Ok(zero_based),
+            _ => Err(Error::User(format!(
+                "ERROR: MIDI: Channel {} is out of range for {:?} numbering",
+                channel, self
+            ))),
+        }
+    }
+}
+
+/// Channels that one destination plays
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ChannelSelector {
+    Channels(Vec<usize>),
+    /// Every channel not routed elsewhere
+    AllOthers,
+}
+
+/// Table assigning each of a list of sources the channels it plays, such as
+/// for the children given to MidiNode::replace_children
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChannelRouting {
+    #[serde(default)]
+    pub numbering: ChannelNumbering,
+    /// Channels for each source, by the source's index
+    pub destinations: Vec<ChannelSelector>,
+}
+
+/// Resolved routes from zero-based MIDI channels to destination keys
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChannelRoutes {
+    by_channel: HashMap<usize, usize>,
+    fallback: Option<usize>,
+}
+
+impl ChannelRoutes {
+    pub fn route(&self, channel: usize) -> Option<usize> {
+        self.by_channel.get(&channel).copied().or(self.fallback)
+    }
+
+    pub fn add_route(&mut self, channel: usize, destination: usize) {
+        let previous = self.by_channel.insert(channel, destination);
+        if previous.is_some_and(|previous| previous != destination) {
+            println!(
+                "WARNING: MIDI: Channel {} (zero-based) routed again will overwrite previous route",
+                channel
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_channel.is_empty() && self.fallback.is_none()
+    }
+
+    pub fn set_fallback(&mut self, destination: Option<usize>) {
+        self.fallback = destination;
+    }
+
+    /// Routes from a table, where each destination key is the source's index
+    pub fn from_routing(routing: &ChannelRouting) -> Result<Self, Error> {
+        let mut routes = Self::default();
+        for (index, selector) in routing.destinations.iter().enumerate() {
+            match selector {
+                ChannelSelector::Channels(channels) => {
+                    for channel in channels.iter() {
+                        routes.add_route(routing.numbering.to_zero_based(*channel)?, index);
+                    }
+                }
+                ChannelSelector::AllOthers => {
+                    if routes.fallback.is_some() {
+                        return Err(Error::User(
+                            "ERROR: MIDI: Only one destination can take all other channels"
+                                .to_owned(),
+                        ));
+                    }
+                    routes.fallback = Some(index);
+                }
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Print a warning listing the given channels that have no destination
+    pub fn warn_unrouted(&self, channels: impl Iterator<Item = usize>) {
+        let mut unrouted: Vec<usize> = channels
+            .filter(|channel| self.route(*channel).is_none())
+            .collect();
+        unrouted.sort();
+        unrouted.dedup();
+        if unrouted.is_empty() {
+            return;
+        }
+        let list: Vec<String> = unrouted.iter().map(|channel| channel.to_string()).collect();
+        println!(
+            "WARNING: MIDI: Channels {} (zero-based) in the track have no destination",
+            list.join(", ")
+        );
+    }
+}
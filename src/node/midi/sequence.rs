@@ -0,0 +1,103 @@
+use crate::{
+    Error, Event, Message, consts,
+    midi::{CueData, MidiEvent, MidiTextKind, TempoMap},
+    node::midi::text,
+};
+use serde::{Deserialize, Serialize};
+
+/// Time of an event in an inline sequence
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum SequenceTime {
+    Tick(u64),
+    Seconds(f64),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum SequenceEventData {
+    NoteOn {
+        note: u8,
+        vel: f32,
+    },
+    NoteOff {
+        note: u8,
+    },
+    /// Label in the same syntax as MIDI cue points; labels that aren't cue
+    /// syntax become text events
+    Cue(String),
+}
+
+/// Event written directly in a config, rather than loaded from a MIDI file
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SequenceEvent {
+    pub time: SequenceTime,
+    /// Zero-based MIDI channel
+    #[serde(default)]
+    pub channel: usize,
+    pub data: SequenceEventData,
+}
+
+/// Convert an inline sequence into MIDI events. Events are sorted by time,
+/// keeping the written order of events at the same time.
+pub fn midi_events_from_sequence(
+    events: &[SequenceEvent],
+    tempo_map: &TempoMap,
+) -> Result<Vec<MidiEvent>, Error> {
+    let mut timed_events: Vec<(u64, &SequenceEvent)> = events
+        .iter()
+        .map(|event| {
+            let tick = match event.time {
+                SequenceTime::Tick(tick) => tick,
+                SequenceTime::Seconds(seconds) => tempo_map
+                    .tick_at_samples(seconds.max(0.0) * consts::PLAYBACK_SAMPLE_RATE as f64)
+                    .round() as u64,
+            };
+            (tick, event)
+        })
+        .collect();
+    timed_events.sort_by_key(|(tick, _)| *tick);
+
+    let mut midi_events: Vec<MidiEvent> = vec![];
+    let mut previous_tick = 0u64;
+    let mut push_event = |tick: u64, channel: usize, data: Event| {
+        midi_events.push(MidiEvent {
+            delta_ticks: (tick - previous_tick) as isize,
+            channel,
+            message: Message::broadcast(data),
+        });
+        previous_tick = tick;
+    };
+    for (tick, event) in timed_events.into_iter() {
+        match &event.data {
+            SequenceEventData::NoteOn { note, vel } => push_event(
+                tick,
+                event.channel,
+                Event::NoteOn {
+                    note: *note,
+                    vel: *vel,
+                },
+            ),
+            SequenceEventData::NoteOff { note } => push_event(
+                tick,
+                event.channel,
+                Event::NoteOff {
+                    note: *note,
+                    vel: 0.0,
+                },
+            ),
+            SequenceEventData::Cue(label) if text::is_cue_label(label.as_bytes()) => {
+                for cue in CueData::from_label(label.as_bytes())?.into_iter() {
+                    push_event(tick, 0, Event::CueData(cue));
+                }
+            }
+            SequenceEventData::Cue(label) => push_event(
+                tick,
+                0,
+                Event::Text {
+                    kind: MidiTextKind::CuePoint,
+                    text: label.clone(),
+                },
+            ),
+        }
+    }
+    Ok(midi_events)
+}
@@ -0,0 +1,242 @@
+use crate::{
+    AssetLoader, Error, Event, Message, Node,
+    abstraction::ChildConfig,
+    consts,
+    generator::{OneShot, OneShotNode},
+    midi::{Anchor, Midi, MidiNode},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Point in the reference music that a stinger starts on
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum StingerAlignment {
+    /// Start straight away
+    Immediate,
+    /// Start on the next beat of the time signature
+    Beat,
+    /// Start on the next bar line
+    Bar,
+    /// Start when playback next reaches a cue target marker
+    Anchor(Anchor),
+}
+
+/// Phrase layered over a MidiNode's music, started on a musical boundary
+pub enum Stinger {
+    Sequence(Box<MidiNode>),
+    OneShot(OneShotNode),
+}
+
+/// Stinger phrase as written in config
+#[derive(Deserialize, Clone)]
+pub enum StingerConfig {
+    Sequence(Box<Midi>),
+    OneShot(OneShot),
+}
+
+impl StingerConfig {
+    pub fn to_stinger(&self, asset_loader: &mut dyn AssetLoader) -> Result<Stinger, Error> {
+        Ok(match self {
+            Self::Sequence(config) => {
+                Stinger::Sequence(Box::new(config.to_midi_node(asset_loader)?))
+            }
+            Self::OneShot(config) => Stinger::OneShot(config.to_one_shot_node(asset_loader)?),
+        })
+    }
+
+    pub fn to_child_config(&self) -> ChildConfig {
+        match self {
+            Self::Sequence(config) => ChildConfig(config.clone()),
+            Self::OneShot(config) => ChildConfig(Box::new(config.clone())),
+        }
+    }
+}
+
+impl Stinger {
+    /// Play from the beginning
+    fn start(&mut self) {
+        match self {
+            Self::Sequence(node) => node.restart(),
+            Self::OneShot(node) => {
+                node.on_event(&Message::broadcast(Event::NoteOn { note: 0, vel: 1.0 }))
+            }
+        }
+    }
+
+    fn has_finished(&self) -> bool {
+        match self {
+            Self::Sequence(node) => node.has_finished(),
+            Self::OneShot(node) => node.has_finished(),
+        }
+    }
+
+    fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        match self {
+            Self::Sequence(node) => node.fill_buffer(buffer),
+            Self::OneShot(node) => node.fill_buffer(buffer),
+        }
+    }
+}
+
+/// Point of playback that a scheduled stinger waits for
+pub(crate) enum StingerStart {
+    Now,
+    /// Tick of the next beat
+    Beat(f64),
+    /// Tick of the next bar line
+    Bar(f64),
+    Anchor(Anchor),
+}
+
+struct StingerState {
+    stinger: Stinger,
+    /// Point the stinger is waiting for, if it has been scheduled
+    start: Option<StingerStart>,
+    /// Frame of the buffer being filled that the stinger starts on, once
+    /// playback has reached its start
+    start_frame: Option<usize>,
+    is_playing: bool,
+}
+
+/// Stingers of a MidiNode by name, mixed into its output while they play.
+/// The MidiNode starts scheduled stingers as its clock reaches them, so that
+/// they follow time dilation, tempo changes and seeks.
+#[derive(Default)]
+pub(crate) struct Stingers {
+    stingers: HashMap<String, StingerState>,
+    /// Frame of the buffer being filled that playback has reached
+    frame: usize,
+}
+
+impl Stingers {
+    pub fn add(&mut self, name: String, stinger: Stinger) {
+        let state = StingerState {
+            stinger,
+            start: None,
+            start_frame: None,
+            is_playing: false,
+        };
+        if self.stingers.insert(name.clone(), state).is_some() {
+            println!(
+                "WARNING: MIDI: Stinger \"{}\" added again will overwrite previous stinger",
+                name
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stingers.is_empty()
+    }
+
+    /// Start a stinger when playback reaches a point, replacing any earlier
+    /// schedule for it. A stinger already playing carries on until the new
+    /// start.
+    pub fn schedule(&mut self, name: &str, start: StingerStart) {
+        let Some(state) = self.stingers.get_mut(name) else {
+            println!("WARNING: MIDI: No stinger \"{}\" to play", name);
+            return;
+        };
+        match start {
+            StingerStart::Now => {
+                state.start = None;
+                state.start_frame = Some(self.frame);
+            }
+            start => {
+                state.start = Some(start);
+                state.start_frame = None;
+            }
+        }
+    }
+
+    /// Set the frame of the buffer being filled that playback has reached
+    pub fn set_frame(&mut self, frame: usize) {
+        self.frame = frame;
+    }
+
+    /// Start stingers waiting for a tick that playback reaches within the
+    /// next frames, given the frames until each tick. Where the frames end
+    /// on an event, a tick reached on that event's frame starts too.
+    pub fn start_ticks_reached(
+        &mut self,
+        frames: usize,
+        includes_end: bool,
+        frames_until_tick: impl Fn(f64) -> usize,
+    ) {
+        for state in self.stingers.values_mut() {
+            let (Some(StingerStart::Beat(tick)) | Some(StingerStart::Bar(tick))) = state.start
+            else {
+                continue;
+            };
+            let frames_until = frames_until_tick(tick);
+            if frames_until < frames || (includes_end && frames_until == frames) {
+                state.start = None;
+                state.start_frame = Some(self.frame + frames_until);
+            }
+        }
+    }
+
+    /// Move stingers waiting for a beat or bar line to the next one after a
+    /// seek, given the ticks of the next beat and bar line from the seek
+    /// target
+    pub fn realign(&mut self, next_beat_tick: Option<f64>, next_bar_tick: Option<f64>) {
+        for state in self.stingers.values_mut() {
+            match &mut state.start {
+                Some(StingerStart::Beat(tick)) => *tick = next_beat_tick.unwrap_or(*tick),
+                Some(StingerStart::Bar(tick)) => *tick = next_bar_tick.unwrap_or(*tick),
+                _ => {}
+            }
+        }
+    }
+
+    /// Start stingers waiting for an anchor that playback has reached
+    pub fn anchor_reached(&mut self, anchor: &Anchor) {
+        for state in self.stingers.values_mut() {
+            if matches!(&state.start, Some(StingerStart::Anchor(a)) if a == anchor) {
+                state.start = None;
+                state.start_frame = Some(self.frame);
+            }
+        }
+    }
+
+    /// Start every stinger still waiting, for when playback has stopped
+    /// before reaching them
+    pub fn start_waiting(&mut self) {
+        for state in self.stingers.values_mut() {
+            if state.start.take().is_some() {
+                state.start_frame = Some(self.frame);
+            }
+        }
+    }
+
+    /// Mix playing stingers into the buffer, starting those due on their
+    /// frame
+    pub fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        let frames = buffer.len() / consts::CHANNEL_COUNT;
+        for state in self.stingers.values_mut() {
+            let start_frame = match state.start_frame.take() {
+                Some(start_frame) => {
+                    let start_frame = start_frame.min(frames);
+                    if state.is_playing {
+                        state
+                            .stinger
+                            .fill_buffer(&mut buffer[0..start_frame * consts::CHANNEL_COUNT]);
+                    }
+                    state.is_playing = true;
+                    state.stinger.start();
+                    start_frame
+                }
+                None => 0,
+            };
+            if !state.is_playing {
+                continue;
+            }
+            state
+                .stinger
+                .fill_buffer(&mut buffer[start_frame * consts::CHANNEL_COUNT..]);
+            if state.stinger.has_finished() {
+                state.is_playing = false;
+            }
+        }
+        self.frame = 0;
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{Error, consts::PLAYBACK_SAMPLE_RATE};
+use midly::{Fps, MetaMessage, Smf, Timing, TrackEventKind};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_MICROS_PER_BEAT: f64 = 500000.0;
+
+/// Fraction of a grid step by which a position may pass a grid line and still
+/// count as being on it
+const GRID_TOLERANCE: f64 = 0.000001;
+
+/// Position in a MIDI sequence to seek to
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub enum SeekPosition {
+    Tick(u64),
+    Seconds(f64),
+    /// Bar and beat, both counted from 1, using the time signatures in the
+    /// data (or 4/4 if there are none); the beat may be fractional
+    BarBeat {
+        bar: u32,
+        beat: f64,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct TempoSegment {
+    start_tick: u64,
+    start_samples: f64,
+    samples_per_tick: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct TimeSignatureSegment {
+    start_tick: u64,
+    start_bar: f64,
+    numerator: u8,
+    denominator: u8,
+}
+
+/// Mapping between MIDI ticks and output samples, made of segments of
+/// constant tempo. Tempo changes are collected from every track in a file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TempoMap {
+    ticks_per_beat: Option<u16>,
+    segments: Vec<TempoSegment>,
+    time_signatures: Vec<TimeSignatureSegment>,
+}
+
+impl TempoMap {
+    pub fn from_smf(smf: &Smf) -> Result<Self, Error> {
+        match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => {
+                let mut tempo_changes: Vec<(u64, f64)> = vec![];
+                let mut time_signatures: Vec<(u64, u8, u8)> = vec![];
+                for track in smf.tracks.iter() {
+                    let mut tick: u64 = 0;
+                    for event in track.iter() {
+                        tick += u32::from(event.delta) as u64;
+                        match event.kind {
+                            TrackEventKind::Meta(MetaMessage::Tempo(micros)) => {
+                                tempo_changes.push((tick, u32::from(micros) as f64));
+                            }
+                            TrackEventKind::Meta(MetaMessage::TimeSignature(
+                                numerator,
+                                denominator_power,
+                                _,
+                                _,
+                            )) => {
+                                let denominator = 1u8.checked_shl(denominator_power as u32);
+                                time_signatures.push((tick, numerator, denominator.unwrap_or(4)));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                if tempo_changes.is_empty() {
+                    // TODO - This is a fallback for Ardour not exporting
+                    // tempo meta events. This is not ideal.
+                    println!("WARNING: MIDI: Tempo meta event not found, assuming 120 BPM");
+                }
+                tempo_changes.sort_by_key(|(tick, _)| *tick);
+                time_signatures.sort_by_key(|(tick, _, _)| *tick);
+                Ok(
+                    Self::from_tempo_changes(u16::from(ticks_per_beat), &tempo_changes)
+                        .with_time_signatures(&time_signatures),
+                )
+            }
+            Timing::Timecode(fps, sub) => {
+                let frames_per_second: f64 = match fps {
+                    Fps::Fps24 => 24.0,
+                    Fps::Fps25 => 25.0,
+                    Fps::Fps29 => 30.0 / 1.001,
+                    Fps::Fps30 => 30.0,
+                };
+                let ticks_per_second = frames_per_second * (sub as f64);
+                Ok(Self::constant(
+                    PLAYBACK_SAMPLE_RATE as f64 / ticks_per_second,
+                ))
+            }
+        }
+    }
+
+    /// Build a map from metrical timing and a list of (tick, microseconds per beat)
+    /// pairs sorted by tick. Where several changes share a tick, the last one wins.
+    pub fn from_tempo_changes(ticks_per_beat: u16, tempo_changes: &[(u64, f64)]) -> Self {
+        let samples_per_tick_for = |micros_per_beat: f64| {
+            let samples_per_micro = (PLAYBACK_SAMPLE_RATE as f64) / 1000000.0;
+            samples_per_micro * micros_per_beat / (ticks_per_beat.max(1) as f64)
+        };
+        let mut segments = vec![TempoSegment {
+            start_tick: 0,
+            start_samples: 0.0,
+            samples_per_tick: samples_per_tick_for(DEFAULT_MICROS_PER_BEAT),
+        }];
+        for (tick, micros_per_beat) in tempo_changes.iter() {
+            let last = segments.last_mut().unwrap();
+            if last.start_tick == *tick {
+                last.samples_per_tick = samples_per_tick_for(*micros_per_beat);
+                continue;
+            }
+            let start_samples =
+                last.start_samples + (*tick - last.start_tick) as f64 * last.samples_per_tick;
+            segments.push(TempoSegment {
+                start_tick: *tick,
+                start_samples,
+                samples_per_tick: samples_per_tick_for(*micros_per_beat),
+            });
+        }
+        Self {
+            ticks_per_beat: Some(ticks_per_beat),
+            segments,
+            time_signatures: vec![TimeSignatureSegment {
+                start_tick: 0,
+                start_bar: 0.0,
+                numerator: 4,
+                denominator: 4,
+            }],
+        }
+    }
+
+    /// Set the time signatures from a list of (tick, numerator, denominator)
+    /// sorted by tick, replacing the default of 4/4
+    pub fn with_time_signatures(mut self, time_signatures: &[(u64, u8, u8)]) -> Self {
+        let Some(ticks_per_beat) = self.ticks_per_beat else {
+            return self;
+        };
+        for (tick, numerator, denominator) in time_signatures.iter() {
+            let last = self.time_signatures.last_mut().unwrap();
+            let numerator = (*numerator).max(1);
+            let denominator = (*denominator).max(1);
+            if last.start_tick == *tick {
+                last.numerator = numerator;
+                last.denominator = denominator;
+                continue;
+            }
+            let ticks_per_bar = Self::ticks_per_bar(ticks_per_beat, last);
+            let start_bar = last.start_bar + (*tick - last.start_tick) as f64 / ticks_per_bar;
+            self.time_signatures.push(TimeSignatureSegment {
+                start_tick: *tick,
+                start_bar,
+                numerator,
+                denominator,
+            });
+        }
+        self
+    }
+
+    fn ticks_per_bar(ticks_per_beat: u16, time_signature: &TimeSignatureSegment) -> f64 {
+        ticks_per_beat as f64 * 4.0 * time_signature.numerator as f64
+            / time_signature.denominator as f64
+    }
+
+    /// Build a map with a single fixed rate, with no musical beat information
+    pub fn constant(samples_per_tick: f64) -> Self {
+        Self {
+            ticks_per_beat: None,
+            segments: vec![TempoSegment {
+                start_tick: 0,
+                start_samples: 0.0,
+                samples_per_tick,
+            }],
+            time_signatures: vec![],
+        }
+    }
+
+    pub fn ticks_per_beat(&self) -> Option<u16> {
+        self.ticks_per_beat
+    }
+
+    fn segment_index_for_tick(&self, tick: u64) -> usize {
+        self.segments
+            .partition_point(|segment| segment.start_tick <= tick)
+            .saturating_sub(1)
+    }
+
+    pub fn samples_per_tick_at(&self, tick: u64) -> f64 {
+        self.segments[self.segment_index_for_tick(tick)].samples_per_tick
+    }
+
+    /// Number of samples from the start of the sequence to the given tick
+    pub fn samples_at_tick(&self, tick: u64) -> f64 {
+        let segment = &self.segments[self.segment_index_for_tick(tick)];
+        segment.start_samples + (tick - segment.start_tick) as f64 * segment.samples_per_tick
+    }
+
+    /// Tempo in beats per minute at a tick, or None if the timing has no
+    /// musical beats
+    pub fn beats_per_minute_at(&self, tick: u64) -> Option<f64> {
+        let ticks_per_beat = self.ticks_per_beat?;
+        let samples_per_beat = self.samples_per_tick_at(tick) * ticks_per_beat.max(1) as f64;
+        Some(60.0 * PLAYBACK_SAMPLE_RATE as f64 / samples_per_beat)
+    }
+
+    /// Number of samples from the start of the sequence to a fractional tick
+    pub fn samples_at_fractional_tick(&self, tick: f64) -> f64 {
+        let whole_tick = tick.max(0.0).floor() as u64;
+        self.samples_at_tick(whole_tick)
+            + (tick - whole_tick as f64) * self.samples_per_tick_at(whole_tick)
+    }
+
+    /// Tick position (possibly fractional) reached after the given number of samples
+    pub fn tick_at_samples(&self, samples: f64) -> f64 {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.start_samples <= samples)
+            .saturating_sub(1);
+        let segment = &self.segments[index];
+        segment.start_tick as f64 + (samples - segment.start_samples) / segment.samples_per_tick
+    }
+
+    /// Tick position of a bar and beat, both counted from 1, or None if the
+    /// timing has no musical beats
+    pub fn tick_at_bar_beat(&self, bar: u32, beat: f64) -> Option<f64> {
+        let ticks_per_beat = self.ticks_per_beat?;
+        let bar_index = bar.max(1) as f64 - 1.0;
+        let index = self
+            .time_signatures
+            .partition_point(|segment| segment.start_bar <= bar_index)
+            .saturating_sub(1);
+        let segment = self.time_signatures.get(index)?;
+        let ticks_per_bar = Self::ticks_per_bar(ticks_per_beat, segment);
+        let bar_position =
+            bar_index - segment.start_bar + (beat.max(1.0) - 1.0) / segment.numerator as f64;
+        Some(segment.start_tick as f64 + bar_position * ticks_per_bar)
+    }
+
+    /// Tick of the first beat at or after the given tick, where a beat is a
+    /// note of the time signature's denominator, or None if the timing has no
+    /// musical beats
+    pub fn next_beat_tick(&self, tick: f64) -> Option<f64> {
+        self.next_grid_tick(tick, |ticks_per_bar, segment| {
+            ticks_per_bar / segment.numerator as f64
+        })
+    }
+
+    /// Tick of the first bar line at or after the given tick, or None if the
+    /// timing has no musical beats
+    pub fn next_bar_tick(&self, tick: f64) -> Option<f64> {
+        self.next_grid_tick(tick, |ticks_per_bar, _| ticks_per_bar)
+    }
+
+    fn next_grid_tick(
+        &self,
+        tick: f64,
+        step_of: impl Fn(f64, &TimeSignatureSegment) -> f64,
+    ) -> Option<f64> {
+        let ticks_per_beat = self.ticks_per_beat?;
+        let index = self
+            .time_signatures
+            .partition_point(|segment| segment.start_tick as f64 <= tick)
+            .saturating_sub(1);
+        let segment = self.time_signatures.get(index)?;
+        let step = step_of(Self::ticks_per_bar(ticks_per_beat, segment), segment);
+        let steps = ((tick - segment.start_tick as f64) / step - GRID_TOLERANCE).ceil();
+        let grid_tick = segment.start_tick as f64 + steps.max(0.0) * step;
+        match self.time_signatures.get(index + 1) {
+            Some(next) if next.start_tick as f64 <= grid_tick => Some(next.start_tick as f64),
+            _ => Some(grid_tick),
+        }
+    }
+
+    /// Tick position of a seek position, or None if it cannot be resolved
+    pub fn tick_at_position(&self, position: &SeekPosition) -> Option<u64> {
+        let tick = match *position {
+            SeekPosition::Tick(tick) => return Some(tick),
+            SeekPosition::Seconds(seconds) => {
+                self.tick_at_samples(seconds.max(0.0) * PLAYBACK_SAMPLE_RATE as f64)
+            }
+            SeekPosition::BarBeat { bar, beat } => self.tick_at_bar_beat(bar, beat)?,
+        };
+        Some(tick.round().max(0.0) as u64)
+    }
+}
+
+/// Tempo in beats per minute set at runtime in place of a sequence's own
+/// tempo, ramping linearly towards its target
+#[derive(Clone, Debug)]
+pub(crate) struct TempoOverride {
+    beats_per_minute: f64,
+    target_beats_per_minute: f64,
+    change_per_frame: f64,
+}
+
+impl TempoOverride {
+    pub fn new(from_beats_per_minute: f64, to_beats_per_minute: f64, seconds: f32) -> Self {
+        let frames = seconds.max(0.0) as f64 * PLAYBACK_SAMPLE_RATE as f64;
+        let (beats_per_minute, change_per_frame) = match frames < 1.0 {
+            true => (to_beats_per_minute, 0.0),
+            false => (
+                from_beats_per_minute,
+                (to_beats_per_minute - from_beats_per_minute) / frames,
+            ),
+        };
+        Self {
+            beats_per_minute,
+            target_beats_per_minute: to_beats_per_minute,
+            change_per_frame,
+        }
+    }
+
+    pub fn beats_per_minute(&self) -> f64 {
+        self.beats_per_minute
+    }
+
+    /// Move the ramp on by a number of output frames
+    pub fn advance(&mut self, frames: usize) {
+        let remaining = self.target_beats_per_minute - self.beats_per_minute;
+        let change = self.change_per_frame * frames as f64;
+        self.beats_per_minute = match change.abs() < remaining.abs() {
+            true => self.beats_per_minute + change,
+            false => self.target_beats_per_minute,
+        };
+    }
+}
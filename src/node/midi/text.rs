@@ -0,0 +1,51 @@
+use crossbeam_channel::Sender;
+use serde::{Deserialize, Serialize};
+
+/// Characters that begin a cue component, marking a marker or cue point label
+/// as cue syntax rather than plain text
+const CUE_LABEL_START_CHARACTERS: [char; 4] = ['#', '>', '?', '{'];
+
+/// Kind of MIDI meta event that a text event came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MidiTextKind {
+    Text,
+    Lyric,
+    TrackName,
+    Marker,
+    CuePoint,
+}
+
+/// Text reached during playback by a MidiNode, sent to its subscribers
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiText {
+    pub node_id: u64,
+    pub kind: MidiTextKind,
+    pub text: String,
+    /// Tick position of the event within the track
+    pub tick: u64,
+}
+
+/// Whether a marker or cue point label should be parsed as cue components
+pub fn is_cue_label(label: &[u8]) -> bool {
+    String::from_utf8_lossy(label)
+        .trim_start()
+        .starts_with(CUE_LABEL_START_CHARACTERS)
+}
+
+/// Receivers of text events from a MidiNode; subscribers that have hung up
+/// are dropped
+#[derive(Clone, Default)]
+pub(crate) struct TextSubscribers {
+    senders: Vec<Sender<MidiText>>,
+}
+
+impl TextSubscribers {
+    pub fn add(&mut self, sender: Sender<MidiText>) {
+        self.senders.push(sender);
+    }
+
+    pub fn send(&mut self, text: MidiText) {
+        self.senders
+            .retain(|sender| sender.send(text.clone()).is_ok());
+    }
+}
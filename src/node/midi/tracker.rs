@@ -0,0 +1,653 @@
+use crate::{
+    Balance, Error, Event, GraphNode, LoopRange, Message, SampleBuffer,
+    generator::SampleLoopNode,
+    midi::{Anchor, CueData, MidiEvent, TempoMap},
+    node::midi::event::MIDI_CHANNEL_COUNT,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+const TITLE_LENGTH: usize = 20;
+const SAMPLE_COUNT: usize = 31;
+const SAMPLE_HEADER_SIZE: usize = 30;
+const SAMPLE_NAME_LENGTH: usize = 22;
+const SONG_LENGTH_OFFSET: usize = 950;
+const ORDER_TABLE_OFFSET: usize = 952;
+const ORDER_TABLE_LENGTH: usize = 128;
+const SIGNATURE_OFFSET: usize = 1080;
+const PATTERN_DATA_OFFSET: usize = 1084;
+const ROWS_PER_PATTERN: usize = 64;
+const BYTES_PER_CELL: usize = 4;
+
+/// Rate at which an unadjusted sample plays its C-2 (period 428)
+const AMIGA_SAMPLE_RATE: f64 = 8363.0;
+const PERIOD_C1: f64 = 856.0;
+const NOTE_C1: u8 = 48;
+const NOTE_C2: u8 = NOTE_C1 + 12;
+const MIN_PERIOD: f64 = 113.0;
+const MAX_PERIOD: f64 = 856.0;
+const MAX_VOLUME: u8 = 64;
+
+/// One MIDI tick per tracker tick, so that 4 rows of 6 ticks make a beat and
+/// the module's tempo is its BPM
+const TICKS_PER_BEAT: u16 = 24;
+const DEFAULT_SPEED: u64 = 6;
+const DEFAULT_TEMPO: f64 = 125.0;
+const LEFT_PAN: f32 = 0.25;
+const RIGHT_PAN: f32 = 0.75;
+
+const EFFECT_ARPEGGIO: u8 = 0x0;
+const EFFECT_PORTAMENTO_UP: u8 = 0x1;
+const EFFECT_PORTAMENTO_DOWN: u8 = 0x2;
+const EFFECT_TONE_PORTAMENTO: u8 = 0x3;
+const EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE: u8 = 0x5;
+const EFFECT_VOLUME_SLIDE: u8 = 0xA;
+const EFFECT_POSITION_JUMP: u8 = 0xB;
+const EFFECT_SET_VOLUME: u8 = 0xC;
+const EFFECT_PATTERN_BREAK: u8 = 0xD;
+const EFFECT_EXTENDED: u8 = 0xE;
+const EFFECT_SET_SPEED: u8 = 0xF;
+const EXTENDED_FINE_PORTAMENTO_UP: u8 = 0x1;
+const EXTENDED_FINE_PORTAMENTO_DOWN: u8 = 0x2;
+const EXTENDED_FINE_VOLUME_UP: u8 = 0xA;
+const EXTENDED_FINE_VOLUME_DOWN: u8 = 0xB;
+const EXTENDED_NOTE_CUT: u8 = 0xC;
+const EXTENDED_NOTE_DELAY: u8 = 0xD;
+
+/// Instrument sample from a module, held in the module's shared buffer
+struct ModSample {
+    volume: u8,
+    finetune: i8,
+    buffer_start_index: usize,
+    length: usize,
+    loop_range: Option<LoopRange>,
+}
+
+#[derive(Clone, Copy)]
+struct ModCell {
+    sample: u8,
+    period: u16,
+    effect: u8,
+    param: u8,
+}
+
+/// Event from one tracker channel at an absolute tick. Markers sort before
+/// other events at the same tick, so that seeking to one replays its row.
+struct TimedEvent {
+    tick: u64,
+    is_marker: bool,
+    channel: usize,
+    data: Event,
+}
+
+/// Playback state of a tracker channel while its rows are converted
+#[derive(Default, Clone)]
+struct ChannelState {
+    /// Sample last selected by a cell
+    sample: Option<u8>,
+    /// Sample of the source playing, switched to when a note is played
+    program: Option<u8>,
+    note: Option<u8>,
+    /// Period of the note as triggered, which the pitch multiplier is
+    /// relative to
+    note_period: f64,
+    /// Period after slides
+    period: f64,
+    target_period: f64,
+    portamento_speed: u8,
+    volume: u8,
+    multiplier: f32,
+}
+
+/// ProTracker module (.MOD) converted for playback by a MidiNode. Each
+/// tracker channel becomes the MIDI channel of the same (zero-based) number,
+/// and each sample becomes the program of its zero-based sample number.
+pub struct TrackerModule {
+    pub title: String,
+    pub channel_count: usize,
+    pub midi_events: Vec<MidiEvent>,
+    pub tempo_map: TempoMap,
+    samples: Vec<Option<ModSample>>,
+    sample_buffer: SampleBuffer,
+}
+
+impl TrackerModule {
+    /// Parse a 31-sample module. Backward position jumps become a loop, using
+    /// cue anchors; playback otherwise ends after the last position.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < PATTERN_DATA_OFFSET {
+            return Err(Error::User(
+                "ERROR: MOD: File is too short to be a module".to_owned(),
+            ));
+        }
+        let channel_count = channel_count_of(&bytes[SIGNATURE_OFFSET..PATTERN_DATA_OFFSET])?;
+        if channel_count == 0 || channel_count > MIDI_CHANNEL_COUNT {
+            return Err(Error::User(format!(
+                "ERROR: MOD: {} channels is not supported (1 to {} is supported)",
+                channel_count, MIDI_CHANNEL_COUNT
+            )));
+        }
+        let title = text_of(&bytes[0..TITLE_LENGTH]);
+
+        let song_length = (bytes[SONG_LENGTH_OFFSET] as usize).min(ORDER_TABLE_LENGTH);
+        if song_length == 0 {
+            return Err(Error::User(
+                "ERROR: MOD: Module has no positions to play".to_owned(),
+            ));
+        }
+        let orders = &bytes[ORDER_TABLE_OFFSET..ORDER_TABLE_OFFSET + ORDER_TABLE_LENGTH];
+        let pattern_count = *orders.iter().max().unwrap_or(&0) as usize + 1;
+        let pattern_size = ROWS_PER_PATTERN * channel_count * BYTES_PER_CELL;
+        let sample_data_offset = PATTERN_DATA_OFFSET + pattern_count * pattern_size;
+        if bytes.len() < sample_data_offset {
+            return Err(Error::User(format!(
+                "ERROR: MOD: File is too short for {} patterns",
+                pattern_count
+            )));
+        }
+        let patterns = &bytes[PATTERN_DATA_OFFSET..sample_data_offset];
+
+        let (samples, sample_buffer) = read_samples(bytes, sample_data_offset);
+        let mut converter = ModConverter::new(channel_count, &samples);
+        converter.convert(&orders[0..song_length], patterns)?;
+        converter.warn_unsupported_effects();
+        Ok(Self {
+            title,
+            channel_count,
+            midi_events: converter.midi_events(),
+            tempo_map: TempoMap::from_tempo_changes(TICKS_PER_BEAT, &converter.tempo_changes),
+            samples,
+            sample_buffer: Arc::new(sample_buffer),
+        })
+    }
+
+    /// Sources for each sample a channel can play, by program number. Sources
+    /// share the module's sample data, and are panned as on the Amiga.
+    pub fn channel_programs(&self, channel: usize) -> Result<HashMap<u8, GraphNode>, Error> {
+        let balance = match channel % 4 {
+            0 | 3 => Balance::Pan(LEFT_PAN),
+            _ => Balance::Pan(RIGHT_PAN),
+        };
+        let mut programs: HashMap<u8, GraphNode> = HashMap::new();
+        for (index, sample) in self.samples.iter().enumerate() {
+            let Some(sample) = sample else {
+                continue;
+            };
+            let sample_rate = AMIGA_SAMPLE_RATE * 2.0f64.powf(sample.finetune as f64 / 96.0);
+            let source = SampleLoopNode::new(
+                None,
+                sample_rate.round() as u32,
+                1,
+                NOTE_C2,
+                sample.loop_range.clone(),
+                balance,
+                self.sample_buffer.clone(),
+                sample.buffer_start_index,
+                sample.length,
+            )?;
+            programs.insert(index as u8, Box::new(source));
+        }
+        Ok(programs)
+    }
+}
+
+fn channel_count_of(signature: &[u8]) -> Result<usize, Error> {
+    match signature {
+        b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => Ok(4),
+        b"6CHN" => Ok(6),
+        b"8CHN" | b"FLT8" | b"OCTA" | b"CD81" => Ok(8),
+        [tens, ones, b'C', b'H'] if tens.is_ascii_digit() && ones.is_ascii_digit() => {
+            Ok(((tens - b'0') * 10 + (ones - b'0')) as usize)
+        }
+        _ => Err(Error::User(format!(
+            "ERROR: MOD: Unrecognised signature \"{}\"; only 31-sample modules are supported",
+            String::from_utf8_lossy(signature)
+        ))),
+    }
+}
+
+fn text_of(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[0..end])
+        .trim_end()
+        .to_owned()
+}
+
+fn word_of(bytes: &[u8], offset: usize) -> usize {
+    ((bytes[offset] as usize) << 8 | bytes[offset + 1] as usize) * 2
+}
+
+/// Read the sample headers and signed 8-bit sample data into one buffer.
+/// Data missing from the end of a truncated file is left out.
+fn read_samples(bytes: &[u8], sample_data_offset: usize) -> (Vec<Option<ModSample>>, Vec<f32>) {
+    let mut samples: Vec<Option<ModSample>> = Vec::with_capacity(SAMPLE_COUNT);
+    let mut sample_buffer: Vec<f32> = vec![];
+    let mut data_offset = sample_data_offset;
+    for index in 0..SAMPLE_COUNT {
+        let header = TITLE_LENGTH + index * SAMPLE_HEADER_SIZE;
+        let declared_length = word_of(bytes, header + SAMPLE_NAME_LENGTH);
+        let finetune = ((bytes[header + 24] & 0x0F) << 4) as i8 >> 4;
+        let volume = bytes[header + 25].min(MAX_VOLUME);
+        let loop_start = word_of(bytes, header + 26);
+        let loop_length = word_of(bytes, header + 28);
+
+        let data_end = (data_offset + declared_length).min(bytes.len());
+        let data = &bytes[data_offset.min(data_end)..data_end];
+        data_offset += declared_length;
+        if data.len() < declared_length {
+            println!(
+                "WARNING: MOD: Sample {} is truncated from {} to {} bytes",
+                index + 1,
+                declared_length,
+                data.len()
+            );
+        }
+        if data.is_empty() {
+            samples.push(None);
+            continue;
+        }
+        let loop_end = (loop_start + loop_length).min(data.len());
+        let loop_range = match loop_length > 2 && loop_start < loop_end {
+            true => Some(LoopRange::new_frame_range(loop_start, loop_end)),
+            false => None,
+        };
+        samples.push(Some(ModSample {
+            volume,
+            finetune,
+            buffer_start_index: sample_buffer.len(),
+            length: data.len(),
+            loop_range,
+        }));
+        sample_buffer.extend(data.iter().map(|b| *b as i8 as f32 / 128.0));
+    }
+    (samples, sample_buffer)
+}
+
+/// MIDI note nearest to an Amiga period, where period 428 is C-2 and MIDI 60
+fn note_of_period(period: u16) -> u8 {
+    let semitones = 12.0 * (PERIOD_C1 / period as f64).log2();
+    (NOTE_C1 as f64 + semitones).round().clamp(0.0, 127.0) as u8
+}
+
+fn period_of_note(note: u8) -> f64 {
+    PERIOD_C1 * 2.0f64.powf(-((note as f64 - NOTE_C1 as f64) / 12.0))
+}
+
+/// Pattern break rows are written as decimal digits in hexadecimal
+fn break_row_of(param: u8) -> usize {
+    let row = (param >> 4) as usize * 10 + (param & 0x0F) as usize;
+    match row < ROWS_PER_PATTERN {
+        true => row,
+        false => 0,
+    }
+}
+
+/// Where a row's events begin, with the channel states it began with, which
+/// a jump back to the row restores
+struct RowStart {
+    tick: u64,
+    event_index: usize,
+    channels: Vec<ChannelState>,
+}
+
+/// Walks the module's positions and rows, turning cells into timed events
+struct ModConverter<'a> {
+    samples: &'a [Option<ModSample>],
+    channels: Vec<ChannelState>,
+    events: Vec<TimedEvent>,
+    tempo_changes: Vec<(u64, f64)>,
+    unsupported_effects: BTreeSet<String>,
+}
+
+impl<'a> ModConverter<'a> {
+    fn new(channel_count: usize, samples: &'a [Option<ModSample>]) -> Self {
+        Self {
+            samples,
+            channels: (0..channel_count)
+                .map(|_| ChannelState::default())
+                .collect(),
+            events: vec![],
+            tempo_changes: vec![(0, 60000000.0 / DEFAULT_TEMPO)],
+            unsupported_effects: BTreeSet::new(),
+        }
+    }
+
+    fn convert(&mut self, orders: &[u8], patterns: &[u8]) -> Result<(), Error> {
+        let channel_count = self.channels.len();
+        let pattern_size = ROWS_PER_PATTERN * channel_count * BYTES_PER_CELL;
+        let mut row_starts: HashMap<(usize, usize), RowStart> = HashMap::new();
+        let mut tick = 0u64;
+        let mut speed = DEFAULT_SPEED;
+        let (mut position, mut row) = (0usize, 0usize);
+        while position < orders.len() {
+            if let Some(row_start) = row_starts.get(&(position, row)) {
+                self.add_loop(row_start, tick);
+                return Ok(());
+            }
+            row_starts.insert(
+                (position, row),
+                RowStart {
+                    tick,
+                    event_index: self.events.len(),
+                    channels: self.channels.clone(),
+                },
+            );
+
+            let row_start =
+                orders[position] as usize * pattern_size + row * channel_count * BYTES_PER_CELL;
+            let cells: Vec<ModCell> = patterns
+                [row_start..row_start + channel_count * BYTES_PER_CELL]
+                .chunks(BYTES_PER_CELL)
+                .map(|cell| ModCell {
+                    sample: (cell[0] & 0xF0) | (cell[2] >> 4),
+                    period: ((cell[0] as u16 & 0x0F) << 8) | cell[1] as u16,
+                    effect: cell[2] & 0x0F,
+                    param: cell[3],
+                })
+                .collect();
+
+            let mut jump_position: Option<usize> = None;
+            let mut break_row: Option<usize> = None;
+            for cell in cells.iter() {
+                match cell.effect {
+                    EFFECT_SET_SPEED if cell.param == 0 => {}
+                    EFFECT_SET_SPEED if cell.param < 32 => speed = cell.param as u64,
+                    EFFECT_SET_SPEED => self
+                        .tempo_changes
+                        .push((tick, 60000000.0 / cell.param as f64)),
+                    EFFECT_POSITION_JUMP => jump_position = Some(cell.param as usize),
+                    EFFECT_PATTERN_BREAK => break_row = Some(break_row_of(cell.param)),
+                    _ => {}
+                }
+            }
+            for (channel, cell) in cells.into_iter().enumerate() {
+                self.convert_cell(channel, cell, tick, speed);
+            }
+            tick += speed;
+
+            (position, row) = match (jump_position, break_row) {
+                (Some(jump_position), break_row) => (jump_position, break_row.unwrap_or(0)),
+                (None, Some(break_row)) => (position + 1, break_row),
+                (None, None) if row + 1 < ROWS_PER_PATTERN => (position, row + 1),
+                (None, None) => (position + 1, 0),
+            };
+        }
+        for channel in 0..channel_count {
+            self.release_note(channel, tick);
+        }
+        Ok(())
+    }
+
+    /// Mark the row that playback jumps back to, and seek to it at the end of
+    /// the jumping row. Seeking restores programs and sounding notes, so the
+    /// row also restores the volume and pitch of each channel as they were
+    /// when it was first reached, rather than as the loop left them.
+    fn add_loop(&mut self, row_start: &RowStart, tick: u64) {
+        let restore_events: Vec<TimedEvent> = row_start
+            .channels
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.sample.is_some())
+            .flat_map(|(channel, state)| {
+                let volume = Event::Volume(state.volume as f32 / MAX_VOLUME as f32);
+                let pitch = state.note.map(|_| Event::PitchMultiplier(state.multiplier));
+                [Some(volume), pitch]
+                    .into_iter()
+                    .flatten()
+                    .map(move |data| TimedEvent {
+                        tick: row_start.tick,
+                        is_marker: false,
+                        channel,
+                        data,
+                    })
+            })
+            .collect();
+        self.events
+            .splice(row_start.event_index..row_start.event_index, restore_events);
+        let anchor = Anchor::Number(0);
+        self.events.push(TimedEvent {
+            tick: row_start.tick,
+            is_marker: true,
+            channel: 0,
+            data: Event::CueData(CueData::TargetMarker(anchor.clone())),
+        });
+        self.push(tick, 0, Event::CueData(CueData::SeekNowToTarget(anchor)));
+    }
+
+    fn convert_cell(&mut self, channel: usize, cell: ModCell, row_tick: u64, speed: u64) {
+        let (effect, param) = (cell.effect, cell.param);
+        let (x, y) = (param >> 4, param & 0x0F);
+        let is_tone_portamento =
+            effect == EFFECT_TONE_PORTAMENTO || effect == EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE;
+        if effect != EFFECT_ARPEGGIO || param == 0 {
+            self.update_pitch(channel, row_tick, 0);
+        }
+
+        let note_delay = match effect == EFFECT_EXTENDED && x == EXTENDED_NOTE_DELAY {
+            true => y as u64,
+            false => 0,
+        };
+        if note_delay < speed {
+            self.trigger_cell(channel, cell, row_tick + note_delay, is_tone_portamento);
+        }
+
+        let state = &mut self.channels[channel];
+        if effect == EFFECT_TONE_PORTAMENTO && param != 0 {
+            state.portamento_speed = param;
+        }
+        for row_tick_index in 0..speed {
+            let tick = row_tick + row_tick_index;
+            let is_first_tick = row_tick_index == 0;
+            match (effect, x) {
+                (EFFECT_ARPEGGIO, _) if param != 0 => {
+                    let semitones = [0, x, y][(row_tick_index % 3) as usize];
+                    self.update_pitch(channel, tick, semitones);
+                }
+                (EFFECT_ARPEGGIO, _) => {}
+                (EFFECT_PORTAMENTO_UP, _) if !is_first_tick => {
+                    self.slide_period(channel, tick, -(param as f64));
+                }
+                (EFFECT_PORTAMENTO_DOWN, _) if !is_first_tick => {
+                    self.slide_period(channel, tick, param as f64);
+                }
+                (EFFECT_TONE_PORTAMENTO, _) if !is_first_tick => {
+                    self.slide_to_target(channel, tick);
+                }
+                (EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE, _) if !is_first_tick => {
+                    self.slide_to_target(channel, tick);
+                    self.slide_volume(channel, tick, x, y);
+                }
+                (EFFECT_VOLUME_SLIDE, _) if !is_first_tick => {
+                    self.slide_volume(channel, tick, x, y);
+                }
+                (EFFECT_SET_VOLUME, _) if is_first_tick => {
+                    self.set_volume(channel, tick, param.min(MAX_VOLUME));
+                }
+                (EFFECT_EXTENDED, EXTENDED_FINE_PORTAMENTO_UP) if is_first_tick => {
+                    self.slide_period(channel, tick, -(y as f64));
+                }
+                (EFFECT_EXTENDED, EXTENDED_FINE_PORTAMENTO_DOWN) if is_first_tick => {
+                    self.slide_period(channel, tick, y as f64);
+                }
+                (EFFECT_EXTENDED, EXTENDED_FINE_VOLUME_UP) if is_first_tick => {
+                    self.slide_volume(channel, tick, y, 0);
+                }
+                (EFFECT_EXTENDED, EXTENDED_FINE_VOLUME_DOWN) if is_first_tick => {
+                    self.slide_volume(channel, tick, 0, y);
+                }
+                (EFFECT_EXTENDED, EXTENDED_NOTE_CUT) if row_tick_index == y as u64 => {
+                    self.set_volume(channel, tick, 0);
+                }
+                (EFFECT_EXTENDED, EXTENDED_NOTE_CUT | EXTENDED_NOTE_DELAY) => {}
+                (EFFECT_EXTENDED, _) if is_first_tick => {
+                    self.unsupported_effects.insert(format!("E{:X}", x));
+                }
+                (
+                    EFFECT_PORTAMENTO_UP
+                    | EFFECT_PORTAMENTO_DOWN
+                    | EFFECT_TONE_PORTAMENTO
+                    | EFFECT_TONE_PORTAMENTO_VOLUME_SLIDE
+                    | EFFECT_VOLUME_SLIDE
+                    | EFFECT_SET_VOLUME
+                    | EFFECT_EXTENDED
+                    | EFFECT_POSITION_JUMP
+                    | EFFECT_PATTERN_BREAK
+                    | EFFECT_SET_SPEED,
+                    _,
+                ) => {}
+                _ if is_first_tick => {
+                    self.unsupported_effects.insert(format!("{:X}", effect));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Select the cell's sample and play its note. A sample number resets
+    /// the volume to the sample's own, and takes effect from the next note;
+    /// under tone portamento, the note becomes the slide target instead.
+    fn trigger_cell(&mut self, channel: usize, cell: ModCell, tick: u64, is_tone_portamento: bool) {
+        if cell.sample != 0 {
+            let sample = cell.sample - 1;
+            let state = &mut self.channels[channel];
+            state.sample = Some(sample);
+            state.volume = self
+                .samples
+                .get(sample as usize)
+                .and_then(|sample| sample.as_ref())
+                .map_or(0, |sample| sample.volume);
+        }
+        let is_sliding = is_tone_portamento && self.channels[channel].note.is_some();
+        if cell.period == 0 || is_sliding {
+            if cell.period != 0 {
+                self.channels[channel].target_period = period_of_note(note_of_period(cell.period));
+            }
+            if cell.sample != 0 {
+                let volume = self.channels[channel].volume;
+                self.set_volume(channel, tick, volume);
+            }
+            return;
+        }
+
+        let note = note_of_period(cell.period);
+        self.release_note(channel, tick);
+        let state = &mut self.channels[channel];
+        if state.sample.is_some() && state.program != state.sample {
+            state.program = state.sample;
+            let program = state.sample.unwrap_or(0);
+            self.push(tick, channel, Event::ProgramChange(program));
+        }
+        let volume = self.channels[channel].volume;
+        self.set_volume(channel, tick, volume);
+        let state = &mut self.channels[channel];
+        state.note = Some(note);
+        state.note_period = period_of_note(note);
+        state.period = state.note_period;
+        state.target_period = state.note_period;
+        state.multiplier = 1.0;
+        let vel = (volume as f32 / MAX_VOLUME as f32).max(0.001);
+        self.push(tick, channel, Event::NoteOn { note, vel });
+    }
+
+    fn release_note(&mut self, channel: usize, tick: u64) {
+        if let Some(note) = self.channels[channel].note.take() {
+            self.push(tick, channel, Event::NoteOff { note, vel: 0.0 });
+        }
+    }
+
+    fn slide_period(&mut self, channel: usize, tick: u64, change: f64) {
+        let state = &mut self.channels[channel];
+        state.period = (state.period + change).clamp(MIN_PERIOD, MAX_PERIOD);
+        self.update_pitch(channel, tick, 0);
+    }
+
+    fn slide_to_target(&mut self, channel: usize, tick: u64) {
+        let state = &mut self.channels[channel];
+        let speed = state.portamento_speed as f64;
+        state.period = match state.period < state.target_period {
+            true => (state.period + speed).min(state.target_period),
+            false => (state.period - speed).max(state.target_period),
+        };
+        self.update_pitch(channel, tick, 0);
+    }
+
+    /// Send the pitch multiplier for the channel's period, raised by an
+    /// arpeggio offset, if it has changed
+    fn update_pitch(&mut self, channel: usize, tick: u64, semitones: u8) {
+        let state = &mut self.channels[channel];
+        if state.note.is_none() {
+            return;
+        }
+        let multiplier =
+            (state.note_period / state.period * 2.0f64.powf(semitones as f64 / 12.0)) as f32;
+        if (multiplier - state.multiplier).abs() < f32::EPSILON {
+            return;
+        }
+        state.multiplier = multiplier;
+        self.push(tick, channel, Event::PitchMultiplier(multiplier));
+    }
+
+    fn slide_volume(&mut self, channel: usize, tick: u64, up: u8, down: u8) {
+        let volume = self.channels[channel].volume;
+        let volume = match up {
+            0 => volume.saturating_sub(down),
+            up => (volume + up).min(MAX_VOLUME),
+        };
+        self.set_volume(channel, tick, volume);
+    }
+
+    fn set_volume(&mut self, channel: usize, tick: u64, volume: u8) {
+        self.channels[channel].volume = volume;
+        self.push(
+            tick,
+            channel,
+            Event::Volume(volume as f32 / MAX_VOLUME as f32),
+        );
+    }
+
+    fn push(&mut self, tick: u64, channel: usize, data: Event) {
+        self.events.push(TimedEvent {
+            tick,
+            is_marker: false,
+            channel,
+            data,
+        });
+    }
+
+    fn warn_unsupported_effects(&self) {
+        if self.unsupported_effects.is_empty() {
+            return;
+        }
+        let effects: Vec<&str> = self
+            .unsupported_effects
+            .iter()
+            .map(|effect| effect.as_str())
+            .collect();
+        println!(
+            "WARNING: MOD: Effects {} are not supported and will be ignored",
+            effects.join(", ")
+        );
+    }
+
+    /// Events sorted by tick, keeping the order they were added in at each
+    /// tick apart from markers
+    fn midi_events(&mut self) -> Vec<MidiEvent> {
+        let mut events = std::mem::take(&mut self.events);
+        events.sort_by_key(|event| (event.tick, !event.is_marker));
+        let mut previous_tick = 0u64;
+        events
+            .into_iter()
+            .map(|event| {
+                let delta_ticks = (event.tick - previous_tick) as isize;
+                previous_tick = event.tick;
+                MidiEvent {
+                    delta_ticks,
+                    channel: event.channel,
+                    message: Message::broadcast(event.data),
+                }
+            })
+            .collect()
+    }
+}
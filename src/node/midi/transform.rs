@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Reshaping of note-on velocities, from the 0 to 1 range into the same range
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum VelocityCurve {
+    #[default]
+    Unchanged,
+    /// Every note plays at this velocity
+    Fixed(f32),
+    /// Velocities are multiplied by this factor
+    Scaled(f32),
+    /// Velocities are raised to this power; above 1 softens quiet notes
+    /// further, below 1 brings them closer to loud ones
+    Exponential(f32),
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, vel: f32) -> f32 {
+        let vel = match self {
+            Self::Unchanged => vel,
+            Self::Fixed(fixed) => *fixed,
+            Self::Scaled(factor) => vel * factor,
+            Self::Exponential(exponent) => vel.powf(*exponent),
+        };
+        vel.clamp(0.0, 1.0)
+    }
+}
+
+/// Changes made to the notes of one MIDI channel before they reach the
+/// channel's source. Notes are remapped first, and then transposed.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct NoteTransform {
+    #[serde(default)]
+    pub transpose: i8,
+    #[serde(default)]
+    pub velocity: VelocityCurve,
+    #[serde(default)]
+    pub note_map: HashMap<u8, u8>,
+}
+
+impl NoteTransform {
+    /// Output note for a played note, or None if it falls outside the MIDI
+    /// note range after transposing
+    pub fn map_note(&self, note: u8) -> Option<u8> {
+        let note = self.note_map.get(&note).copied().unwrap_or(note);
+        let transposed = note as i16 + self.transpose as i16;
+        (0..=127).contains(&transposed).then_some(transposed as u8)
+    }
+}
@@ -1,14 +1,180 @@
 use crate::{
-    Balance, BaseMixer, FileAssetLoader,
+    AssetLoadPayload, AssetLoader, Balance, BaseMixer, Error, Event, EventTarget, EventTiming,
+    FileAssetLoader, GraphNode, LoopRange, Message, MessageSender, Node, NoteRange,
     config::{ChildConfig, NodeConfig},
-    generator::{SampleBufferSource, SampleLoop, SquareWave},
-    group::{Font, FontSource, RangeSource},
-    midi::{Midi, MidiDataSource},
+    consts,
+    effect::ModulationProperty,
+    generator::{
+        OneShotFileMetadata, OneShotNode, SampleBufferSource, SampleLoop, SampleLoopNode,
+        SquareWave, SquareWaveNode,
+    },
+    group::{
+        AdaptiveMusicNode, ArrangementsNode, Font, FontNodeBuilder, FontSource, MusicState,
+        MusicTransition, PolyphonyNode, RangeSource, TransitionTiming,
+    },
+    midi::{
+        Anchor, ChannelMix, ChannelNumbering, ChannelRouting, ChannelSelector, ControllerBinding,
+        ControllerCurve, ControllerMapper, ControllerSource, ControllerTarget, CueCondition,
+        CueData, GeneralMidi, GeneralMidiChannelNode, Groove, Midi, MidiDataSource, MidiEvent,
+        MidiInput, MidiNode, MidiNodeBuilder, MidiRecorderNode, MidiTextKind, NoteTransform,
+        ProgramBank, SeekPosition, SequenceEvent, SequenceEventData, SequenceTime, Stinger,
+        StingerAlignment, TempoMap, TrackerModule, VelocityCurve, midi_events_from_sequence,
+        parse_mml,
+    },
+    mix::base::{AudioCommand, ScheduledMessages},
+    node::{
+        generator::util::PitchBend, group::font::load_sf2_file, midi::event::RegisteredParameters,
+    },
+};
+use crossbeam_channel::Receiver;
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+    num::{u15, u28},
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use std::{collections::HashMap, time::Duration};
 
 const MIDI_FILE: &'static str = "resources/sample-in-c.mid";
 const WAV_FILE: &'static str = "resources/guitar-a2-48k-stereo.wav";
+const SF2_FILE: &str = "resources/demo-font.sf2";
+
+/// Events reaching a node, by the ID of the node they reached
+type EventLog = Arc<Mutex<Vec<(u64, Event)>>>;
+
+/// Node that keeps a shared log of the events reaching it and its duplicates
+struct EventLogNode {
+    node_id: u64,
+    log: EventLog,
+}
+
+impl EventLogNode {
+    fn new(log: &EventLog) -> Self {
+        Self {
+            node_id: <Self as Node>::new_node_id(),
+            log: log.clone(),
+        }
+    }
+}
+
+impl Node for EventLogNode {
+    fn get_node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: u64) {
+        self.node_id = node_id;
+    }
+
+    fn duplicate(&self) -> Result<GraphNode, Error> {
+        Ok(Box::new(Self::new(&self.log)))
+    }
+
+    fn try_consume_event(&mut self, event: &Message) -> bool {
+        self.log
+            .lock()
+            .unwrap()
+            .push((self.node_id, event.data.clone()));
+        true
+    }
+
+    fn propagate(&mut self, _event: &Message) {}
+
+    fn fill_buffer(&mut self, _buffer: &mut [f32]) {}
+
+    fn replace_children(&mut self, _children: &[GraphNode]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn get_state_snapshot(&self, _for_node_id: u64) -> Option<Result<Value, Error>> {
+        None
+    }
+}
+
+/// Node that logs the events reaching it by the frame they arrived at,
+/// counting the frames it has been asked to render
+struct FrameLogNode {
+    node_id: u64,
+    frames_rendered: u64,
+    log: EventLog,
+}
+
+impl FrameLogNode {
+    fn new(log: &EventLog) -> Self {
+        Self {
+            node_id: <Self as Node>::new_node_id(),
+            frames_rendered: 0,
+            log: log.clone(),
+        }
+    }
+}
+
+impl Node for FrameLogNode {
+    fn get_node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: u64) {
+        self.node_id = node_id;
+    }
+
+    fn duplicate(&self) -> Result<GraphNode, Error> {
+        Ok(Box::new(Self::new(&self.log)))
+    }
+
+    fn try_consume_event(&mut self, event: &Message) -> bool {
+        self.log
+            .lock()
+            .unwrap()
+            .push((self.frames_rendered, event.data.clone()));
+        true
+    }
+
+    fn propagate(&mut self, _event: &Message) {}
+
+    fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        self.frames_rendered += (buffer.len() / consts::CHANNEL_COUNT) as u64;
+    }
+
+    fn replace_children(&mut self, _children: &[GraphNode]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn get_state_snapshot(&self, _for_node_id: u64) -> Option<Result<Value, Error>> {
+        None
+    }
+}
+
+fn take_events(log: &EventLog) -> Vec<(u64, Event)> {
+    log.lock().unwrap().drain(..).collect()
+}
+
+/// Note events in the log, as the note and whether it turned on
+fn take_notes(log: &EventLog) -> Vec<(u8, bool)> {
+    take_events(log)
+        .into_iter()
+        .filter_map(|(_, event)| match event {
+            Event::NoteOn { note, vel } => Some((note, vel > 0.0)),
+            Event::NoteOff { note, .. } => Some((note, false)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn note_on(note: u8) -> Message {
+    Message::broadcast(Event::NoteOn { note, vel: 1.0 })
+}
+
+fn note_off(note: u8) -> Message {
+    Message::broadcast(Event::NoteOff { note, vel: 0.0 })
+}
+
+fn controller(controller: u8, value: u8) -> Message {
+    Message::broadcast(Event::Controller { controller, value })
+}
 
 fn wav_config_from_file() -> ChildConfig {
     ChildConfig(Box::new(SampleLoop {
@@ -23,18 +189,2161 @@ fn wav_config_from_file() -> ChildConfig {
 #[test]
 fn can_decode_midi_file() {
     let mut asset_loader = FileAssetLoader::default();
-    let midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::new(),
-    };
+        HashMap::new(),
+    );
     let midi_node_result = midi.to_node(&mut asset_loader);
     assert!(midi_node_result.is_ok());
 }
 
+#[test]
+fn midi_file_is_prepared_once_and_reused() {
+    let mut asset_loader = FileAssetLoader::default();
+    let midi = Midi {
+        other_channels: Some(SquareWave::stock()),
+        ..Midi::new(
+            None,
+            MidiDataSource::FilePath {
+                path: MIDI_FILE.to_owned(),
+                track_index: 0,
+            },
+            HashMap::new(),
+        )
+    };
+    let render = |asset_loader: &mut FileAssetLoader| {
+        let mut node = midi.to_node(asset_loader).unwrap();
+        let mut buffer = vec![0.0; 96000 * 2];
+        node.fill_buffer(&mut buffer);
+        buffer
+    };
+    let parsed_buffer = render(&mut asset_loader);
+    assert!(matches!(
+        asset_loader.load_asset_data(MIDI_FILE),
+        Ok(AssetLoadPayload::PreparedData(_))
+    ));
+    let prepared_buffer = render(&mut asset_loader);
+    assert!(parsed_buffer.iter().any(|sample| *sample != 0.0));
+    assert_eq!(parsed_buffer, prepared_buffer);
+}
+
+#[test]
+fn tempo_map_converts_across_tempo_changes() {
+    // 96 ticks per beat, 120 BPM from the start then 60 BPM from tick 192
+    let tempo_map = TempoMap::from_tempo_changes(96, &[(0, 500000.0), (192, 1000000.0)]);
+    assert_eq!(tempo_map.samples_at_tick(96), 24000.0);
+    assert_eq!(tempo_map.samples_at_tick(192), 48000.0);
+    assert_eq!(tempo_map.samples_at_tick(288), 96000.0);
+    assert_eq!(tempo_map.tick_at_samples(72000.0), 240.0);
+}
+
+#[test]
+fn tempo_map_finds_bar_and_beat() {
+    // 96 ticks per beat in 4/4 for two bars, then 3/4
+    let tempo_map =
+        TempoMap::from_tempo_changes(96, &[]).with_time_signatures(&[(0, 4, 4), (768, 3, 4)]);
+    assert_eq!(tempo_map.tick_at_bar_beat(1, 3.0), Some(192.0));
+    assert_eq!(tempo_map.tick_at_bar_beat(3, 1.0), Some(768.0));
+    assert_eq!(tempo_map.tick_at_bar_beat(4, 2.0), Some(1152.0));
+    assert_eq!(
+        tempo_map.tick_at_position(&SeekPosition::Seconds(1.0)),
+        Some(192)
+    );
+}
+
+fn controller_events(channel: usize, controllers: &[(u8, u8)]) -> Vec<MidiEvent> {
+    controllers
+        .iter()
+        .map(|(controller, value)| MidiEvent {
+            delta_ticks: 10,
+            channel,
+            message: Message::broadcast(Event::Controller {
+                controller: *controller,
+                value: *value,
+            }),
+        })
+        .collect()
+}
+
+fn pitch_bend_ranges(midi_events: &[MidiEvent]) -> Vec<(isize, f32)> {
+    midi_events
+        .iter()
+        .filter_map(|event| match event.message.data {
+            Event::PitchBendRange(range) => Some((event.delta_ticks, range)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn rpn_data_entry_sets_pitch_bend_range() {
+    let midi_events = controller_events(0, &[(101, 0), (100, 0), (6, 12), (38, 50)]);
+    let decoded = RegisteredParameters::default().decode_events(midi_events);
+    assert_eq!(pitch_bend_ranges(&decoded), vec![(10, 12.0), (10, 12.5)]);
+
+    // Each parameter change takes the place of its data entry message in time
+    assert_eq!(decoded.len(), 6);
+    assert!(matches!(
+        decoded[3].message.data,
+        Event::Controller {
+            controller: 6,
+            value: 12
+        }
+    ));
+    assert_eq!(decoded[3].delta_ticks, 0);
+}
+
+#[test]
+fn nrpn_selection_stops_data_entry_setting_pitch_bend_range() {
+    let midi_events = controller_events(3, &[(101, 0), (100, 0), (99, 1), (98, 2), (6, 24)]);
+    let decoded = RegisteredParameters::default().decode_events(midi_events);
+    assert_eq!(pitch_bend_ranges(&decoded), vec![]);
+}
+
+#[test]
+fn rpn_fine_data_entry_keeps_configured_pitch_bend_range() {
+    let midi_events = controller_events(0, &[(101, 0), (100, 0), (38, 25)]);
+    let decoded = RegisteredParameters::new(12.0).decode_events(midi_events.clone());
+    assert_eq!(pitch_bend_ranges(&decoded), vec![(10, 12.25)]);
+    let decoded = RegisteredParameters::default().decode_events(midi_events);
+    assert_eq!(pitch_bend_ranges(&decoded), vec![(10, 2.25)]);
+}
+
+#[test]
+fn pitch_bend_glides_to_new_positions() {
+    let mut pitch_bend = PitchBend::default();
+    pitch_bend.set_position(1.0);
+    let target = 2.0f32.powf(2.0 / 12.0);
+    assert!(pitch_bend.is_gliding());
+    let first_step = pitch_bend.advance(1);
+    assert!(first_step > 1.0 && first_step < target);
+
+    // Settles on the target exactly, after a few milliseconds
+    assert_eq!(pitch_bend.advance(4800), target);
+    assert!(!pitch_bend.is_gliding());
+
+    // A new range keeps the position, moving the target
+    pitch_bend.set_range(12.0);
+    assert!(pitch_bend.is_gliding());
+    assert_eq!(pitch_bend.advance(4800), 2.0);
+}
+
+#[test]
+fn controller_bindings_combine_volume() {
+    let mut mapper = ControllerMapper::new(ControllerBinding::general_midi()).unwrap();
+    let mut volumes = vec![];
+    let mut record_volume = |message: &Message| {
+        if let Event::Volume(volume) = message.data {
+            volumes.push(volume);
+        }
+    };
+    mapper.map_controller(0, 7, 127, &mut record_volume);
+    mapper.map_controller(0, 11, 0, &mut record_volume);
+    mapper.map_controller(1, 11, 127, &mut record_volume);
+    assert_eq!(volumes, vec![1.0, 0.0, 1.0]);
+}
+
+#[test]
+fn exponential_controller_binding_needs_positive_range() {
+    let binding = |min: f32| {
+        ControllerBinding::new(
+            ControllerSource::Controller(16),
+            ControllerTarget::Property(ModulationProperty::PitchMultiplier),
+            min,
+            2.0,
+        )
+        .with_curve(ControllerCurve::Exponential)
+    };
+    assert!(ControllerMapper::new(vec![binding(0.0)]).is_err());
+    assert!(ControllerMapper::new(vec![binding(-0.5)]).is_err());
+    let mapper = ControllerMapper::new(vec![binding(0.5)]).unwrap();
+    assert_eq!(mapper.bindings()[0].value_for(0.5), 1.0);
+}
+
+#[test]
+fn controller_binding_source_accepts_controller_key() {
+    let sources: Vec<ControllerSource> = [
+        r#"{"controller": 7, "target": {"Property": "Volume"}}"#,
+        r#"{"source": {"Controller": 7}, "target": {"Property": "Volume"}}"#,
+        r#"{"source": "ChannelPressure", "target": {"Property": "Volume"}}"#,
+    ]
+    .iter()
+    .map(|json| {
+        serde_json::from_str::<ControllerBinding>(json)
+            .unwrap()
+            .source
+    })
+    .collect();
+    assert_eq!(
+        sources,
+        vec![
+            ControllerSource::Controller(7),
+            ControllerSource::Controller(7),
+            ControllerSource::ChannelPressure,
+        ]
+    );
+}
+
+#[test]
+fn aftertouch_decodes_to_pressure_events() {
+    let channel = 3.into();
+    let key_pressure = TrackEventKind::Midi {
+        channel,
+        message: MidiMessage::Aftertouch {
+            key: 60.into(),
+            vel: 127.into(),
+        },
+    };
+    let channel_pressure = TrackEventKind::Midi {
+        channel,
+        message: MidiMessage::ChannelAftertouch { vel: 0.into() },
+    };
+    let key_pressure = MidiEvent::from_midi_event(5, &key_pressure).unwrap();
+    assert_eq!(key_pressure.channel, 3);
+    assert!(matches!(
+        key_pressure.message.data,
+        Event::KeyPressure {
+            note: 60,
+            pressure: 1.0
+        }
+    ));
+    let channel_pressure = MidiEvent::from_midi_event(0, &channel_pressure).unwrap();
+    assert!(matches!(
+        channel_pressure.message.data,
+        Event::ChannelPressure(0.0)
+    ));
+}
+
+#[test]
+fn channel_pressure_volume_combines_with_controllers() {
+    let mut bindings = ControllerBinding::general_midi();
+    bindings.push(ControllerBinding::new(
+        ControllerSource::ChannelPressure,
+        ControllerTarget::Property(ModulationProperty::Volume),
+        0.0,
+        1.0,
+    ));
+    let mut mapper = ControllerMapper::new(bindings).unwrap();
+    let mut volumes = vec![];
+    let mut record_volume = |message: &Message| {
+        if let Event::Volume(volume) = message.data {
+            volumes.push(volume);
+        }
+    };
+    mapper.apply_event(0, &Event::ChannelPressure(0.5), &mut record_volume);
+    mapper.map_controller(0, 7, 0, &mut record_volume);
+    mapper.map_controller(0, 7, 127, &mut record_volume);
+    assert_eq!(volumes, vec![0.5, 0.0, 0.5]);
+}
+
+#[test]
+fn key_pressure_volume_scales_channel_volume_per_voice() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let binding = ControllerBinding::new(
+        ControllerSource::KeyPressure,
+        ControllerTarget::Property(ModulationProperty::Volume),
+        0.0,
+        1.0,
+    );
+    let mut polyphony = PolyphonyNode::new(None, 2, Box::new(EventLogNode::new(&log)))
+        .unwrap()
+        .with_pressure_bindings(vec![binding.clone()])
+        .unwrap();
+    polyphony.on_event(&note_on(60));
+    polyphony.on_event(&note_on(64));
+    polyphony.on_event(&Message::broadcast(Event::Volume(0.5)));
+    polyphony.on_event(&Message::broadcast(Event::KeyPressure {
+        note: 64,
+        pressure: 0.5,
+    }));
+    let volumes: Vec<f32> = take_events(&log)
+        .into_iter()
+        .filter_map(|(_, event)| match event {
+            Event::Volume(volume) => Some(volume),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(volumes, vec![0.5, 0.5, 0.25]);
+
+    // Channel pressure is bound by the MIDI node instead
+    let channel_binding = ControllerBinding::new(
+        ControllerSource::ChannelPressure,
+        ControllerTarget::Property(ModulationProperty::Volume),
+        0.0,
+        1.0,
+    );
+    let polyphony = PolyphonyNode::new(None, 2, Box::new(EventLogNode::new(&log)))
+        .unwrap()
+        .with_pressure_bindings(vec![binding, channel_binding]);
+    assert!(polyphony.is_err());
+}
+
+/// Live input sending channel 0 to node 7, and the commands it sends
+fn detached_midi_input() -> (MidiInput, Receiver<AudioCommand>) {
+    let (sender, receiver) = MessageSender::detached();
+    let input = MidiInput::new(Arc::new(sender)).add_channel_target(0, 7);
+    (input, receiver)
+}
+
+/// Notes sent by a live input, as the frame, note and whether it turned on
+fn sent_notes(receiver: &Receiver<AudioCommand>) -> Vec<(u64, u8, bool)> {
+    receiver
+        .try_iter()
+        .filter_map(|command| match command {
+            AudioCommand::GraphMessage(message) => Some(message),
+            _ => None,
+        })
+        .filter_map(|message| {
+            assert_eq!(message.target, EventTarget::SpecificNode(7));
+            let EventTiming::AtAbsoluteFrame(frame) = message.timing else {
+                panic!("Live input messages are scheduled");
+            };
+            match message.data {
+                Event::NoteOn { note, vel } => Some((frame, note, vel > 0.0)),
+                Event::NoteOff { note, .. } => Some((frame, note, false)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn live_input_follows_running_status() {
+    let (mut input, receiver) = detached_midi_input();
+    input
+        .push_bytes_at(&[0x90, 60, 100, 64, 100, 60, 0], 10)
+        .unwrap();
+    // Channel 1 has no target node
+    input
+        .push_bytes_at(&[0x91, 60, 100, 0x80, 64, 0], 20)
+        .unwrap();
+    assert_eq!(
+        sent_notes(&receiver),
+        vec![
+            (10, 60, true),
+            (10, 64, true),
+            (10, 60, false),
+            (20, 64, false)
+        ]
+    );
+}
+
+#[test]
+fn live_input_skips_realtime_and_sysex_bytes() {
+    let (mut input, receiver) = detached_midi_input();
+    // Timing clock bytes may arrive between the bytes of a message
+    input
+        .push_bytes_at(&[0x90, 0xF8, 60, 0xFA, 100, 0xF8, 62, 100], 0)
+        .unwrap();
+    input
+        .push_bytes_at(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7, 0x80, 60, 0], 0)
+        .unwrap();
+    assert_eq!(
+        sent_notes(&receiver),
+        vec![(0, 60, true), (0, 62, true), (0, 60, false)]
+    );
+}
+
+#[test]
+fn live_input_routes_channels_by_table() {
+    let (sender, receiver) = MessageSender::detached();
+    let routing = ChannelRouting {
+        numbering: ChannelNumbering::OneBased,
+        destinations: vec![
+            ChannelSelector::Channels(vec![1, 2]),
+            ChannelSelector::AllOthers,
+        ],
+    };
+    let mut input = MidiInput::new(Arc::new(sender))
+        .set_channel_routing(&routing, &[7, 9])
+        .unwrap();
+    // Note-ons on zero-based channels 0, 1 and 5
+    input
+        .push_bytes_at(&[0x90, 60, 100, 0x91, 62, 100, 0x95, 64, 100], 0)
+        .unwrap();
+    let targets: Vec<(EventTarget, u8)> = receiver
+        .try_iter()
+        .filter_map(|command| match command {
+            AudioCommand::GraphMessage(Message {
+                target,
+                data: Event::NoteOn { note, .. },
+                ..
+            }) => Some((target, note)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        targets,
+        vec![
+            (EventTarget::SpecificNode(7), 60),
+            (EventTarget::SpecificNode(7), 62),
+            (EventTarget::SpecificNode(9), 64)
+        ]
+    );
+
+    let (sender, _) = MessageSender::detached();
+    let result = MidiInput::new(Arc::new(sender)).set_channel_routing(&routing, &[7]);
+    assert!(matches!(result, Err(Error::User(_))));
+}
+
+#[test]
+fn live_input_joins_messages_split_across_pushes() {
+    let (mut input, receiver) = detached_midi_input();
+    input.push_bytes_at(&[0x90, 60], 10).unwrap();
+    assert_eq!(sent_notes(&receiver), vec![]);
+    input.push_bytes_at(&[100, 62], 20).unwrap();
+    input.push_bytes_at(&[100], 30).unwrap();
+    assert_eq!(sent_notes(&receiver), vec![(20, 60, true), (30, 62, true)]);
+}
+
+#[test]
+fn scheduled_messages_keep_send_order_within_a_frame() {
+    let mut messages = ScheduledMessages::default();
+    for (note, frame) in [(60, 5), (61, 3), (62, 5), (63, 5)] {
+        messages.push(
+            EventTarget::Broadcast,
+            Event::NoteOn { note, vel: 1.0 },
+            frame,
+        );
+    }
+    let mut take_due = |frame: u64| {
+        std::iter::from_fn(|| messages.pop_due(frame))
+            .filter_map(|(_, data)| match data {
+                Event::NoteOn { note, .. } => Some(note),
+                _ => None,
+            })
+            .collect::<Vec<u8>>()
+    };
+    assert_eq!(take_due(4), vec![61]);
+    assert_eq!(take_due(5), vec![60, 62, 63]);
+}
+
+#[test]
+fn sustain_defers_note_offs_until_released() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut polyphony = PolyphonyNode::new(None, 4, Box::new(EventLogNode::new(&log))).unwrap();
+    polyphony.on_event(&controller(64, 127));
+    polyphony.on_event(&note_on(60));
+    polyphony.on_event(&note_off(60));
+    assert_eq!(take_notes(&log), vec![(60, true)]);
+    polyphony.on_event(&controller(64, 0));
+    assert_eq!(take_notes(&log), vec![(60, false)]);
+}
+
+#[test]
+fn sostenuto_holds_only_notes_down_when_pressed() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut polyphony = PolyphonyNode::new(None, 4, Box::new(EventLogNode::new(&log))).unwrap();
+    polyphony.on_event(&note_on(60));
+    polyphony.on_event(&controller(66, 127));
+    polyphony.on_event(&note_on(64));
+    polyphony.on_event(&note_off(60));
+    polyphony.on_event(&note_off(64));
+    assert_eq!(take_notes(&log), vec![(60, true), (64, true), (64, false)]);
+    polyphony.on_event(&controller(66, 0));
+    assert_eq!(take_notes(&log), vec![(60, false)]);
+}
+
+#[test]
+fn note_struck_again_under_pedal_restarts() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut polyphony = PolyphonyNode::new(None, 4, Box::new(EventLogNode::new(&log))).unwrap();
+    polyphony.on_event(&controller(64, 127));
+    polyphony.on_event(&note_on(60));
+    polyphony.on_event(&note_off(60));
+    polyphony.on_event(&note_on(60));
+    assert_eq!(take_notes(&log), vec![(60, true), (60, false), (60, true)]);
+
+    // The new note's key is still down, so releasing the pedal keeps it
+    polyphony.on_event(&controller(64, 0));
+    assert_eq!(take_notes(&log), vec![]);
+}
+
+#[test]
+fn pedal_held_voices_are_stolen_oldest_first() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut polyphony = PolyphonyNode::new(None, 2, Box::new(EventLogNode::new(&log))).unwrap();
+    polyphony.on_event(&controller(64, 127));
+    for note in [60, 62] {
+        polyphony.on_event(&note_on(note));
+        polyphony.on_event(&note_off(note));
+    }
+    take_notes(&log);
+    polyphony.on_event(&note_on(64));
+    polyphony.on_event(&note_on(65));
+    assert_eq!(
+        take_notes(&log),
+        vec![(60, false), (64, true), (62, false), (65, true)]
+    );
+
+    // With every key down, there is no voice to take
+    polyphony.on_event(&note_on(67));
+    assert_eq!(take_notes(&log), vec![]);
+}
+
+#[test]
+fn program_bank_falls_back_to_channel_default() {
+    let mut asset_loader = FileAssetLoader::default();
+    let mut square_wave = |node_id: u64| {
+        let config = SquareWave {
+            node_id: Some(node_id),
+            balance: Balance::Both,
+            amplitude: 0.25,
+            duty_cycle: 0.125,
+        };
+        config.to_node(&mut asset_loader).unwrap()
+    };
+    let mut bank = ProgramBank::new(HashMap::from([(5, square_wave(2))]));
+    let active = bank.select(5, Some(square_wave(1)));
+    assert_eq!(active.as_ref().map(|node| node.get_node_id()), Some(2));
+    let active = bank.select(5, active);
+    assert_eq!(active.as_ref().map(|node| node.get_node_id()), Some(2));
+    let active = bank.select(9, active);
+    assert_eq!(active.as_ref().map(|node| node.get_node_id()), Some(1));
+}
+
+#[test]
+fn program_change_lets_held_notes_finish_and_replays_channel_state() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let default_source = EventLogNode::new(&log);
+    let program_source = EventLogNode::new(&log);
+    let (default_id, program_id) = (default_source.node_id, program_source.node_id);
+    let events = [
+        (
+            0,
+            Event::Controller {
+                controller: 7,
+                value: 127,
+            },
+        ),
+        (0, Event::PitchBend(0.5)),
+        (0, Event::NoteOn { note: 60, vel: 1.0 }),
+        (10, Event::ProgramChange(5)),
+        (10, Event::NoteOff { note: 60, vel: 0.0 }),
+        (10, Event::NoteOn { note: 62, vel: 1.0 }),
+        (10, Event::NoteOff { note: 62, vel: 0.0 }),
+    ];
+    let midi_events = events
+        .into_iter()
+        .map(|(delta_ticks, data)| MidiEvent {
+            delta_ticks,
+            channel: 0,
+            message: Message::broadcast(data),
+        })
+        .collect();
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(0, Box::new(default_source))
+        .add_channel_program(0, 5, Box::new(program_source))
+        .build()
+        .unwrap();
+    take_events(&log);
+    let mut buffer = vec![0.0; 5000 * 2];
+    node.fill_buffer(&mut buffer);
+    let events = take_events(&log);
+    let notes_for = |node_id: u64| {
+        events
+            .iter()
+            .filter(|(id, _)| *id == node_id)
+            .filter_map(|(_, event)| match event {
+                Event::NoteOn { note, .. } => Some((*note, true)),
+                Event::NoteOff { note, .. } => Some((*note, false)),
+                _ => None,
+            })
+            .collect::<Vec<(u8, bool)>>()
+    };
+
+    // The note held over the program change is released where it started
+    assert_eq!(notes_for(default_id), vec![(60, true), (60, false)]);
+    assert_eq!(notes_for(program_id), vec![(62, true), (62, false)]);
+    assert!(
+        !events
+            .iter()
+            .any(|(_, event)| matches!(event, Event::AllNotesOff))
+    );
+
+    // The new instrument hears the volume and bend before its first note
+    let program_events: Vec<&Event> = events
+        .iter()
+        .filter(|(id, _)| *id == program_id)
+        .map(|(_, event)| event)
+        .collect();
+    let first_note = program_events
+        .iter()
+        .position(|event| matches!(event, Event::NoteOn { .. }))
+        .unwrap();
+    let before_note = &program_events[0..first_note];
+    assert!(
+        before_note
+            .iter()
+            .any(|event| matches!(event, Event::Volume(volume) if *volume == 1.0))
+    );
+    assert!(
+        before_note
+            .iter()
+            .any(|event| matches!(event, Event::PitchBend(bend) if *bend == 0.5))
+    );
+}
+
+#[test]
+fn seek_chases_program_controllers_and_sounding_notes() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let program_source = EventLogNode::new(&log);
+    let program_id = program_source.node_id;
+    let mut events = vec![
+        (0, Event::ProgramChange(5)),
+        (
+            0,
+            Event::Controller {
+                controller: 10,
+                value: 20,
+            },
+        ),
+        (0, Event::NoteOn { note: 60, vel: 1.0 }),
+    ];
+    // Enough events that seeks scan on from a recorded state
+    events.extend((0..300).map(|index| {
+        (
+            1,
+            Event::Controller {
+                controller: 74,
+                value: (index % 128) as u8,
+            },
+        )
+    }));
+    events.extend([
+        (0, Event::NoteOn { note: 62, vel: 0.5 }),
+        (10, Event::NoteOff { note: 60, vel: 0.0 }),
+        (10, Event::NoteOff { note: 62, vel: 0.0 }),
+    ]);
+    let midi_events = events
+        .into_iter()
+        .map(|(delta_ticks, data)| MidiEvent {
+            delta_ticks,
+            channel: 0,
+            message: Message::broadcast(data),
+        })
+        .collect();
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(0, Box::new(EventLogNode::new(&log)))
+        .add_channel_program(0, 5, Box::new(program_source))
+        .build()
+        .unwrap();
+    take_events(&log);
+    let program_events = |log: &EventLog| {
+        take_events(log)
+            .into_iter()
+            .filter(|(id, _)| *id == program_id)
+            .map(|(_, event)| event)
+            .collect::<Vec<Event>>()
+    };
+    let notes = |events: &[Event]| {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::NoteOn { note, .. } => Some((*note, true)),
+                Event::NoteOff { note, .. } => Some((*note, false)),
+                _ => None,
+            })
+            .collect::<Vec<(u8, bool)>>()
+    };
+
+    node.on_event(&Message::broadcast(Event::Seek(SeekPosition::Tick(305))));
+    let chased = program_events(&log);
+    assert_eq!(notes(&chased), vec![(60, true), (62, true)]);
+    for (expected_controller, expected_value) in [(10, 20), (74, (299 % 128) as u8)] {
+        assert!(chased.iter().any(|event| matches!(
+            event,
+            Event::Controller { controller, value }
+                if *controller == expected_controller && *value == expected_value
+        )));
+    }
+
+    // Notes sounding at the seek are released before the target's notes play
+    node.on_event(&Message::broadcast(Event::Seek(SeekPosition::Tick(315))));
+    assert_eq!(
+        notes(&program_events(&log)),
+        vec![(60, false), (62, false), (62, true)]
+    );
+}
+
+#[test]
+fn cue_labels_parse_loops_conditions_and_random_targets() {
+    let cues = CueData::from_label(b"#intro ?{combat}>4 >2*3 >(5|chorus)").unwrap();
+    assert_eq!(
+        cues,
+        vec![
+            CueData::TargetMarker("intro".into()),
+            CueData::GoodPointToSeekFrom,
+            CueData::Conditional {
+                condition: CueCondition::IsSet("combat".to_owned()),
+                cue: Box::new(CueData::SeekNowToTarget(4.into())),
+            },
+            CueData::LoopToTarget {
+                target: 2.into(),
+                times: 3,
+            },
+            CueData::SeekToRandomTarget(vec![5.into(), "chorus".into()]),
+        ]
+    );
+    let labels: Vec<String> = cues.iter().filter_map(CueData::to_label).collect();
+    assert_eq!(labels.concat(), "#intro?{combat}>4>2*3>(5|chorus)");
+
+    let Err(Error::User(message)) = CueData::from_label(b"#1>(2|)") else {
+        panic!("Expected a parse error");
+    };
+    assert!(message.contains("\"#1>(2|)\"") && message.contains("character 7"));
+}
+
+#[test]
+fn channel_mix_solos_and_fades_channels() {
+    let mut mix = ChannelMix::new();
+    let fill_ones = |buffer: &mut [f32]| buffer.iter_mut().for_each(|sample| *sample += 1.0);
+    mix.set_soloed(1, true, 0.0);
+    let mut buffer = vec![0.0; 256];
+    mix.fill_channel(0, &mut buffer, fill_ones);
+    assert!(buffer.iter().all(|sample| *sample == 0.0));
+    mix.fill_channel(1, &mut buffer, fill_ones);
+    assert!(buffer.iter().all(|sample| *sample == 1.0));
+
+    // Fading out over 128 frames reaches silence at the end of the buffer
+    mix.set_volume(1, 0.0, 128.0 / 48000.0);
+    let mut buffer = vec![0.0; 256];
+    mix.fill_channel(1, &mut buffer, fill_ones);
+    assert!(buffer[0] < 1.0 && buffer[0] > 0.9);
+    assert_eq!(buffer[255], 0.0);
+
+    mix.set_soloed(1, false, 0.0);
+    // Only channels that differ from the defaults are saved
+    let settings = mix.settings();
+    assert_eq!(settings.len(), 1);
+    assert_eq!(settings[0].channel, 1);
+    assert_eq!(settings[0].volume, 0.0);
+    assert!(!settings[0].soloed);
+}
+
+#[test]
+fn channel_mix_snapshot_keeps_fade_in_progress() {
+    let mut mix = ChannelMix::new();
+    let fill_ones = |buffer: &mut [f32]| buffer.iter_mut().for_each(|sample| *sample += 1.0);
+    mix.set_volume(0, 0.0, 256.0 / 48000.0);
+    let mut buffer = vec![0.0; 256];
+    mix.fill_channel(0, &mut buffer, fill_ones);
+    let settings = mix.settings();
+    let fade = settings[0].fade.as_ref().unwrap();
+    assert_eq!(fade.frames_remaining, 128);
+
+    let mut restored = ChannelMix::new();
+    restored.restore_settings(&settings);
+    // Longer than the mix's own buffer, so rendered in parts
+    let mut buffer = vec![0.0; consts::BUFFER_SIZE * consts::CHANNEL_COUNT * 2];
+    restored.fill_channel(0, &mut buffer, fill_ones);
+    assert!(buffer[0] > 0.45 && buffer[0] < 0.5);
+    assert_eq!(buffer[255], 0.0);
+    assert!(restored.settings()[0].fade.is_none());
+}
+
+#[test]
+fn note_transform_remaps_then_transposes() {
+    let transform = NoteTransform {
+        transpose: 12,
+        velocity: VelocityCurve::Exponential(2.0),
+        note_map: HashMap::from([(36, 35)]),
+    };
+    assert_eq!(transform.map_note(36), Some(47));
+    assert_eq!(transform.map_note(60), Some(72));
+    assert_eq!(transform.map_note(120), None);
+    assert_eq!(transform.velocity.apply(0.5), 0.25);
+    assert_eq!(VelocityCurve::Scaled(3.0).apply(0.5), 1.0);
+    assert_eq!(VelocityCurve::Fixed(0.75).apply(0.1), 0.75);
+}
+
+#[test]
+fn note_off_reaches_note_played_before_transpose_changed() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let events = [
+        (0, Event::NoteOn { note: 60, vel: 1.0 }),
+        (480, Event::NoteOff { note: 60, vel: 0.0 }),
+        // Released without being played, so not tracked
+        (0, Event::NoteOff { note: 64, vel: 0.0 }),
+    ];
+    let midi_events = events
+        .into_iter()
+        .map(|(delta_ticks, data)| MidiEvent {
+            delta_ticks,
+            channel: 0,
+            message: Message::broadcast(data),
+        })
+        .collect();
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(0, Box::new(EventLogNode::new(&log)))
+        .build()
+        .unwrap();
+    let transpose = |semitones: i8| {
+        Message::broadcast(Event::Transpose {
+            channel: 0,
+            semitones,
+        })
+    };
+    node.on_event(&transpose(12));
+    let mut buffer = vec![0.0; 256];
+    node.fill_buffer(&mut buffer);
+    node.on_event(&transpose(5));
+    let mut buffer = vec![0.0; 48000 * 2];
+    node.fill_buffer(&mut buffer);
+    assert_eq!(take_notes(&log), vec![(72, true), (72, false), (64, false)]);
+}
+
+#[test]
+fn events_within_one_buffer_land_on_their_frames() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let midi_events = [60, 62, 64, 65]
+        .into_iter()
+        .enumerate()
+        .map(|(index, note)| MidiEvent {
+            delta_ticks: match index {
+                0 => 0,
+                _ => 10,
+            },
+            channel: 0,
+            message: Message::broadcast(Event::NoteOn { note, vel: 1.0 }),
+        })
+        .collect();
+    // At 120 BPM with 480 ticks per beat, a tick lasts 50 frames
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(0, Box::new(FrameLogNode::new(&log)))
+        .build()
+        .unwrap();
+    take_events(&log);
+    let mut buffer = vec![0.0; 2048 * consts::CHANNEL_COUNT];
+    node.fill_buffer(&mut buffer);
+    let note_frames: Vec<(u64, u8)> = take_events(&log)
+        .into_iter()
+        .filter_map(|(frame, event)| match event {
+            Event::NoteOn { note, .. } => Some((frame, note)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        note_frames,
+        vec![(0, 60), (500, 62), (1000, 64), (1500, 65)]
+    );
+}
+
+#[test]
+fn final_event_of_sequence_is_played() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let midi_events = [
+        (0, Event::NoteOn { note: 60, vel: 1.0 }),
+        (480, Event::NoteOff { note: 60, vel: 0.0 }),
+    ]
+    .into_iter()
+    .map(|(delta_ticks, data)| MidiEvent {
+        delta_ticks,
+        channel: 0,
+        message: Message::broadcast(data),
+    })
+    .collect();
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(0, Box::new(EventLogNode::new(&log)))
+        .build()
+        .unwrap();
+    let mut buffer = vec![0.0; 48000 * 2];
+    node.fill_buffer(&mut buffer);
+    assert!(node.has_finished());
+    assert_eq!(take_notes(&log), vec![(60, true), (60, false)]);
+}
+
+#[test]
+fn groove_swings_and_quantizes_notes() {
+    let note_at = |delta_ticks: isize, note: u8| MidiEvent {
+        delta_ticks,
+        channel: 0,
+        message: Message::broadcast(Event::NoteOn { note, vel: 0.5 }),
+    };
+    let events = vec![note_at(0, 60), note_at(240, 62), note_at(250, 64)];
+    let groove = Groove {
+        swing: 0.5,
+        quantize: Some(4),
+        ..Groove::default()
+    };
+    let (_, ticks) = groove.apply(&events, 480);
+    assert_eq!(ticks, vec![0, 360, 480]);
+
+    let humanized = Groove {
+        humanize_timing: 0.1,
+        humanize_velocity: 0.2,
+        seed: 7,
+        ..Groove::default()
+    };
+    let (first_events, first_ticks) = humanized.apply(&events, 480);
+    let (second_events, second_ticks) = humanized.apply(&events, 480);
+    assert_eq!(first_ticks, second_ticks);
+    assert!(first_ticks.windows(2).all(|pair| pair[0] <= pair[1]));
+    for (first, second) in first_events.iter().zip(second_events.iter()) {
+        let (Event::NoteOn { vel: a, .. }, Event::NoteOn { vel: b, .. }) =
+            (&first.message.data, &second.message.data)
+        else {
+            panic!("Expected note events");
+        };
+        assert_eq!(a, b);
+        assert!((a - 0.5).abs() <= 0.2);
+    }
+}
+
+#[test]
+fn groove_reorders_swung_notes_past_other_channels() {
+    // Channel 0 plays off-beats, which swing delays; channel 1 plays on the
+    // beat and moves a controller between
+    let events = [
+        (0, 1, Event::NoteOn { note: 48, vel: 0.5 }),
+        (240, 0, Event::NoteOn { note: 62, vel: 0.5 }),
+        (
+            60,
+            1,
+            Event::Controller {
+                controller: 1,
+                value: 64,
+            },
+        ),
+        (120, 0, Event::NoteOff { note: 62, vel: 0.0 }),
+        (60, 1, Event::NoteOff { note: 48, vel: 0.0 }),
+    ];
+    let events: Vec<MidiEvent> = events
+        .into_iter()
+        .map(|(delta_ticks, channel, data)| MidiEvent {
+            delta_ticks,
+            channel,
+            message: Message::broadcast(data),
+        })
+        .collect();
+    let groove = Groove {
+        swing: 0.5,
+        ..Groove::default()
+    };
+    let (grooved, ticks) = groove.apply(&events, 480);
+    assert_eq!(ticks, vec![0, 300, 360, 450, 480]);
+    let channels: Vec<usize> = grooved.iter().map(|event| event.channel).collect();
+    assert_eq!(channels, vec![1, 1, 0, 0, 1]);
+    let deltas: Vec<isize> = grooved.iter().map(|event| event.delta_ticks).collect();
+    assert_eq!(deltas, vec![0, 300, 60, 90, 30]);
+}
+
+#[test]
+fn groove_during_playback_chases_notes_moved_before_playhead() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let midi_events = [(0, 60), (350, 62)]
+        .into_iter()
+        .map(|(delta_ticks, note)| MidiEvent {
+            delta_ticks,
+            channel: 0,
+            message: Message::broadcast(Event::NoteOn { note, vel: 1.0 }),
+        })
+        .collect();
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(0, Box::new(EventLogNode::new(&log)))
+        .build()
+        .unwrap();
+    // Play to tick 302
+    let mut buffer = vec![0.0; 302 * 50 * 2];
+    node.fill_buffer(&mut buffer);
+    assert_eq!(take_notes(&log), vec![(60, true)]);
+
+    // Quantising to eighths moves the second note back to tick 240
+    node.on_event(&Message::broadcast(Event::Groove(Groove {
+        quantize: Some(2),
+        ..Groove::default()
+    })));
+    let mut notes = take_notes(&log);
+    assert_eq!(notes.remove(0), (60, false));
+    notes.sort();
+    assert_eq!(notes, vec![(60, true), (62, true)]);
+    assert!(node.has_finished());
+    node.fill_buffer(&mut buffer);
+    assert!(take_notes(&log).is_empty());
+}
+
+/// Notes reaching each child of a MIDI node, with the children playing the
+/// given routing table, or their own index's channel without one
+fn notes_by_child(
+    mut node: MidiNode,
+    routing: Option<ChannelRouting>,
+    child_count: usize,
+) -> Result<Vec<Vec<(u8, bool)>>, Error> {
+    let logs: Vec<EventLog> = (0..child_count)
+        .map(|_| Arc::new(Mutex::new(vec![])))
+        .collect();
+    let children: Vec<GraphNode> = logs
+        .iter()
+        .map(|log| Box::new(EventLogNode::new(log)) as GraphNode)
+        .collect();
+    if let Some(routing) = routing {
+        node.set_child_routing(routing);
+    }
+    node.replace_children(&children)?;
+    let mut buffer = vec![0.0; 256];
+    node.fill_buffer(&mut buffer);
+    Ok(logs.iter().map(take_notes).collect())
+}
+
+#[test]
+fn channel_routing_shares_sources_and_falls_back() {
+    let note_on_channels = |channels: &[usize]| {
+        let midi_events = channels
+            .iter()
+            .map(|channel| MidiEvent {
+                delta_ticks: 0,
+                channel: *channel,
+                message: Message::broadcast(Event::NoteOn {
+                    note: 60 + *channel as u8,
+                    vel: 1.0,
+                }),
+            })
+            .collect();
+        let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+        MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+            .build()
+            .unwrap()
+    };
+    let routing = ChannelRouting {
+        numbering: ChannelNumbering::OneBased,
+        destinations: vec![
+            ChannelSelector::Channels(vec![1]),
+            ChannelSelector::Channels(vec![10, 11]),
+            ChannelSelector::AllOthers,
+        ],
+    };
+    let notes = notes_by_child(note_on_channels(&[0, 9, 10, 4]), Some(routing), 3).unwrap();
+    assert_eq!(
+        notes,
+        vec![
+            vec![(60, true)],
+            vec![(69, true), (70, true)],
+            vec![(64, true)]
+        ]
+    );
+
+    // Without a routing table, children play zero-based channels by index
+    let notes = notes_by_child(note_on_channels(&[0, 1, 2]), None, 2).unwrap();
+    assert_eq!(notes, vec![vec![(60, true)], vec![(61, true)]]);
+
+    let out_of_range = ChannelRouting {
+        numbering: ChannelNumbering::OneBased,
+        destinations: vec![ChannelSelector::Channels(vec![0])],
+    };
+    assert!(notes_by_child(note_on_channels(&[0]), Some(out_of_range), 1).is_err());
+}
+
+#[test]
+fn one_based_numbering_applies_to_transforms() {
+    let json = r#"{ "Events": { "events": [
+        { "time": { "Tick": 0 }, "channel": 0, "data": { "NoteOn": { "note": 60, "vel": 1.0 } } }
+    ] } }"#;
+    let source = serde_json::from_str::<MidiDataSource>(json).unwrap();
+    let config = Midi {
+        channel_numbering: ChannelNumbering::OneBased,
+        transforms: HashMap::from([(
+            1,
+            NoteTransform {
+                transpose: 12,
+                ..NoteTransform::default()
+            },
+        )]),
+        ..Midi::new(None, source, HashMap::new())
+    };
+    let node = config
+        .to_midi_node(&mut FileAssetLoader::default())
+        .unwrap();
+    let notes = notes_by_child(node, None, 1).unwrap();
+    assert_eq!(notes, vec![vec![(72, true)]]);
+}
+
+#[test]
+fn text_events_reach_subscribers() {
+    let meta_at = |delta: u32, message: MetaMessage<'static>| TrackEvent {
+        delta: u28::new(delta),
+        kind: TrackEventKind::Meta(message),
+    };
+    let smf = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))),
+        tracks: vec![vec![
+            meta_at(0, MetaMessage::TrackName(b"Theme")),
+            meta_at(0, MetaMessage::CuePoint(b"#1")),
+            meta_at(240, MetaMessage::Lyric(b"Hel")),
+            meta_at(240, MetaMessage::Marker(b"Verse")),
+            // Prose that starts like a cue label stays text
+            meta_at(0, MetaMessage::Marker(b"# Chorus")),
+            meta_at(480, MetaMessage::EndOfTrack),
+        ]],
+    };
+    let mut node = MidiNodeBuilder::new(None, smf, 0).unwrap().build().unwrap();
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    node.on_event(&Message::broadcast(Event::SubscribeText(sender)));
+    let mut buffer = vec![0.0; 48000 * 2];
+    node.fill_buffer(&mut buffer);
+    let texts: Vec<(MidiTextKind, String, u64)> = receiver
+        .try_iter()
+        .map(|text| (text.kind, text.text, text.tick))
+        .collect();
+    assert_eq!(
+        texts,
+        vec![
+            (MidiTextKind::TrackName, "Theme".to_owned(), 0),
+            (MidiTextKind::Lyric, "Hel".to_owned(), 240),
+            (MidiTextKind::Marker, "Verse".to_owned(), 480),
+            (MidiTextKind::Marker, "# Chorus".to_owned(), 480),
+        ]
+    );
+}
+
+#[test]
+fn sources_without_events_finish_immediately() {
+    let smf = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))),
+        tracks: vec![vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        }]],
+    };
+    let mut bytes = vec![];
+    smf.write_std(&mut bytes).unwrap();
+    let sources = [
+        MidiDataSource::Events {
+            ticks_per_beat: 480,
+            beats_per_minute: 120.0,
+            events: vec![],
+        },
+        MidiDataSource::Bytes {
+            bytes,
+            track_index: 0,
+        },
+    ];
+    for source in sources {
+        let mut node = Midi::new(None, source, HashMap::new())
+            .to_midi_node(&mut FileAssetLoader::default())
+            .unwrap();
+        assert!(node.has_finished());
+        let mut buffer = vec![0.0; 512];
+        node.fill_buffer(&mut buffer);
+        node.on_event(&Message::broadcast(Event::Seek(SeekPosition::Tick(0))));
+        node.fill_buffer(&mut buffer);
+    }
+}
+
+#[test]
+fn inline_sequence_converts_from_json() {
+    let json = r##"{ "Events": { "beats_per_minute": 120.0, "events": [
+        { "time": { "Seconds": 0.5 }, "data": { "NoteOff": { "note": 60 } } },
+        { "time": { "Tick": 0 }, "channel": 2, "data": { "NoteOn": { "note": 60, "vel": 0.8 } } },
+        { "time": { "Tick": 960 }, "data": { "Cue": "#1" } },
+        { "time": { "Tick": 960 }, "data": { "Cue": "Fanfare" } }
+    ] } }"##;
+    let MidiDataSource::Events {
+        ticks_per_beat,
+        events,
+        ..
+    } = serde_json::from_str::<MidiDataSource>(json).unwrap()
+    else {
+        panic!("Expected an inline sequence");
+    };
+    assert_eq!(ticks_per_beat, 480);
+    let tempo_map = TempoMap::from_tempo_changes(ticks_per_beat, &[(0, 500000.0)]);
+    let midi_events = midi_events_from_sequence(&events, &tempo_map).unwrap();
+    let deltas: Vec<isize> = midi_events.iter().map(|event| event.delta_ticks).collect();
+    assert_eq!(deltas, vec![0, 480, 480, 0]);
+    assert_eq!(midi_events[0].channel, 2);
+    assert!(matches!(
+        midi_events[0].message.data,
+        Event::NoteOn { note: 60, .. }
+    ));
+    assert!(matches!(
+        midi_events[2].message.data,
+        Event::CueData(CueData::TargetMarker(_))
+    ));
+    assert!(matches!(
+        &midi_events[3].message.data,
+        Event::Text { text, .. } if text == "Fanfare"
+    ));
+}
+
+#[test]
+fn seek_to_anchor_at_end_of_sequence_finishes_playback() {
+    let (midi_events, tempo_map) = parse_mml("c4 '>1' r4 '#1'").unwrap();
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .build()
+        .unwrap();
+    let mut buffer = vec![0.0; 48000 * 2];
+    node.fill_buffer(&mut buffer);
+    assert!(node.has_finished());
+    node.fill_buffer(&mut buffer);
+}
+
+#[test]
+fn mml_without_notes_finishes_immediately() {
+    for text in ["", "r4"] {
+        let source = MidiDataSource::Mml {
+            text: text.to_owned(),
+        };
+        let mut node = Midi::new(None, source, HashMap::new())
+            .to_midi_node(&mut FileAssetLoader::default())
+            .unwrap();
+        let mut buffer = vec![0.0; 512];
+        node.fill_buffer(&mut buffer);
+        assert!(node.has_finished());
+    }
+}
+
+#[test]
+fn mml_expands_loops_lengths_and_channels() {
+    let (midi_events, tempo_map) =
+        parse_mml("t60 o4 l8 '#1' [c:d]3 e4. q4 v0 n36 v8 n38 ; o2 >a+2 '>1'").unwrap();
+    let mut tick = 0u64;
+    let notes: Vec<(u64, usize, u8, bool)> = midi_events
+        .iter()
+        .filter_map(|event| {
+            tick += event.delta_ticks as u64;
+            match event.message.data {
+                Event::NoteOn { note, vel } => Some((tick, event.channel, note, vel > 0.0)),
+                _ => None,
+            }
+        })
+        .collect();
+    assert_eq!(
+        notes,
+        vec![
+            (0, 0, 60, true),
+            (0, 1, 58, true),
+            (240, 0, 62, true),
+            (480, 0, 60, true),
+            (720, 0, 62, true),
+            (960, 0, 60, true),
+            (1200, 0, 64, true),
+            // The note at volume 0 rests
+            (2160, 0, 38, true),
+        ]
+    );
+    // At 60 BPM, a beat of 480 ticks lasts a second
+    assert_eq!(tempo_map.samples_at_tick(480), 48000.0);
+    let cue_count = midi_events
+        .iter()
+        .filter(|event| matches!(event.message.data, Event::CueData(_)))
+        .count();
+    assert_eq!(cue_count, 2);
+
+    // A note tied across a cue label ends after the tie
+    let (midi_events, _) = parse_mml("c4 '#2' ^4").unwrap();
+    let mut tick = 0u64;
+    let note_off_tick = midi_events.iter().find_map(|event| {
+        tick += event.delta_ticks as u64;
+        matches!(event.message.data, Event::NoteOff { .. }).then_some(tick)
+    });
+    assert_eq!(note_off_tick, Some(960));
+
+    let Err(Error::User(message)) = parse_mml("c4 [d e") else {
+        panic!("Expected an unclosed loop error");
+    };
+    assert!(message.contains("Channel 0"));
+}
+
+/// Minimal 4-channel module with one looping square wave sample
+fn tracker_module_bytes(rows: &[(usize, usize, [u8; 4])]) -> Vec<u8> {
+    let mut bytes = vec![0u8; 1084];
+    bytes[0..4].copy_from_slice(b"test");
+    // Sample 1: 16 words long, finetune 0, volume 48, looping over words 4 to 12
+    bytes[42..50].copy_from_slice(&[0, 16, 0, 48, 0, 4, 0, 8]);
+    bytes[950] = 1;
+    bytes[1080..1084].copy_from_slice(b"M.K.");
+    let mut pattern = vec![0u8; 64 * 4 * 4];
+    for (row, channel, cell) in rows.iter() {
+        let offset = (row * 4 + channel) * 4;
+        pattern[offset..offset + 4].copy_from_slice(cell);
+    }
+    bytes.extend(pattern);
+    bytes.extend((0..32).map(|index| match index % 8 < 4 {
+        true => 64u8,
+        false => (-64i8) as u8,
+    }));
+    bytes
+}
+
+#[test]
+fn tracker_module_converts_notes_effects_and_jumps() {
+    let bytes = tracker_module_bytes(&[
+        // C-2 (period 428) with sample 1, then set volume to 32
+        (0, 0, [0x01, 0xAC, 0x1C, 0x20]),
+        // Volume slide down by 2 per tick
+        (1, 0, [0x00, 0x00, 0x0A, 0x02]),
+        // C-3 (period 214) with sample 1 and an arpeggio of 4 and 7 semitones
+        (1, 1, [0x00, 0xD6, 0x10, 0x47]),
+        // Portamento up, then jump back to the start
+        (2, 0, [0x00, 0x00, 0x01, 0x10]),
+        (3, 0, [0x00, 0x00, 0x0B, 0x00]),
+    ]);
+    let module = TrackerModule::parse(&bytes).unwrap();
+    assert_eq!(module.title, "test");
+    assert_eq!(module.channel_count, 4);
+
+    let mut tick = 0u64;
+    let events: Vec<(u64, usize, Event)> = module
+        .midi_events
+        .iter()
+        .map(|event| {
+            tick += event.delta_ticks as u64;
+            (tick, event.channel, event.message.data.clone())
+        })
+        .collect();
+    assert!(matches!(
+        events.first(),
+        Some((
+            0,
+            0,
+            Event::CueData(CueData::TargetMarker(Anchor::Number(0)))
+        ))
+    ));
+    assert!(matches!(
+        events.last(),
+        Some((
+            24,
+            0,
+            Event::CueData(CueData::SeekNowToTarget(Anchor::Number(0)))
+        ))
+    ));
+    let notes: Vec<(u64, usize, u8)> = events
+        .iter()
+        .filter_map(|(tick, channel, data)| match data {
+            Event::NoteOn { note, .. } => Some((*tick, *channel, *note)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(notes, vec![(0, 0, 60), (6, 1, 72)]);
+    let volumes: Vec<f32> = events
+        .iter()
+        .filter_map(|(_, channel, data)| match data {
+            Event::Volume(volume) if *channel == 0 => Some(volume * 64.0),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(volumes, vec![48.0, 32.0, 30.0, 28.0, 26.0, 24.0, 22.0]);
+    let arpeggio: Vec<(u64, f32)> = events
+        .iter()
+        .filter_map(|(tick, channel, data)| match data {
+            Event::PitchMultiplier(multiplier) if *channel == 1 => Some((*tick, *multiplier)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(arpeggio.len(), 6);
+    assert!((arpeggio[1].1 - 2.0f32.powf(7.0 / 12.0)).abs() < 0.0001);
+    assert_eq!(arpeggio[5], (12, 1.0));
+    let slide_end = events
+        .iter()
+        .rev()
+        .find_map(|(tick, channel, data)| match data {
+            Event::PitchMultiplier(multiplier) if *channel == 0 => Some((*tick, *multiplier)),
+            _ => None,
+        });
+    assert_eq!(slide_end, Some((17, 428.0 / 348.0)));
+    assert_eq!(module.channel_programs(0).unwrap().len(), 1);
+
+    let mut node = MidiNodeBuilder::from_mod(None, &bytes)
+        .unwrap()
+        .build()
+        .unwrap();
+    let mut buffer = vec![0.0; 4096];
+    node.fill_buffer(&mut buffer);
+    assert!(buffer.iter().any(|sample| sample.abs() > 0.1));
+
+    let mut bytes = bytes;
+    bytes[1080..1084].copy_from_slice(b"????");
+    let Err(Error::User(message)) = TrackerModule::parse(&bytes) else {
+        panic!("Expected an unrecognised signature error");
+    };
+    assert!(message.contains("31-sample"));
+}
+
+#[test]
+fn tracker_loop_restores_volume_and_pitch_of_its_target_row() {
+    let bytes = tracker_module_bytes(&[
+        // C-2 with sample 1 at volume 32, then slides down in volume and up
+        // in pitch before jumping back to row 1
+        (0, 0, [0x01, 0xAC, 0x1C, 0x20]),
+        (1, 0, [0x00, 0x00, 0x0A, 0x02]),
+        (2, 0, [0x00, 0x00, 0x01, 0x10]),
+        (3, 0, [0x00, 0x00, 0x0B, 0x00]),
+        (3, 1, [0x00, 0x00, 0x0D, 0x01]),
+    ]);
+    let module = TrackerModule::parse(&bytes).unwrap();
+    let mut tick = 0u64;
+    let loop_row_events: Vec<(usize, Event)> = module
+        .midi_events
+        .iter()
+        .filter_map(|event| {
+            tick += event.delta_ticks as u64;
+            (tick == 6).then(|| (event.channel, event.message.data.clone()))
+        })
+        .collect();
+    assert_eq!(loop_row_events.len(), 3);
+    assert!(matches!(
+        loop_row_events[0],
+        (0, Event::CueData(CueData::TargetMarker(Anchor::Number(0))))
+    ));
+    assert!(matches!(loop_row_events[1], (0, Event::Volume(0.5))));
+    assert!(matches!(
+        loop_row_events[2],
+        (0, Event::PitchMultiplier(1.0))
+    ));
+}
+
+#[test]
+fn event_timing_does_not_drift_over_many_events() {
+    // At 130 BPM, a tick of 480 per beat lasts a fraction over 46 samples
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 60000000.0 / 130.0)]);
+    let mut sequence: Vec<SequenceEvent> = (0..2000)
+        .map(|tick| SequenceEvent {
+            time: SequenceTime::Tick(tick),
+            channel: 0,
+            data: SequenceEventData::NoteOff { note: 60 },
+        })
+        .collect();
+    sequence.push(SequenceEvent {
+        time: SequenceTime::Tick(2000),
+        channel: 0,
+        data: SequenceEventData::NoteOn { note: 60, vel: 1.0 },
+    });
+    sequence.push(SequenceEvent {
+        time: SequenceTime::Tick(8000),
+        channel: 0,
+        data: SequenceEventData::NoteOff { note: 60 },
+    });
+    let first_sound_frame = |time_dilation: f32| {
+        let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+        let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map.clone())
+            .add_channel_source(
+                0,
+                Box::new(SquareWaveNode::new(None, Balance::Both, 0.5, 0.5)),
+            )
+            .build()
+            .unwrap();
+        node.on_event(&Message::broadcast(Event::TimeDilation(time_dilation)));
+        let mut buffer = vec![0.0; 200000 * 2];
+        for chunk in buffer.chunks_mut(consts::BUFFER_SIZE * 2) {
+            node.fill_buffer(chunk);
+        }
+        buffer
+            .iter()
+            .position(|sample| *sample != 0.0)
+            .map(|index| index / 2)
+    };
+
+    // Tick 2000 is 92307.7 samples in
+    assert_eq!(first_sound_frame(1.0), Some(92308));
+    assert_eq!(first_sound_frame(0.5), Some(184616));
+}
+
+#[test]
+fn tempo_event_sets_beats_per_minute() {
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let sequence = [
+        SequenceEvent {
+            time: SequenceTime::Tick(960),
+            channel: 0,
+            data: SequenceEventData::NoteOn { note: 60, vel: 1.0 },
+        },
+        SequenceEvent {
+            time: SequenceTime::Tick(1920),
+            channel: 0,
+            data: SequenceEventData::NoteOff { note: 60 },
+        },
+    ];
+    let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(
+            0,
+            Box::new(SquareWaveNode::new(None, Balance::Both, 0.5, 0.5)),
+        )
+        .build()
+        .unwrap();
+    node.on_event(&Message::broadcast(Event::Tempo {
+        bpm: 240.0,
+        seconds: 0.0,
+    }));
+    let snapshot = node
+        .get_state_snapshot(node.get_node_id())
+        .unwrap()
+        .unwrap();
+    assert_eq!(snapshot["beats_per_minute"].as_f64(), Some(240.0));
+
+    // Two beats last 24000 samples at 240 BPM, rather than 48000 at 120 BPM
+    let mut buffer = vec![0.0; 30000 * 2];
+    node.fill_buffer(&mut buffer);
+    let first_sound_frame = buffer.chunks(2).position(|frame| frame[0] != 0.0);
+    assert_eq!(first_sound_frame, Some(24000));
+
+    node.on_event(&Message::broadcast(Event::TimeDilation(1.0)));
+    assert_eq!(node.beats_per_minute(), Some(120.0));
+}
+
+#[test]
+fn stingers_start_on_the_next_beat_bar_or_anchor() {
+    // At 120 BPM, a beat of 480 ticks lasts 24000 samples
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let sequence = [
+        SequenceEvent {
+            time: SequenceTime::Tick(960),
+            channel: 0,
+            data: SequenceEventData::Cue("#1".to_owned()),
+        },
+        SequenceEvent {
+            time: SequenceTime::Tick(7680),
+            channel: 0,
+            data: SequenceEventData::NoteOff { note: 60 },
+        },
+    ];
+    let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+    let hit = OneShotNode::new_from_data(
+        None,
+        Balance::Both,
+        OneShotFileMetadata { channels: 1 },
+        Arc::new(vec![0.5; 100]),
+    )
+    .unwrap();
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_stinger("hit".to_owned(), Stinger::OneShot(hit))
+        .build()
+        .unwrap();
+    let first_sound_frame = |node: &mut MidiNode, frames: usize| {
+        let mut buffer = vec![0.0; frames * 2];
+        node.fill_buffer(&mut buffer);
+        buffer
+            .iter()
+            .position(|sample| *sample != 0.0)
+            .map(|index| index / 2)
+    };
+    assert_eq!(first_sound_frame(&mut node, 10000), None);
+
+    node.on_event(&Message::broadcast(Event::Stinger {
+        name: "hit".to_owned(),
+        alignment: StingerAlignment::Beat,
+    }));
+    assert_eq!(first_sound_frame(&mut node, 16000), Some(14000));
+
+    // Anchor 1 is at tick 960, 48000 samples in
+    node.play_stinger("hit", &StingerAlignment::Anchor(1.into()));
+    assert_eq!(first_sound_frame(&mut node, 30000), Some(22000));
+
+    // The second bar starts at tick 1920, 96000 samples in
+    node.play_stinger("hit", &StingerAlignment::Bar);
+    assert_eq!(first_sound_frame(&mut node, 50000), Some(40000));
+}
+
+#[test]
+fn stingers_follow_time_dilation_and_seeks_before_they_start() {
+    // At 120 BPM, a bar of 1920 ticks lasts 96000 samples
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let sequence = [
+        SequenceEvent {
+            time: SequenceTime::Tick(960),
+            channel: 0,
+            data: SequenceEventData::Cue("#1".to_owned()),
+        },
+        SequenceEvent {
+            time: SequenceTime::Tick(7680),
+            channel: 0,
+            data: SequenceEventData::NoteOff { note: 60 },
+        },
+    ];
+    let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+    let hit = OneShotNode::new_from_data(
+        None,
+        Balance::Both,
+        OneShotFileMetadata { channels: 1 },
+        Arc::new(vec![0.5; 100]),
+    )
+    .unwrap();
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_stinger("hit".to_owned(), Stinger::OneShot(hit))
+        .build()
+        .unwrap();
+    let first_sound_frame = |node: &mut MidiNode, frames: usize| {
+        let mut buffer = vec![0.0; frames * 2];
+        node.fill_buffer(&mut buffer);
+        buffer
+            .iter()
+            .position(|sample| *sample != 0.0)
+            .map(|index| index / 2)
+    };
+    assert_eq!(first_sound_frame(&mut node, 1000), None);
+
+    // Doubling the speed after scheduling halves the wait for the bar line
+    node.play_stinger("hit", &StingerAlignment::Bar);
+    node.on_event(&Message::broadcast(Event::TimeDilation(2.0)));
+    assert_eq!(first_sound_frame(&mut node, 40000), None);
+
+    // Seeking back to tick 960 leaves half a bar, 24000 frames at double
+    // speed, before the bar line
+    node.on_event(&Message::broadcast(Event::CueData(
+        CueData::SeekNowToTarget(1.into()),
+    )));
+    assert_eq!(first_sound_frame(&mut node, 30000), Some(24000));
+}
+
+#[test]
+fn adaptive_music_changes_state_on_the_next_bar() {
+    // At 120 BPM in 4/4, a bar of 1920 ticks lasts 96000 samples
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let sequence = [
+        SequenceEvent {
+            time: SequenceTime::Tick(0),
+            channel: 0,
+            data: SequenceEventData::NoteOn { note: 60, vel: 1.0 },
+        },
+        SequenceEvent {
+            time: SequenceTime::Tick(7680),
+            channel: 0,
+            data: SequenceEventData::NoteOff { note: 60 },
+        },
+    ];
+    let segment = |balance: Balance| {
+        let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+        MidiNodeBuilder::from_events(None, midi_events, tempo_map.clone())
+            .add_channel_source(0, Box::new(SquareWaveNode::new(None, balance, 0.5, 0.5)))
+            .build()
+            .unwrap()
+    };
+    let segments = HashMap::from([
+        ("calm".to_owned(), segment(Balance::Left)),
+        ("tense".to_owned(), segment(Balance::Right)),
+    ]);
+    let states = HashMap::from([
+        (
+            "explore".to_owned(),
+            MusicState {
+                segment: "calm".to_owned(),
+                anchor: None,
+                end_anchor: None,
+            },
+        ),
+        (
+            "battle".to_owned(),
+            MusicState {
+                segment: "tense".to_owned(),
+                anchor: None,
+                end_anchor: None,
+            },
+        ),
+    ]);
+    let transitions = vec![MusicTransition {
+        timing: TransitionTiming::NextBar,
+        ..MusicTransition::default()
+    }];
+    let mut node = AdaptiveMusicNode::new(None, segments, states, transitions).unwrap();
+    let mut buffer = vec![0.0; 10000 * 2];
+    node.set_state("explore");
+    node.fill_buffer(&mut buffer);
+    assert!(
+        buffer
+            .chunks(2)
+            .all(|frame| frame[0] != 0.0 && frame[1] == 0.0)
+    );
+
+    node.on_event(&Message::broadcast(Event::MusicState("battle".to_owned())));
+    let mut buffer = vec![0.0; 100000 * 2];
+    node.fill_buffer(&mut buffer);
+    assert_eq!(node.current_state(), Some("battle"));
+    let first_right = buffer.chunks(2).position(|frame| frame[1] != 0.0);
+    let last_left = buffer.chunks(2).rposition(|frame| frame[0] != 0.0);
+    assert_eq!(first_right, Some(86000));
+    assert_eq!(last_left, Some(85999));
+}
+
+#[test]
+fn end_of_segment_waits_for_state_end_anchor() {
+    let segment = |text: &str| {
+        let (midi_events, tempo_map) = parse_mml(text).unwrap();
+        MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+            .build()
+            .unwrap()
+    };
+    // The loop back to the start means the outro is never reached
+    let segments = HashMap::from([
+        ("calm".to_owned(), segment("'#1' c4 c4 '#end' '>1' d4")),
+        ("tense".to_owned(), segment("e1")),
+    ]);
+    let states = HashMap::from([
+        (
+            "explore".to_owned(),
+            MusicState {
+                segment: "calm".to_owned(),
+                anchor: None,
+                end_anchor: Some(Anchor::from("end")),
+            },
+        ),
+        (
+            "battle".to_owned(),
+            MusicState {
+                segment: "tense".to_owned(),
+                anchor: None,
+                end_anchor: None,
+            },
+        ),
+    ]);
+    let transitions = vec![MusicTransition {
+        timing: TransitionTiming::EndOfSegment,
+        ..MusicTransition::default()
+    }];
+    let mut node = AdaptiveMusicNode::new(None, segments, states, transitions).unwrap();
+    node.set_state("explore");
+    let mut buffer = vec![0.0; 30000 * 2];
+    node.fill_buffer(&mut buffer);
+    node.set_state("battle");
+    // The end anchor is two beats in, at 48000 samples
+    let mut buffer = vec![0.0; 17999 * 2];
+    node.fill_buffer(&mut buffer);
+    assert_eq!(node.current_state(), Some("explore"));
+    let mut buffer = vec![0.0; 2 * 2];
+    node.fill_buffer(&mut buffer);
+    assert_eq!(node.current_state(), Some("battle"));
+}
+
+#[test]
+fn arrangements_switch_at_the_same_song_position() {
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let arrangement = |note_off_tick: u64, balance: Balance| {
+        let sequence = [
+            SequenceEvent {
+                time: SequenceTime::Tick(0),
+                channel: 0,
+                data: SequenceEventData::NoteOn { note: 60, vel: 1.0 },
+            },
+            SequenceEvent {
+                time: SequenceTime::Tick(note_off_tick),
+                channel: 0,
+                data: SequenceEventData::NoteOff { note: 60 },
+            },
+        ];
+        let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+        MidiNodeBuilder::from_events(None, midi_events, tempo_map.clone())
+            .add_channel_source(0, Box::new(SquareWaveNode::new(None, balance, 0.5, 0.5)))
+            .build()
+            .unwrap()
+    };
+    let arrangements = HashMap::from([
+        ("calm".to_owned(), arrangement(3840, Balance::Left)),
+        ("intense".to_owned(), arrangement(960, Balance::Right)),
+    ]);
+    let mut node = ArrangementsNode::new(None, arrangements, "calm").unwrap();
+    let mut buffer = vec![0.0; 40000 * 2];
+    node.fill_buffer(&mut buffer);
+    assert!(
+        buffer
+            .chunks(2)
+            .all(|frame| frame[0] != 0.0 && frame[1] == 0.0)
+    );
+
+    // The intense note is chased at 40000 samples in, and ends at tick 960
+    // (48000 samples)
+    node.on_event(&Message::broadcast(Event::Arrangement {
+        name: "intense".to_owned(),
+        seconds: 0.0,
+    }));
+    let mut buffer = vec![0.0; 20000 * 2];
+    node.fill_buffer(&mut buffer);
+    assert_eq!(node.selected(), "intense");
+    assert!(buffer.chunks(2).all(|frame| frame[0] == 0.0));
+    let last_right = buffer.chunks(2).rposition(|frame| frame[1] != 0.0);
+    assert_eq!(last_right, Some(7999));
+}
+
+#[test]
+fn arrangements_keep_cue_state_and_fades_when_switching() {
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let log = Arc::new(Mutex::new(vec![]));
+    // Both loop back from tick 960 to the start once, then play on
+    let arrangement = |note_on_tick: u64, source: GraphNode| {
+        let sequence = [
+            SequenceEvent {
+                time: SequenceTime::Tick(0),
+                channel: 0,
+                data: SequenceEventData::Cue("#1".to_owned()),
+            },
+            SequenceEvent {
+                time: SequenceTime::Tick(note_on_tick),
+                channel: 0,
+                data: SequenceEventData::NoteOn { note: 60, vel: 1.0 },
+            },
+            SequenceEvent {
+                time: SequenceTime::Tick(960),
+                channel: 0,
+                data: SequenceEventData::Cue(">1*1".to_owned()),
+            },
+            SequenceEvent {
+                time: SequenceTime::Tick(3840),
+                channel: 0,
+                data: SequenceEventData::NoteOff { note: 60 },
+            },
+        ];
+        let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+        MidiNodeBuilder::from_events(None, midi_events, tempo_map.clone())
+            .add_channel_source(0, source)
+            .build()
+            .unwrap()
+    };
+    let arrangements = HashMap::from([
+        (
+            "calm".to_owned(),
+            arrangement(10, Box::new(EventLogNode::new(&log))),
+        ),
+        (
+            "intense".to_owned(),
+            arrangement(
+                1000,
+                Box::new(SquareWaveNode::new(None, Balance::Right, 0.5, 0.5)),
+            ),
+        ),
+    ]);
+    let mut node = ArrangementsNode::new(None, arrangements, "calm").unwrap();
+
+    // Calm loops back at 48000 samples, and is at tick 240 of its second
+    // pass when intense takes over, which then plays on past tick 960 and
+    // starts its note at tick 1000
+    let mut buffer = vec![0.0; 60000 * 2];
+    node.fill_buffer(&mut buffer);
+    let select = |node: &mut ArrangementsNode, name: &str| {
+        node.on_event(&Message::broadcast(Event::Arrangement {
+            name: name.to_owned(),
+            seconds: 1.0,
+        }));
+    };
+    select(&mut node, "intense");
+    let mut buffer = vec![0.0; 40000 * 2];
+    node.fill_buffer(&mut buffer);
+    let first_right = buffer.chunks(2).position(|frame| frame[1] != 0.0);
+    assert_eq!(first_right, Some(38000));
+
+    // Switching back to calm while it fades out continues its playback,
+    // rather than releasing and chasing its note again
+    take_events(&log);
+    select(&mut node, "calm");
+    let notes = take_notes(&log);
+    assert!(notes.is_empty(), "{:?}", notes);
+    assert_eq!(node.selected(), "calm");
+}
+
+#[test]
+fn arrangements_switch_during_tempo_ramp() {
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let arrangement = |balance: Balance| {
+        let sequence = [
+            SequenceEvent {
+                time: SequenceTime::Tick(0),
+                channel: 0,
+                data: SequenceEventData::NoteOn { note: 60, vel: 1.0 },
+            },
+            SequenceEvent {
+                time: SequenceTime::Tick(1920),
+                channel: 0,
+                data: SequenceEventData::NoteOff { note: 60 },
+            },
+        ];
+        let midi_events = midi_events_from_sequence(&sequence, &tempo_map).unwrap();
+        MidiNodeBuilder::from_events(None, midi_events, tempo_map.clone())
+            .add_channel_source(0, Box::new(SquareWaveNode::new(None, balance, 0.5, 0.5)))
+            .build()
+            .unwrap()
+    };
+    let arrangements = HashMap::from([
+        ("calm".to_owned(), arrangement(Balance::Left)),
+        ("intense".to_owned(), arrangement(Balance::Right)),
+    ]);
+    let mut node = ArrangementsNode::new(None, arrangements, "calm").unwrap();
+    let fill_frames = |node: &mut ArrangementsNode, frames: usize| {
+        let mut buffer = vec![0.0; frames * 2];
+        for chunk in buffer.chunks_mut(consts::BUFFER_SIZE * 2) {
+            node.fill_buffer(chunk);
+        }
+        buffer
+    };
+    // Ramping from 120 to 240 BPM over two seconds reaches 2.5 beats and
+    // 180 BPM after one second
+    node.on_event(&Message::broadcast(Event::Tempo {
+        bpm: 240.0,
+        seconds: 2.0,
+    }));
+    fill_frames(&mut node, 48000);
+    node.select("intense", 0.0);
+
+    // Carrying on up the ramp, the last 1.5 beats take about 0.464 seconds
+    let buffer = fill_frames(&mut node, 48000);
+    let last_right = buffer.chunks(2).rposition(|frame| frame[1] != 0.0).unwrap();
+    assert!((21800..22800).contains(&last_right));
+}
+
+#[test]
+fn duplicated_sample_loop_keeps_loop_within_shared_buffer() {
+    // A mono sample of 8 frames stored after another of 8 frames, looping
+    // over its last 4 frames
+    let sample_buffer = Arc::new(
+        (0..16)
+            .map(|index| index as f32 / 16.0)
+            .collect::<Vec<f32>>(),
+    );
+    let source = SampleLoopNode::new(
+        None,
+        consts::PLAYBACK_SAMPLE_RATE as u32,
+        1,
+        60,
+        Some(LoopRange::new_frame_range(4, 8)),
+        Balance::Both,
+        sample_buffer,
+        8,
+        8,
+    )
+    .unwrap();
+    let mut duplicate = source.duplicate().unwrap();
+    let mut source: GraphNode = Box::new(source);
+    let play = |node: &mut GraphNode| {
+        node.on_event(&Message::broadcast(Event::NoteOn { note: 60, vel: 1.0 }));
+        let mut buffer = vec![0.0; 32 * 2];
+        node.fill_buffer(&mut buffer);
+        buffer
+    };
+    let original_output = play(&mut source);
+    assert!(original_output.iter().all(|sample| *sample >= 0.5));
+    assert_eq!(play(&mut duplicate), original_output);
+}
+
+#[test]
+fn general_midi_picks_presets_by_channel_and_program() {
+    let mut asset_loader = FileAssetLoader::default();
+    let config = |channel: usize| {
+        let json = format!(
+            r##"{{ "source": {{ "Events": {{ "events": [
+                {{ "time": {{ "Tick": 0 }}, "channel": {}, "data": {{ "NoteOn": {{ "note": 45, "vel": 1.0 }} }} }},
+                {{ "time": {{ "Tick": 480 }}, "channel": {}, "data": {{ "NoteOff": {{ "note": 45 }} }} }}
+            ] }} }}, "soundfont": "{}" }}"##,
+            channel, channel, SF2_FILE
+        );
+        serde_json::from_str::<GeneralMidi>(&json).unwrap()
+    };
+    let mut buffer = vec![0.0; 4800 * 2];
+
+    // Channel 1 plays the guitar at bank 0, program 0
+    let mut melodic = config(0).to_node(&mut asset_loader).unwrap();
+    melodic.fill_buffer(&mut buffer);
+    assert!(buffer.iter().any(|sample| *sample != 0.0));
+
+    // Channel 10 is percussion, and the SoundFont has no percussion bank
+    buffer.fill(0.0);
+    let mut percussion = config(9).to_node(&mut asset_loader).unwrap();
+    percussion.fill_buffer(&mut buffer);
+    assert!(buffer.iter().all(|sample| *sample == 0.0));
+
+    let (metadata, sample_buffer) = load_sf2_file(&mut asset_loader, SF2_FILE).unwrap();
+    let mut channel = GeneralMidiChannelNode::new(false, Arc::new(metadata), sample_buffer, 4);
+    channel.prepare_preset(0, 5).unwrap();
+    channel.prepare_preset(8, 0).unwrap();
+    let note_on = Message::broadcast(Event::NoteOn { note: 45, vel: 1.0 });
+    channel.on_event(&Message::broadcast(Event::ProgramChange(5)));
+    channel.on_event(&note_on);
+    buffer.fill(0.0);
+    channel.fill_buffer(&mut buffer);
+    assert!(buffer.iter().all(|sample| *sample == 0.0));
+
+    // Unknown banks fall back to bank 0
+    channel.on_event(&Message::broadcast(Event::Controller {
+        controller: 0,
+        value: 8,
+    }));
+    channel.on_event(&Message::broadcast(Event::ProgramChange(0)));
+    channel.on_event(&note_on);
+    channel.fill_buffer(&mut buffer);
+    assert!(buffer.iter().any(|sample| *sample != 0.0));
+
+    // Presets that weren't prepared are refused, keeping the current one
+    channel.on_event(&Message::broadcast(Event::NoteOff { note: 45, vel: 1.0 }));
+    let mut release_buffer = vec![0.0; 48000 * 2];
+    channel.fill_buffer(&mut release_buffer);
+    channel.on_event(&Message::broadcast(Event::ProgramChange(5)));
+    channel.on_event(&note_on);
+    buffer.fill(0.0);
+    channel.fill_buffer(&mut buffer);
+    assert!(buffer.iter().any(|sample| *sample != 0.0));
+}
+
+#[test]
+fn font_velocity_layers_play_one_at_a_time() {
+    let soft_log = Arc::new(Mutex::new(vec![]));
+    let loud_log = Arc::new(Mutex::new(vec![]));
+    let keys = NoteRange::new_inclusive_range(0, 127);
+    let mut font = FontNodeBuilder::new(None)
+        .add_velocity_layer(
+            keys.clone(),
+            NoteRange::new_inclusive_range(0, 63),
+            Box::new(EventLogNode::new(&soft_log)),
+        )
+        .unwrap()
+        .add_velocity_layer(
+            keys,
+            NoteRange::new_inclusive_range(64, 127),
+            Box::new(EventLogNode::new(&loud_log)),
+        )
+        .unwrap()
+        .build();
+    font.on_event(&Message::broadcast(Event::NoteOn {
+        note: 60,
+        vel: 0.25,
+    }));
+    font.on_event(&Message::broadcast(Event::NoteOn {
+        note: 62,
+        vel: 0.75,
+    }));
+    assert_eq!(take_notes(&soft_log), vec![(60, true)]);
+    assert_eq!(take_notes(&loud_log), vec![(62, true)]);
+
+    // Note-offs reach every layer, as only the one playing the note responds
+    font.on_event(&note_off(60));
+    assert_eq!(take_notes(&soft_log), vec![(60, false)]);
+    assert_eq!(take_notes(&loud_log), vec![(60, false)]);
+}
+
+#[test]
+fn general_midi_notes_held_across_program_change_end_on_their_note_off() {
+    let mut asset_loader = FileAssetLoader::default();
+    let (metadata, sample_buffer) = load_sf2_file(&mut asset_loader, SF2_FILE).unwrap();
+    let mut channel = GeneralMidiChannelNode::new(false, Arc::new(metadata), sample_buffer, 4);
+    channel.prepare_preset(0, 0).unwrap();
+    channel.prepare_preset(0, 5).unwrap();
+    channel.on_event(&Message::broadcast(Event::NoteOn { note: 45, vel: 1.0 }));
+    let mut buffer = vec![0.0; 48000 * 2];
+    let mut last_sound_frame = |channel: &mut GeneralMidiChannelNode| {
+        buffer.fill(0.0);
+        channel.fill_buffer(&mut buffer);
+        buffer.chunks(2).rposition(|frame| frame[0] != 0.0)
+    };
+    assert_eq!(last_sound_frame(&mut channel), Some(47999));
+
+    // The guitar's note loops on after switching to a program without a
+    // preset, and ends once its note-off reaches it
+    channel.on_event(&Message::broadcast(Event::ProgramChange(5)));
+    assert_eq!(last_sound_frame(&mut channel), Some(47999));
+    channel.on_event(&Message::broadcast(Event::NoteOff { note: 45, vel: 1.0 }));
+    assert!(last_sound_frame(&mut channel).is_some_and(|frame| frame < 47999));
+    assert_eq!(last_sound_frame(&mut channel), None);
+}
+
+#[test]
+fn program_changes_reach_only_program_selecting_sources() {
+    let plain_log = Arc::new(Mutex::new(vec![]));
+    let selecting_log = Arc::new(Mutex::new(vec![]));
+    let midi_events = (0..2)
+        .map(|channel| MidiEvent {
+            delta_ticks: 0,
+            channel,
+            message: Message::broadcast(Event::ProgramChange(3)),
+        })
+        .collect();
+    let tempo_map = TempoMap::from_tempo_changes(480, &[(0, 500000.0)]);
+    let mut node = MidiNodeBuilder::from_events(None, midi_events, tempo_map)
+        .add_channel_source(0, Box::new(EventLogNode::new(&plain_log)))
+        .add_program_selecting_source(1, Box::new(EventLogNode::new(&selecting_log)))
+        .build()
+        .unwrap();
+    let mut buffer = vec![0.0; 100 * 2];
+    node.fill_buffer(&mut buffer);
+    let program_changes = |log: &EventLog| {
+        take_events(log)
+            .into_iter()
+            .filter(|(_, event)| matches!(event, Event::ProgramChange(3)))
+            .count()
+    };
+    assert_eq!(program_changes(&plain_log), 0);
+    assert_eq!(program_changes(&selecting_log), 1);
+}
+
+#[test]
+fn recording_saves_notes_and_cues() {
+    let mut asset_loader = FileAssetLoader::default();
+    let square_wave = SquareWave {
+        node_id: None,
+        balance: Balance::Both,
+        amplitude: 0.25,
+        duty_cycle: 0.125,
+    };
+    let consumer = square_wave.to_node(&mut asset_loader).unwrap();
+    let (mut recorder, mut recording) = MidiRecorderNode::new(None, consumer);
+    let mut buffer = vec![0.0; 24000 * 2];
+    recorder.on_event(&Message::broadcast(Event::NoteOn { note: 60, vel: 1.0 }));
+    recorder.fill_buffer(&mut buffer);
+    recorder.on_event(&Message::broadcast(Event::CueData(CueData::TargetMarker(
+        1.into(),
+    ))));
+    recorder.on_event(&Message::broadcast(Event::CueData(
+        CueData::GoodPointToSeekFrom,
+    )));
+    recorder.on_event(&Message::broadcast(Event::NoteOff { note: 60, vel: 0.0 }));
+    // Running-status note-offs decode to note-ons at zero velocity
+    recorder.on_event(&Message::broadcast(Event::NoteOn { note: 64, vel: 1.0 }));
+    recorder.fill_buffer(&mut buffer);
+    recorder.on_event(&Message::broadcast(Event::NoteOn { note: 64, vel: 0.0 }));
+
+    // 24000 frames is one beat at the default 120 BPM
+    let bytes = recording.to_smf_bytes().unwrap();
+    let smf = Smf::parse(&bytes).unwrap();
+    let ticks_and_kinds: Vec<(u32, TrackEventKind)> = smf.tracks[0]
+        .iter()
+        .scan(0, |tick, event| {
+            *tick += u32::from(event.delta);
+            Some((*tick, event.kind))
+        })
+        .collect();
+    assert!(matches!(
+        ticks_and_kinds[1],
+        (
+            0,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { .. },
+                ..
+            }
+        )
+    ));
+    assert!(matches!(
+        ticks_and_kinds[2],
+        (480, TrackEventKind::Meta(MetaMessage::CuePoint(b"#1?")))
+    ));
+    assert!(matches!(
+        ticks_and_kinds[3],
+        (
+            480,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { .. },
+                ..
+            }
+        )
+    ));
+    let note_64_offs = ticks_and_kinds
+        .iter()
+        .filter(|(tick, kind)| {
+            *tick == 960
+                && matches!(
+                    kind,
+                    TrackEventKind::Midi {
+                        message: MidiMessage::NoteOff { key, .. },
+                        ..
+                    } if u8::from(*key) == 64
+                )
+        })
+        .count();
+    assert_eq!(note_64_offs, 1);
+}
+
 #[test]
 fn can_decode_wav_file() {
     let mut asset_loader = FileAssetLoader::default();
@@ -45,13 +2354,13 @@ fn can_decode_wav_file() {
 #[test]
 fn can_play_square_stream() {
     let mut asset_loader = FileAssetLoader::default();
-    let midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([(
+        HashMap::from([(
             0,
             ChildConfig(Box::new(Font {
                 node_id: None,
@@ -67,7 +2376,7 @@ fn can_play_square_stream() {
                 }]),
             })),
         )]),
-    };
+    );
     let midi_node = midi.to_node(&mut asset_loader).unwrap();
     let mixer = BaseMixer::builder_with_default_registry()
         .unwrap()
@@ -81,13 +2390,13 @@ fn can_play_square_stream() {
 #[test]
 fn can_play_wav_stream() {
     let mut asset_loader = FileAssetLoader::default();
-    let midi = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let midi = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE.to_owned(),
             track_index: 0,
         },
-        channels: HashMap::from([(
+        HashMap::from([(
             0,
             ChildConfig(Box::new(Font {
                 node_id: None,
@@ -98,7 +2407,7 @@ fn can_play_wav_stream() {
                 }]),
             })),
         )]),
-    };
+    );
     let midi_node = midi.to_node(&mut asset_loader).unwrap();
     let mixer = BaseMixer::builder_with_existing_registry()
         .set_initial_program(1, midi_node)
@@ -32,13 +32,13 @@ impl AssetLoader for WasmAssetLoader {
 
 #[wasm_bindgen]
 pub fn play_stream() {
-    let config = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let config = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE_PATH.to_string(),
             track_index: 0,
         },
-        channels: HashMap::from([(
+        HashMap::from([(
             0,
             ChildConfig(Box::new(SquareWave {
                 node_id: None,
@@ -47,7 +47,7 @@ pub fn play_stream() {
                 duty_cycle: 0.125,
             })),
         )]),
-    };
+    );
     let mut asset_loader = WasmAssetLoader;
     let midi_source = config.to_node(&mut asset_loader).unwrap();
     let _mixer = BaseMixer::builder_with_default_registry()
@@ -39,14 +39,14 @@ impl AssetLoader for WasmAssetLoader {
 }
 
 fn midi_node_from_file() -> Result<GraphNode, Error> {
-    let midi_config = Midi {
-        node_id: None,
-        source: MidiDataSource::FilePath {
+    let midi_config = Midi::new(
+        None,
+        MidiDataSource::FilePath {
             path: MIDI_FILE_PATH.to_string(),
             track_index: 0,
         },
-        channels: HashMap::new(),
-    };
+        HashMap::new(),
+    );
     let mut file_loader = WasmAssetLoader;
     midi_config.to_node(&mut file_loader)
 }